@@ -0,0 +1,12 @@
+pub fn caller_is_controller() -> Result<(), String> {
+    check_caller_is_controller("Caller is not a controller".to_string())
+}
+
+// Returns the given error if the caller is not a controller
+pub fn check_caller_is_controller<E>(error: E) -> Result<(), E> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
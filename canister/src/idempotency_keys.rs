@@ -0,0 +1,122 @@
+use crate::RegisterNeuronPairError;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+const RETENTION_PERIOD: Duration = Duration::from_secs(24 * 60 * 60); // 1 day
+const MAX_KEYS: usize = 10_000;
+
+// The outcomes of registrations made with client supplied idempotency keys, so that a client
+// which retries after timing out receives the outcome of its original call. Keys are scoped to
+// the caller and are retained for a day.
+#[derive(Serialize, Deserialize, Default)]
+pub struct IdempotencyKeys {
+    outcomes: BTreeMap<Principal, BTreeMap<String, StoredOutcome>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredOutcome {
+    result: Result<u64, RegisterNeuronPairError>,
+    recorded_at: u64,
+}
+
+impl IdempotencyKeys {
+    pub fn get(
+        &self,
+        caller: Principal,
+        key: &str,
+        now: u64,
+    ) -> Option<Result<u64, RegisterNeuronPairError>> {
+        self.outcomes
+            .get(&caller)?
+            .get(key)
+            .filter(|o| !Self::is_expired(o, now))
+            .map(|o| o.result.clone())
+    }
+
+    pub fn insert(
+        &mut self,
+        caller: Principal,
+        key: String,
+        result: Result<u64, RegisterNeuronPairError>,
+        now: u64,
+    ) {
+        self.prune(now);
+        self.outcomes.entry(caller).or_default().insert(
+            key,
+            StoredOutcome {
+                result,
+                recorded_at: now,
+            },
+        );
+    }
+
+    pub fn count(&self) -> usize {
+        self.outcomes.values().map(|o| o.len()).sum()
+    }
+
+    // Removes expired outcomes, then if there are still too many, removes the oldest
+    fn prune(&mut self, now: u64) {
+        for outcomes in self.outcomes.values_mut() {
+            outcomes.retain(|_, o| !Self::is_expired(o, now));
+        }
+        self.outcomes.retain(|_, o| !o.is_empty());
+
+        while self.count() >= MAX_KEYS {
+            let Some((caller, key)) = self
+                .outcomes
+                .iter()
+                .flat_map(|(caller, o)| o.iter().map(move |(key, o)| (o.recorded_at, caller, key)))
+                .min()
+                .map(|(_, caller, key)| (*caller, key.clone()))
+            else {
+                break;
+            };
+            if let Some(outcomes) = self.outcomes.get_mut(&caller) {
+                outcomes.remove(&key);
+                if outcomes.is_empty() {
+                    self.outcomes.remove(&caller);
+                }
+            }
+        }
+    }
+
+    fn is_expired(outcome: &StoredOutcome, now: u64) -> bool {
+        now >= outcome.recorded_at + RETENTION_PERIOD.as_nanos() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_are_scoped_to_the_caller_and_expire() {
+        let caller1 = Principal::from_slice(&[1]);
+        let caller2 = Principal::from_slice(&[2]);
+        let mut keys = IdempotencyKeys::default();
+
+        keys.insert(caller1, "abc".to_string(), Ok(5), 0);
+        keys.insert(
+            caller2,
+            "abc".to_string(),
+            Err(RegisterNeuronPairError::NotPermittedToVote),
+            0,
+        );
+
+        assert!(matches!(keys.get(caller1, "abc", 1), Some(Ok(5))));
+        assert!(matches!(
+            keys.get(caller2, "abc", 1),
+            Some(Err(RegisterNeuronPairError::NotPermittedToVote))
+        ));
+        assert!(keys.get(caller1, "xyz", 1).is_none());
+
+        let expiry = RETENTION_PERIOD.as_nanos() as u64;
+        assert!(keys.get(caller1, "abc", expiry).is_none());
+
+        keys.insert(caller1, "xyz".to_string(), Ok(6), expiry);
+        assert_eq!(keys.count(), 1);
+    }
+}
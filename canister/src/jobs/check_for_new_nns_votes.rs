@@ -1,109 +1,436 @@
 use crate::logs::log;
-use crate::{state, NnsVote};
-use candid::CandidType;
-use ic_cdk::api::call::CallResult;
+use crate::neuron_pair::NeuronPair;
+use crate::nns_ballot_gaps::BallotLookupOutcome;
+use crate::state::NnsPollingPlan;
+use crate::{nns_governance, state};
+use ic_cdk_timers::TimerId;
 use ic_principal::Principal;
-use serde::Deserialize;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::Duration;
 
-const CHECK_FOR_NEW_NNS_VOTES_INTERVAL: Duration = Duration::from_secs(120);
+// Neurons are polled more frequently as the deadlines of the proposals they have yet to vote on
+// approach, down to this interval
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_secs(30);
+// When no neurons have open proposals to vote on, every neuron is polled at this slower interval
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// The batches are spread over this portion of the minimum interval, leaving time for the final
+// batch to complete before the next run
+const BATCH_SPREAD: Duration = Duration::from_secs(15);
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_NNS_BALLOTS_TO_RECOVER_PER_RUN: usize = 20;
+
+thread_local! {
+    static RUN_IN_PROGRESS: Cell<bool> = Cell::default();
+    static NEXT_RUN: Cell<Option<(TimerId, u64)>> = Cell::default();
+    static LAST_FULL_POLL: Cell<u64> = Cell::default();
+}
 
 pub fn start_job() {
-    ic_cdk_timers::set_timer_interval(CHECK_FOR_NEW_NNS_VOTES_INTERVAL, || ic_cdk::spawn(run()));
+    run_within(Duration::ZERO);
+}
+
+// Schedules the next run to start within `delay`, unless a run is already scheduled sooner
+pub fn run_within(delay: Duration) {
+    let run_at = ic_cdk::api::time() + delay.as_nanos() as u64;
+    if let Some((timer_id, scheduled_at)) = NEXT_RUN.get() {
+        if scheduled_at <= run_at {
+            return;
+        }
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+
+    let timer_id = ic_cdk_timers::set_timer(delay, || {
+        NEXT_RUN.set(None);
+        ic_cdk::spawn(run())
+    });
+    NEXT_RUN.set(Some((timer_id, run_at)));
 }
 
 async fn run() {
-    log("Checking for new NNS votes");
+    let Some(_guard) = RunGuard::try_acquire() else {
+        log("Skipping check for new NNS votes, the previous run is still in progress");
+        // The run in progress schedules the next run once it completes, this ensures polling
+        // continues if it never does
+        run_within(HEARTBEAT_INTERVAL);
+        return;
+    };
+
+    // Ensures polling continues even if this run fails to complete
+    run_within(HEARTBEAT_INTERVAL);
+
+    let now = ic_cdk::api::time();
+    let full_poll = now >= LAST_FULL_POLL.get() + HEARTBEAT_INTERVAL.as_nanos() as u64;
+    if full_poll {
+        LAST_FULL_POLL.set(now);
+    }
 
-    let futures: Vec<_> = state::mutate(|s| {
-        s.neuron_pairs()
-            .values()
-            .map(|p| run_single(p.id(), s.nns_governance_canister_id(), p.nns_neuron_id()))
-            .collect()
+    let (nns_governance_canister_id, backfill_required, plan) = state::read(|s| {
+        (
+            s.nns_governance_canister_id(),
+            s.neuron_pairs()
+                .values()
+                .any(|p| p.nns_ballot_watermark().is_none() && p.backfill_open_proposals()),
+            s.nns_polling_plan(now / NANOS_PER_SECOND),
+        )
     });
 
-    let results = futures::future::join_all(futures).await;
+    log(format!(
+        "Checking for new NNS votes. Full poll: {full_poll}. Neurons pending: {}",
+        plan.nns_neuron_ids.len()
+    ));
+
+    // Newly registered pairs which have opted in to backfilling need to know which proposals are
+    // still open. If these haven't been retrieved yet, those pairs are skipped until the next run.
+    let open_nns_proposals = if backfill_required {
+        state::read(|s| s.open_nns_proposal_ids())
+    } else {
+        Some(BTreeSet::new())
+    };
+
+    let pair_ids_per_nns_neuron = state::read(|s| {
+        pair_ids_per_nns_neuron(
+            s.neuron_pairs().values(),
+            |nns_neuron_id| full_poll || plan.nns_neuron_ids.contains(&nns_neuron_id),
+            open_nns_proposals.is_some(),
+        )
+    });
 
-    let succeeded: usize = results.iter().filter(|success| **success).count();
-    let failed = results.len() - succeeded;
+    let pairs_count: usize = pair_ids_per_nns_neuron.values().map(|ids| ids.len()).sum();
+    let calls_saved = pairs_count - pair_ids_per_nns_neuron.len();
+    state::mutate(|s| s.record_nns_neuron_polls(pair_ids_per_nns_neuron.len(), calls_saved));
+
+    // The neurons are polled in batches of bounded size, with the start of each batch spread
+    // evenly across `BATCH_SPREAD`, to avoid hitting the limits on outstanding calls
+    let batch_size = state::read(|s| s.nns_polling_batch_size()) as usize;
+    let neurons: Vec<_> = pair_ids_per_nns_neuron.into_iter().collect();
+    let batch_offsets = batch_offsets(neurons.len(), batch_size);
+    let start = Duration::from_nanos(ic_cdk::api::time());
+
+    let no_open_proposals = BTreeSet::new();
+    let mut succeeded = 0;
+    let mut failed = 0;
+    for (batch, offset) in neurons.chunks(batch_size).zip(batch_offsets) {
+        let now = Duration::from_nanos(ic_cdk::api::time());
+        if start + offset > now {
+            super::sleep(start + offset - now).await;
+        }
+
+        let futures: Vec<_> = batch
+            .iter()
+            .map(|(nns_neuron_id, pair_ids)| {
+                run_single(
+                    nns_governance_canister_id,
+                    *nns_neuron_id,
+                    pair_ids,
+                    open_nns_proposals.as_ref().unwrap_or(&no_open_proposals),
+                )
+            })
+            .collect();
+
+        let results = futures::future::join_all(futures).await;
+        let batch_succeeded = results.iter().filter(|success| **success).count();
+        succeeded += batch_succeeded;
+        failed += results.len() - batch_succeeded;
+    }
 
     log(format!(
-        "Check for new NNS votes completed. Succeeded: {succeeded}. Failed: {failed}"
+        "Check for new NNS votes completed. Succeeded: {succeeded}. Failed: {failed}. Calls saved: {calls_saved}"
     ));
+
+    state::mutate(|s| s.prune_unfollowed_nns_neurons());
+
+    let now_seconds = ic_cdk::api::time() / NANOS_PER_SECOND;
+    let queued = state::mutate(|s| s.record_nns_votes_for_watched_wtn_proposals(now_seconds));
+    if queued > 0 {
+        log(format!("Votes queued from watched WTN proposals: {queued}"));
+    }
+
+    recover_nns_ballots(nns_governance_canister_id).await;
+
+    let plan = state::read(|s| s.nns_polling_plan(now_seconds));
+    run_within(next_poll_delay(&plan, now_seconds));
+}
+
+// Many pairs follow the same NNS neuron, so each neuron is only polled once and its ballots are
+// then recorded against every pair following it. Pairs which need the open proposals to backfill
+// their votes are left out until those are known.
+fn pair_ids_per_nns_neuron<'a>(
+    pairs: impl Iterator<Item = &'a NeuronPair>,
+    should_poll: impl Fn(u64) -> bool,
+    open_nns_proposals_known: bool,
+) -> BTreeMap<u64, Vec<u64>> {
+    let mut pair_ids_per_nns_neuron: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    for pair in pairs
+        .filter(|p| should_poll(p.nns_neuron_id()))
+        .filter(|p| {
+            open_nns_proposals_known
+                || p.nns_ballot_watermark().is_some()
+                || !p.backfill_open_proposals()
+        })
+    {
+        pair_ids_per_nns_neuron
+            .entry(pair.nns_neuron_id())
+            .or_default()
+            .push(pair.id());
+    }
+    pair_ids_per_nns_neuron
+}
+
+// The delay from the start of the run until each batch of neurons is polled, spreading the batches
+// evenly across `BATCH_SPREAD`
+fn batch_offsets(neuron_count: usize, batch_size: usize) -> Vec<Duration> {
+    let batch_count = neuron_count.div_ceil(batch_size) as u32;
+    let batch_spacing = BATCH_SPREAD.checked_div(batch_count).unwrap_or_default();
+    (0..batch_count)
+        .map(|index| batch_spacing * index)
+        .collect()
+}
+
+// Polls more frequently as the earliest deadline of the proposals which the neurons have yet to
+// vote on approaches. Pairs which are yet to be polled, eg. because they were registered during
+// this run, are polled as soon as possible.
+fn next_poll_delay(plan: &NnsPollingPlan, now_seconds: u64) -> Duration {
+    const HOUR: u64 = 60 * 60;
+
+    if plan.awaiting_first_poll {
+        return MIN_POLL_INTERVAL;
+    }
+    let Some(deadline) = plan.next_deadline_seconds else {
+        return HEARTBEAT_INTERVAL;
+    };
+
+    match deadline.saturating_sub(now_seconds) {
+        t if t <= HOUR => MIN_POLL_INTERVAL,
+        t if t <= 6 * HOUR => Duration::from_secs(60),
+        t if t <= 24 * HOUR => Duration::from_secs(120),
+        _ => Duration::from_secs(300),
+    }
+}
+
+// Looks up the proposals whose ballots fell out of the NNS neurons' recent ballots before they
+// could be seen, relaying the votes on those which are still open. Any remaining proposals are
+// looked up on the following runs.
+async fn recover_nns_ballots(nns_governance_canister_id: Principal) {
+    let proposals =
+        state::read(|s| s.next_nns_proposals_to_recover(MAX_NNS_BALLOTS_TO_RECOVER_PER_RUN));
+
+    if proposals.is_empty() {
+        return;
+    }
+
+    let futures: Vec<_> = proposals
+        .into_iter()
+        .map(|(proposal_id, nns_neuron_ids)| {
+            recover_single(nns_governance_canister_id, proposal_id, nns_neuron_ids)
+        })
+        .collect();
+
+    let results = futures::future::join_all(futures).await;
+    let outcomes: Vec<_> = results.iter().flatten().flatten().collect();
+    let recovered = outcomes
+        .iter()
+        .filter(|o| matches!(o, BallotLookupOutcome::Recovered))
+        .count();
+    let unrecoverable = outcomes
+        .iter()
+        .filter(|o| matches!(o, BallotLookupOutcome::Unrecoverable))
+        .count();
+    let failed = results.iter().filter(|r| r.is_none()).count();
+    let remaining = state::read(|s| s.nns_ballots_to_recover_count());
+
+    log(format!(
+        "Recovery of NNS ballots completed. Recovered: {recovered}. Unrecoverable: {unrecoverable}. Failed: {failed}. Remaining: {remaining}"
+    ));
+}
+
+// Returns the outcome for each of the neurons, or None if the proposal couldn't be retrieved, in
+// which case it is looked up again on the next run
+async fn recover_single(
+    nns_governance_canister_id: Principal,
+    proposal_id: u64,
+    nns_neuron_ids: Vec<u64>,
+) -> Option<Vec<BallotLookupOutcome>> {
+    match nns_governance::get_proposal_info(nns_governance_canister_id, proposal_id).await {
+        Ok(proposal) => {
+            let now_seconds = ic_cdk::api::time() / NANOS_PER_SECOND;
+            let outcomes = state::mutate(|s| {
+                nns_neuron_ids
+                    .into_iter()
+                    .map(|nns_neuron_id| {
+                        // If the proposal doesn't exist then there is nothing to recover
+                        let vote = proposal.as_ref().and_then(|p| p.vote(nns_neuron_id));
+                        let is_open = proposal.as_ref().is_some_and(|p| p.is_open(now_seconds));
+                        s.record_nns_ballot_lookup(nns_neuron_id, proposal_id, vote, is_open)
+                    })
+                    .collect()
+            });
+            Some(outcomes)
+        }
+        Err(error) => {
+            log(format!(
+                "Error calling `get_proposal_info`: {error:?}. ProposalId: {proposal_id}"
+            ));
+            None
+        }
+    }
 }
 
 async fn run_single(
-    pair_id: u64,
     nns_governance_canister_id: Principal,
     nns_neuron_id: u64,
+    pair_ids: &[u64],
+    open_nns_proposals: &BTreeSet<u64>,
 ) -> bool {
-    match get_neuron_info(nns_governance_canister_id, nns_neuron_id).await {
+    match nns_governance::get_neuron_info(nns_governance_canister_id, nns_neuron_id).await {
         Ok(Ok(neuron)) => {
+            let ballot_proposal_ids = neuron.ballot_proposal_ids();
+            let votes = neuron.votes();
             state::mutate(|s| {
-                for vote in neuron
-                    .recent_ballots
-                    .into_iter()
-                    .filter_map(|b| NnsVote::try_from(b).ok())
-                {
-                    s.record_nns_vote(pair_id, vote);
+                s.record_nns_ballot_poll(nns_neuron_id, &ballot_proposal_ids);
+                s.record_nns_neuron_votes(nns_neuron_id, &votes);
+                for pair_id in pair_ids {
+                    s.record_nns_votes(
+                        *pair_id,
+                        votes.clone(),
+                        open_nns_proposals,
+                        ic_cdk::api::time(),
+                    );
                 }
             });
             true
         }
         error => {
-            log(format!("Error calling `get_neuron_info`: {error:?}"));
+            log(format!(
+                "Error calling `get_neuron_info`: {error:?}. NnsNeuronId: {nns_neuron_id}"
+            ));
             false
         }
     }
 }
 
-async fn get_neuron_info(
-    nns_governance_canister_id: Principal,
-    nns_neuron_id: u64,
-) -> CallResult<Result<NeuronInfo, GovernanceError>> {
-    let response: CallResult<(Result<NeuronInfo, GovernanceError>,)> = ic_cdk::call(
-        nns_governance_canister_id,
-        "get_neuron_info",
-        (nns_neuron_id,),
-    )
-    .await;
-
-    response.map(|r| r.0)
-}
+// Marks a run as in progress until dropped, so that a slow run isn't overlapped by the next one
+struct RunGuard;
 
-#[derive(CandidType, Deserialize, Debug)]
-struct NeuronInfo {
-    recent_ballots: Vec<BallotInfo>,
+impl RunGuard {
+    fn try_acquire() -> Option<RunGuard> {
+        (!RUN_IN_PROGRESS.replace(true)).then_some(RunGuard)
+    }
 }
 
-#[derive(CandidType, Deserialize, Debug)]
-struct BallotInfo {
-    vote: i32,
-    proposal_id: Option<ProposalId>,
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUN_IN_PROGRESS.set(false);
+    }
 }
 
-#[derive(CandidType, Deserialize, Debug)]
-struct ProposalId {
-    id: u64,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neuron_pair::NewNeuronPair;
 
-#[derive(CandidType, Deserialize, Debug)]
-struct GovernanceError {
-    error_message: String,
-    error_type: i32,
-}
+    fn plan(next_deadline_seconds: Option<u64>, awaiting_first_poll: bool) -> NnsPollingPlan {
+        NnsPollingPlan {
+            nns_neuron_ids: BTreeSet::new(),
+            next_deadline_seconds,
+            awaiting_first_poll,
+        }
+    }
 
-impl TryFrom<BallotInfo> for NnsVote {
-    type Error = ();
+    fn pair(nns_neuron_id: u64, wtn_neuron_id: u8, backfill_open_proposals: bool) -> NeuronPair {
+        let args = NewNeuronPair {
+            name: format!("Pair{wtn_neuron_id}"),
+            nns_neuron_id,
+            nns_known_neuron_name: None,
+            wtn_neuron_id: [wtn_neuron_id; 32],
+            backfill_open_proposals,
+        };
+        NeuronPair::new(Principal::from_slice(&[1]), args, 0)
+    }
 
-    fn try_from(value: BallotInfo) -> Result<Self, Self::Error> {
-        if let Some(proposal_id) = value.proposal_id {
-            Ok(NnsVote {
-                proposal_id: proposal_id.id,
-                adopt: value.vote == 1,
-            })
-        } else {
-            Err(())
-        }
+    #[test]
+    fn each_nns_neuron_is_polled_once_for_all_of_the_pairs_following_it() {
+        let pairs = [
+            pair(1, 1, false),
+            pair(2, 2, false),
+            pair(1, 3, false),
+            pair(1, 4, false),
+            pair(3, 5, false),
+        ];
+        let pair_ids: Vec<_> = pairs.iter().map(|p| p.id()).collect();
+
+        let grouped = pair_ids_per_nns_neuron(pairs.iter(), |id| id != 3, true);
+
+        assert_eq!(
+            grouped,
+            BTreeMap::from([
+                (1, vec![pair_ids[0], pair_ids[2], pair_ids[3]]),
+                (2, vec![pair_ids[1]]),
+            ])
+        );
+        let pairs_count: usize = grouped.values().map(|ids| ids.len()).sum();
+        assert_eq!(pairs_count - grouped.len(), 2);
+    }
+
+    #[test]
+    fn pairs_which_backfill_wait_for_the_open_proposals() {
+        let pairs = [pair(1, 1, true), pair(1, 2, false)];
+
+        let grouped = pair_ids_per_nns_neuron(pairs.iter(), |_| true, false);
+        assert_eq!(grouped, BTreeMap::from([(1, vec![pairs[1].id()])]));
+
+        let grouped = pair_ids_per_nns_neuron(pairs.iter(), |_| true, true);
+        assert_eq!(grouped.get(&1).map(|ids| ids.len()), Some(2));
+    }
+
+    #[test]
+    fn batches_are_spread_evenly_across_the_batch_spread() {
+        assert!(batch_offsets(0, 50).is_empty());
+        assert_eq!(batch_offsets(50, 50), vec![Duration::ZERO]);
+        assert_eq!(
+            batch_offsets(101, 50),
+            vec![
+                Duration::ZERO,
+                Duration::from_secs(5),
+                Duration::from_secs(10)
+            ]
+        );
+
+        let offsets = batch_offsets(1000, 7);
+        assert_eq!(offsets.len(), 143);
+        assert!(offsets.iter().all(|o| *o < BATCH_SPREAD));
+    }
+
+    #[test]
+    fn polls_faster_as_deadlines_approach() {
+        const HOUR: u64 = 60 * 60;
+        let now = 1_000_000;
+
+        assert_eq!(next_poll_delay(&plan(None, false), now), HEARTBEAT_INTERVAL);
+        assert_eq!(
+            next_poll_delay(&plan(Some(now + 2 * 24 * HOUR), false), now),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            next_poll_delay(&plan(Some(now + 12 * HOUR), false), now),
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            next_poll_delay(&plan(Some(now + 2 * HOUR), false), now),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            next_poll_delay(&plan(Some(now + 60), false), now),
+            MIN_POLL_INTERVAL
+        );
+        assert_eq!(
+            next_poll_delay(&plan(Some(now - 60), false), now),
+            MIN_POLL_INTERVAL
+        );
+    }
+
+    #[test]
+    fn newly_registered_pairs_are_polled_without_waiting_for_the_heartbeat() {
+        assert_eq!(next_poll_delay(&plan(None, true), 0), MIN_POLL_INTERVAL);
     }
 }
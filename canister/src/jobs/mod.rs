@@ -1,9 +1,66 @@
+use crate::logs::log;
 use crate::state::State;
+use crate::{state, wtn_governance};
+use futures::channel::oneshot;
+use std::time::Duration;
 
-mod check_for_new_nns_votes;
+pub mod check_for_new_nns_votes;
 pub mod process_votes;
+mod reconcile_votes;
+mod refresh_known_neurons;
+mod sweep_vote_leases;
+mod track_open_nns_proposals;
+mod watch_wtn_proposals;
 
 pub fn start_jobs(state: &State) {
     check_for_new_nns_votes::start_job();
     process_votes::start_job_if_required(state);
+    reconcile_votes::start_job();
+    refresh_known_neurons::start_job();
+    sweep_vote_leases::start_job();
+    track_open_nns_proposals::start_job();
+    watch_wtn_proposals::start_job();
+}
+
+// Completes once `duration` has elapsed, allowing a job to pause between steps
+async fn sleep(duration: Duration) {
+    let (sender, receiver) = oneshot::channel();
+    ic_cdk_timers::set_timer(duration, move || {
+        let _ = sender.send(());
+    });
+    let _ = receiver.await;
+}
+
+// Returns the id of WTN governance's "Vote for NNS Proposals" function, looking it up if it isn't
+// yet known. Returns None if it can't be determined, in which case no votes can be cast.
+async fn wtn_vote_for_nns_proposals_function_id() -> Option<u64> {
+    let (function_id, wtn_governance_canister_id) = state::read(|s| {
+        (
+            s.wtn_vote_for_nns_proposals_function_id(),
+            s.wtn_governance_canister_id(),
+        )
+    });
+    if function_id.is_some() {
+        return function_id;
+    }
+
+    match wtn_governance::list_nervous_system_functions(wtn_governance_canister_id).await {
+        Ok(functions) => {
+            let function_id = wtn_governance::find_vote_for_nns_proposals_function(&functions);
+            match function_id {
+                Some(id) => state::mutate(|s| s.set_wtn_vote_for_nns_proposals_function_id(id)),
+                None => log(format!(
+                    "Unable to find the WTN \"Vote for NNS Proposals\" function. Functions: {}",
+                    functions.len()
+                )),
+            }
+            function_id
+        }
+        Err(error) => {
+            log(format!(
+                "Error calling `list_nervous_system_functions`: {error:?}"
+            ));
+            None
+        }
+    }
 }
@@ -1,152 +1,866 @@
 use crate::logs::log;
+use crate::single_flight::SingleFlight;
 use crate::state::State;
-use crate::{state, VoteToProcess, WtnVote};
+use crate::vote_retries::MAX_VOTE_ATTEMPTS;
+use crate::wtn_governance::{GovernanceError, ProposalData, WtnProposal};
+use crate::{
+    state, wtn_governance, Alert, AlertKind, PairEvent, PairEventKind, SnsError, SnsErrorKind,
+    Vote, VoteConfirmation, VoteSkippedReason, WtnProposalMappingSource, WtnProposalMappingStatus,
+    WtnVote,
+};
 use candid::CandidType;
 use ic_cdk::api::call::CallResult;
 use ic_cdk_timers::TimerId;
 use ic_principal::Principal;
 use serde::{Deserialize, Serialize};
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::Duration;
 
+// If the WTN proposal ids of some NNS proposals couldn't be determined, they aren't attempted again
+// until this delay has passed
+const RESOLUTION_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// The maximum number of votes which can be in flight to a single governance canister at once. Each
+// WTN neuron only ever has one vote in flight, so that its votes are cast in the order queued.
+const MAX_CONCURRENT_VOTES_PER_CANISTER: usize = 10;
+
+// How long the WTN protocol canister can leave an NNS proposal unresolved before WTN governance is
+// searched for its mirror proposal instead
+const PROTOCOL_CANISTER_LAG_THRESHOLD: Duration = Duration::from_secs(5 * 60); // 5 minutes
+
+// The number of WTN governance's most recent proposals searched for mirror proposals
+const MIRROR_PROPOSAL_SEARCH_LIMIT: u32 = 100;
+
 thread_local! {
-    static TIMER_ID: Cell<Option<TimerId>> = Cell::default();
+    // The timer which runs the job, along with the time it is set for
+    static TIMER_ID: Cell<Option<(TimerId, u64)>> = Cell::default();
+    // The timer which runs the job once the earliest vote waiting to be retried becomes ready,
+    // along with the time it is set for
+    static RETRY_TIMER: Cell<Option<(TimerId, u64)>> = Cell::default();
+    static RESOLUTION_IN_PROGRESS: Cell<bool> = Cell::default();
+    static NEXT_RESOLUTION_AT: Cell<u64> = Cell::default();
+    static FIRST_UNRESOLVED_AT: RefCell<BTreeMap<u64, u64>> = RefCell::default();
+    static WTN_PROPOSAL_ID_LOOKUPS: SingleFlight<u64, CallResult<Result<ProposalId, ProposalId>>> =
+        SingleFlight::default();
+    static IN_FLIGHT: RefCell<InFlightVotes> = RefCell::default();
+}
+
+#[derive(Default)]
+struct InFlightVotes {
+    per_canister: BTreeMap<Principal, usize>,
+    wtn_neuron_ids: BTreeSet<[u8; 32]>,
+}
+
+impl InFlightVotes {
+    // Returns the WTN neurons which already have a vote in flight, or None if the governance
+    // canister has no free slots
+    fn busy_wtn_neuron_ids(&self, canister_id: Principal) -> Option<BTreeSet<[u8; 32]>> {
+        let in_flight = self
+            .per_canister
+            .get(&canister_id)
+            .copied()
+            .unwrap_or_default();
+        (in_flight < MAX_CONCURRENT_VOTES_PER_CANISTER).then(|| self.wtn_neuron_ids.clone())
+    }
 }
 
 pub(crate) fn start_job_if_required(state: &State) {
-    if TIMER_ID.get().is_none() && state.votes_to_process_count() > 0 {
-        let timer_id = ic_cdk_timers::set_timer(Duration::ZERO, run);
-        TIMER_ID.set(Some(timer_id));
+    if state.votes_to_process_count() > 0 {
+        run_within(Duration::ZERO);
+    }
+}
+
+// Schedules the job to run within `delay`, unless it is already scheduled to run sooner
+fn run_within(delay: Duration) {
+    let run_at = ic_cdk::api::time() + delay.as_nanos() as u64;
+    if let Some((timer_id, scheduled_at)) = TIMER_ID.get() {
+        if scheduled_at <= run_at {
+            return;
+        }
+        ic_cdk_timers::clear_timer(timer_id);
     }
+
+    let timer_id = ic_cdk_timers::set_timer(delay, run);
+    TIMER_ID.set(Some((timer_id, run_at)));
 }
 
 fn run() {
     TIMER_ID.set(None);
-    if let Some(vote) = state::mutate(|s| s.pop_next_vote_to_process()) {
-        ic_cdk::spawn(process_vote(vote));
+
+    let now = ic_cdk::api::time();
+    let unresolved = state::mutate(|s| {
+        s.promote_nns_votes(now);
+        s.unresolved_nns_proposal_ids(now)
+    });
+
+    if !unresolved.is_empty() && !RESOLUTION_IN_PROGRESS.get() {
+        let next_resolution_at = NEXT_RESOLUTION_AT.get();
+        if now >= next_resolution_at {
+            ic_cdk::spawn(resolve_wtn_proposal_ids(unresolved));
+        } else {
+            run_within(Duration::from_nanos(next_resolution_at - now));
+        }
+    }
+
+    // Starts as many queued votes as the limits allow. Votes whose WTN neuron already has a vote in
+    // flight are left in the queue, so a slow or failing neuron doesn't hold up the others.
+    let canister_id = state::read(|s| s.wtn_governance_canister_id());
+    while let Some((pair_id, wtn_vote, slot)) = next_vote(canister_id) {
+        ic_cdk::spawn(process_vote(pair_id, wtn_vote, slot));
+    }
+
+    state::read(schedule_next_retry);
+}
+
+// Ensures the job runs again once the earliest vote waiting to be retried becomes ready, since
+// nothing else may happen to trigger it in the meantime
+fn schedule_next_retry(state: &State) {
+    let now = ic_cdk::api::time();
+    let Some(retry_at) = state.next_vote_retry_at(now) else {
+        return;
+    };
+
+    if let Some((timer_id, scheduled_at)) = RETRY_TIMER.get() {
+        if scheduled_at <= retry_at {
+            return;
+        }
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+
+    let timer_id = ic_cdk_timers::set_timer(Duration::from_nanos(retry_at - now), || {
+        RETRY_TIMER.set(None);
+        run();
+    });
+    RETRY_TIMER.set(Some((timer_id, retry_at)));
+}
+
+fn next_vote(canister_id: Principal) -> Option<(u64, WtnVote, VoteSlot)> {
+    let busy_wtn_neuron_ids = IN_FLIGHT.with_borrow(|f| f.busy_wtn_neuron_ids(canister_id))?;
+
+    let now = ic_cdk::api::time();
+    let (pair_id, wtn_vote, wtn_neuron_id, lease_id) = state::mutate(|s| {
+        let (pair_id, wtn_vote) = s.pop_next_pending_wtn_vote(&busy_wtn_neuron_ids, now)?;
+        let wtn_neuron_id = s.neuron_pairs().get(&pair_id).map(|p| p.wtn_neuron_id());
+        let lease_id = s.lease_vote(pair_id, wtn_vote.clone(), now);
+        Some((pair_id, wtn_vote, wtn_neuron_id, lease_id))
+    })?;
+
+    Some((
+        pair_id,
+        wtn_vote,
+        VoteSlot::acquire(canister_id, wtn_neuron_id, lease_id),
+    ))
+}
+
+// Resolves the WTN proposal ids of every distinct NNS proposal which has votes waiting on it, with
+// a single lookup per NNS proposal, then promotes all of the waiting votes at once. Only one batch
+// is in flight at a time, so concurrent lookups for the same NNS proposal are never made.
+//
+// The WTN protocol canister usually takes a while to process new NNS proposals, so it is simply
+// asked again on the next batch. Only those NNS proposals which it still hasn't resolved after
+// `PROTOCOL_CANISTER_LAG_THRESHOLD` are searched for in WTN governance, with a single scan of its
+// recent proposals covering the whole batch.
+async fn resolve_wtn_proposal_ids(nns_proposal_ids: BTreeSet<u64>) {
+    let guard = ResolutionGuard::acquire();
+    log(format!(
+        "Resolving WTN proposal ids. NNS proposals: {}",
+        nns_proposal_ids.len()
+    ));
+
+    let futures: Vec<_> = nns_proposal_ids
+        .into_iter()
+        .map(|id| async move { (id, resolve_with_protocol_canister(id).await) })
+        .collect();
+
+    let unresolved: BTreeSet<_> = futures::future::join_all(futures)
+        .await
+        .into_iter()
+        .filter(|(_, resolved)| !resolved)
+        .map(|(id, _)| id)
+        .collect();
+
+    let lagged = FIRST_UNRESOLVED_AT.with_borrow_mut(|first_unresolved_at| {
+        lagged_past_threshold(first_unresolved_at, &unresolved, ic_cdk::api::time())
+    });
+    let found = if lagged.is_empty() {
+        BTreeSet::new()
+    } else {
+        resolve_with_wtn_governance(&lagged).await
+    };
+
+    let retries = unresolved.difference(&found).count();
+    if retries > 0 {
+        log(format!(
+            "Unable to resolve WTN proposal ids, will retry shortly. NNS proposals: {retries}"
+        ));
+        NEXT_RESOLUTION_AT.set(ic_cdk::api::time() + RESOLUTION_RETRY_DELAY.as_nanos() as u64);
+        run_within(RESOLUTION_RETRY_DELAY);
+    }
+
+    drop(guard);
+    state::read(start_job_if_required);
+}
+
+// Marks a batch of resolutions as in flight until dropped
+struct ResolutionGuard;
+
+impl ResolutionGuard {
+    fn acquire() -> ResolutionGuard {
+        RESOLUTION_IN_PROGRESS.set(true);
+        ResolutionGuard
+    }
+}
+
+impl Drop for ResolutionGuard {
+    fn drop(&mut self) {
+        RESOLUTION_IN_PROGRESS.set(false);
+    }
+}
+
+// Holds one of the governance canister's slots, and the WTN neuron's slot, until dropped. The
+// vote's lease is released explicitly rather than on drop, since the slot is also dropped when
+// cleaning up after a trap, in which case the vote must be left to be requeued.
+struct VoteSlot {
+    canister_id: Principal,
+    wtn_neuron_id: Option<[u8; 32]>,
+    lease_id: u64,
+}
+
+impl VoteSlot {
+    fn acquire(canister_id: Principal, wtn_neuron_id: Option<[u8; 32]>, lease_id: u64) -> VoteSlot {
+        IN_FLIGHT.with_borrow_mut(|f| {
+            *f.per_canister.entry(canister_id).or_default() += 1;
+            if let Some(id) = wtn_neuron_id {
+                f.wtn_neuron_ids.insert(id);
+            }
+        });
+        VoteSlot {
+            canister_id,
+            wtn_neuron_id,
+            lease_id,
+        }
+    }
+}
+
+impl Drop for VoteSlot {
+    fn drop(&mut self) {
+        IN_FLIGHT.with_borrow_mut(|f| {
+            if let Some(count) = f.per_canister.get_mut(&self.canister_id) {
+                *count = count.saturating_sub(1);
+            }
+            if let Some(id) = self.wtn_neuron_id {
+                f.wtn_neuron_ids.remove(&id);
+            }
+        });
     }
 }
 
-async fn process_vote(vote: VoteToProcess) {
-    let vote_string = format!("{vote:?}");
+async fn process_vote(pair_id: u64, wtn_vote: WtnVote, slot: VoteSlot) {
+    let vote_string = format!("{wtn_vote:?}. PairId: {pair_id}");
     log(format!("Processing vote: {vote_string}"));
 
-    match vote {
-        VoteToProcess::NnsVote(pair_id, nns_vote) => {
-            let vote_to_process = match state::read(|s| {
-                s.get_cached_wtn_proposal_for_nns_proposal(nns_vote.proposal_id)
-            }) {
-                Some(Some(wtn_proposal_id)) => Some(VoteToProcess::PendingWtnVote(
-                    pair_id,
-                    WtnVote {
-                        nns_proposal_id: nns_vote.proposal_id,
-                        wtn_proposal_id,
-                        adopt: nns_vote.adopt,
-                    },
-                )),
-                Some(None) => None,
-                None => {
-                    // Didn't find the WTN proposal in the cache, so call
-                    // into WTN canister to retrieve it
-                    let canister_id = state::read(|s| s.wtn_protocol_canister_id());
-                    let response = get_wtn_proposal_id(canister_id, nns_vote.proposal_id).await;
-                    match response {
-                        Ok(Ok(wtn_proposal_id)) => {
-                            state::mutate(|s| {
-                                s.record_wtn_proposal_for_nns_proposal(
-                                    nns_vote.proposal_id,
-                                    Some(wtn_proposal_id.id),
-                                )
-                            });
-                            Some(VoteToProcess::PendingWtnVote(
-                                pair_id,
-                                WtnVote {
-                                    nns_proposal_id: nns_vote.proposal_id,
-                                    wtn_proposal_id: wtn_proposal_id.id,
-                                    adopt: nns_vote.adopt,
-                                },
-                            ))
-                        }
-                        Ok(Err(latest_processed_nns_proposal_id)) => {
-                            if latest_processed_nns_proposal_id.id >= nns_vote.proposal_id {
-                                state::mutate(|s| {
-                                    s.record_wtn_proposal_for_nns_proposal(
-                                        nns_vote.proposal_id,
-                                        None,
-                                    )
-                                });
-                                log(format!(
-                                    "No WTN proposal found for NNS proposal {}",
-                                    nns_vote.proposal_id
-                                ));
-                                None
-                            } else {
-                                // The WTN canister hasn't processed this NNS proposal yet, so put the NNS
-                                // proposal back in the queue for it to be attempted again shortly
-                                log(format!(
-                                    "WTN canister has not processed NNS proposal yet. ProposalId: {}. Latest processed: {}",
-                                    nns_vote.proposal_id,
-                                    latest_processed_nns_proposal_id.id
-                                ));
-                                Some(VoteToProcess::NnsVote(pair_id, nns_vote))
-                            }
-                        }
-                        Err(error) => {
-                            log(format!("Error calling `get_wtn_proposal_id`: {error:?}"));
-                            Some(VoteToProcess::NnsVote(pair_id, nns_vote))
-                        }
+    let outcome = process_pending_wtn_vote(pair_id, wtn_vote.clone()).await;
+
+    log(format!(
+        "Finished processing vote: {vote_string}. Outcome: {outcome:?}"
+    ));
+
+    state::mutate(|s| {
+        match outcome {
+            VoteOutcome::Done => s.clear_vote_retry(pair_id, wtn_vote.wtn_proposal_id),
+            VoteOutcome::Retry => retry_vote(s, pair_id, wtn_vote, ic_cdk::api::time()),
+        }
+        s.release_vote_lease(slot.lease_id);
+    });
+    drop(slot);
+    state::read(start_job_if_required);
+}
+
+#[derive(Debug)]
+enum VoteOutcome {
+    // The vote was cast, or will never be cast, so nothing more needs to be done with it
+    Done,
+    // The vote failed with a transient error, so it should be attempted again after a backoff
+    Retry,
+}
+
+// Requeues the vote to be retried after a backoff, or if it has failed too many times, drops it
+// and raises an alert
+fn retry_vote(state: &mut State, pair_id: u64, wtn_vote: WtnVote, now: u64) {
+    if state.retry_vote_to_process(pair_id, wtn_vote.clone(), now) {
+        return;
+    }
+
+    state.raise_alert(Alert {
+        timestamp: now,
+        pair_id: Some(pair_id),
+        kind: AlertKind::VoteRetriesExhausted {
+            nns_proposal_id: wtn_vote.nns_proposal_id,
+            wtn_proposal_id: wtn_vote.wtn_proposal_id,
+            attempts: MAX_VOTE_ATTEMPTS,
+        },
+    });
+    record_skipped_event(
+        state,
+        pair_id,
+        &wtn_vote,
+        VoteSkippedReason::RetriesExhausted,
+    );
+}
+
+async fn process_pending_wtn_vote(pair_id: u64, wtn_vote: WtnVote) -> VoteOutcome {
+    let Some((canister_id, neuron_id, suspended)) = state::read(|s| {
+        s.neuron_pairs().get(&pair_id).map(|p| {
+            (
+                s.wtn_governance_canister_id(),
+                p.wtn_neuron_id(),
+                p.is_suspended(),
+            )
+        })
+    }) else {
+        return VoteOutcome::Done;
+    };
+
+    if suspended {
+        state::mutate(|s| {
+            record_skipped_event(s, pair_id, &wtn_vote, VoteSkippedReason::PairSuspended)
+        });
+        return VoteOutcome::Done;
+    }
+
+    match check_wtn_proposal(canister_id, &wtn_vote).await {
+        ProposalCheckResult::Vote => {}
+        ProposalCheckResult::Skip(reason) => {
+            state::mutate(|s| record_skipped_event(s, pair_id, &wtn_vote, reason));
+            return VoteOutcome::Done;
+        }
+        ProposalCheckResult::Failed(error) => {
+            return handle_governance_error(pair_id, wtn_vote, error, ErrorSource::GetProposal);
+        }
+        ProposalCheckResult::Retry => return VoteOutcome::Retry,
+    }
+
+    // If the vote was lost while in flight it may already have been cast, in which case the
+    // neuron's ballot is recorded rather than the vote being cast again
+    if state::read(|s| s.wtn_vote_requires_ballot_check(pair_id, wtn_vote.wtn_proposal_id)) {
+        let Some(ballot) = get_wtn_ballot(canister_id, pair_id, neuron_id, &wtn_vote).await else {
+            return VoteOutcome::Retry;
+        };
+
+        let now = ic_cdk::api::time();
+        let already_cast = state::mutate(|s| {
+            s.clear_wtn_vote_ballot_check(pair_id, wtn_vote.wtn_proposal_id);
+            match ballot {
+                Vote::Unspecified => false,
+                ballot if ballot == wtn_vote.vote() => {
+                    let recorded = s
+                        .neuron_pairs()
+                        .get(&pair_id)
+                        .is_some_and(|p| p.has_wtn_vote(wtn_vote.wtn_proposal_id));
+                    if !recorded {
+                        s.record_wtn_vote_registered(pair_id, wtn_vote.clone(), now);
                     }
+                    true
+                }
+                earlier_vote => {
+                    record_preempted_event(s, pair_id, &wtn_vote, Some(earlier_vote), now);
+                    true
                 }
-            };
-            if let Some(vote) = vote_to_process {
-                state::mutate(|s| s.push_vote_to_process(vote));
             }
+        });
+        if already_cast {
+            return VoteOutcome::Done;
         }
-        VoteToProcess::PendingWtnVote(pair_id, wtn_vote) => {
-            if let Some((canister_id, neuron_id)) = state::read(|s| {
-                s.neuron_pairs()
-                    .get(&pair_id)
-                    .map(|p| (s.wtn_governance_canister_id(), p.wtn_neuron_id()))
-            }) {
-                let args = ManageNeuronArgs {
-                    subaccount: neuron_id.to_vec(),
-                    command: Some(Command::RegisterVote(RegisterVote {
-                        proposal: Some(ProposalId {
-                            id: wtn_vote.wtn_proposal_id,
-                        }),
-                        vote: if wtn_vote.adopt { 1 } else { 2 },
-                    })),
-                };
-                let response: CallResult<(ManageNeuronResponse,)> =
-                    ic_cdk::call(canister_id, "manage_neuron", (&args,)).await;
-                state::mutate(|s| match response.map(|r| r.0.command) {
-                    Ok(Some(CommandResponse::RegisterVote(_))) => {
-                        s.record_wtn_vote_registered(pair_id, wtn_vote);
-                    }
-                    Ok(Some(CommandResponse::Error(error))) => {
-                        log(format!(
-                            "Governance canister returned an error: {error:?}. Args: {args:?}"
-                        ));
-                    }
-                    Ok(None) => {
-                        log(format!(
-                            "Governance canister returned an empty response. Args: {args:?}"
-                        ));
-                    }
-                    Err(error) => {
-                        log(format!(
-                            "Error calling `manage_neuron`: {error:?}. Args: {args:?}"
-                        ));
-                        s.push_vote_to_process(VoteToProcess::PendingWtnVote(pair_id, wtn_vote));
-                    }
-                });
+    }
+
+    let args = ManageNeuronArgs {
+        subaccount: neuron_id.to_vec(),
+        command: Some(Command::RegisterVote(RegisterVote {
+            proposal: Some(ProposalId {
+                id: wtn_vote.wtn_proposal_id,
+            }),
+            vote: if wtn_vote.adopt { 1 } else { 2 },
+        })),
+    };
+    let response: CallResult<(ManageNeuronResponse,)> =
+        ic_cdk::call(canister_id, "manage_neuron", (&args,)).await;
+    let follow_up = state::mutate(|s| match response.map(|r| r.0.command) {
+        Ok(Some(CommandResponse::RegisterVote(_))) => {
+            s.record_wtn_vote_registered(pair_id, wtn_vote.clone(), ic_cdk::api::time());
+            FollowUp::ConfirmVote
+        }
+        // SNS governance rejects votes from neurons which have already voted on the proposal,
+        // which happens when the neuron's followees vote before the relay does
+        Ok(Some(CommandResponse::Error(error))) if error.kind() == SnsErrorKind::AlreadyVoted => {
+            log(format!(
+                "WTN neuron has already voted. PairId: {pair_id}. Args: {args:?}"
+            ));
+            s.record_sns_error(SnsErrorKind::AlreadyVoted);
+            FollowUp::RecordPreemption
+        }
+        Ok(Some(CommandResponse::Error(error))) => {
+            log(format!(
+                "Governance canister returned an error: {error:?}. Args: {args:?}"
+            ));
+            FollowUp::HandleError(error.into_sns_error())
+        }
+        Ok(None) => {
+            // The vote may still have been registered, which the confirmation will determine
+            log(format!(
+                "Governance canister returned an empty response. Args: {args:?}"
+            ));
+            FollowUp::ConfirmVote
+        }
+        Err(error) => {
+            log(format!(
+                "Error calling `manage_neuron`: {error:?}. Args: {args:?}"
+            ));
+            FollowUp::Retry
+        }
+    });
+
+    match follow_up {
+        FollowUp::ConfirmVote => {
+            confirm_wtn_vote(canister_id, pair_id, neuron_id, wtn_vote).await;
+            VoteOutcome::Done
+        }
+        FollowUp::RecordPreemption => {
+            record_preemption(canister_id, pair_id, neuron_id, wtn_vote).await;
+            VoteOutcome::Done
+        }
+        FollowUp::HandleError(error) => {
+            handle_governance_error(pair_id, wtn_vote, error, ErrorSource::ManageNeuron)
+        }
+        FollowUp::Retry => VoteOutcome::Retry,
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum ErrorSource {
+    GetProposal,
+    ManageNeuron,
+}
+
+// What to do with a vote when WTN governance returns an error
+#[derive(Debug)]
+enum ErrorAction {
+    // The error is transient, so the vote is retried after a backoff
+    Retry,
+    // The vote can never succeed, so it is dropped
+    Drop,
+    // The relay can't vote on behalf of the WTN neuron, so the pair is suspended
+    Suspend,
+    // The error is unexpected, so the vote is dropped and an alert is raised
+    Alert,
+}
+
+fn error_action(kind: SnsErrorKind, source: ErrorSource) -> ErrorAction {
+    match kind {
+        kind if kind.is_transient() => ErrorAction::Retry,
+        SnsErrorKind::AlreadyVoted | SnsErrorKind::ProposalClosed => ErrorAction::Drop,
+        SnsErrorKind::NotFound if source == ErrorSource::GetProposal => ErrorAction::Drop,
+        // The proposal has already been retrieved by the time the vote is cast, so if something
+        // can't be found when voting, it is the WTN neuron
+        SnsErrorKind::NotFound | SnsErrorKind::NotAuthorized => ErrorAction::Suspend,
+        _ => ErrorAction::Alert,
+    }
+}
+
+fn handle_governance_error(
+    pair_id: u64,
+    wtn_vote: WtnVote,
+    error: SnsError,
+    source: ErrorSource,
+) -> VoteOutcome {
+    let action = error_action(error.kind, source);
+    log(format!(
+        "Handling WTN governance error: {error:?}. Action: {action:?}. PairId: {pair_id}"
+    ));
+
+    let now = ic_cdk::api::time();
+    state::mutate(|s| {
+        s.record_sns_error(error.kind);
+        match action {
+            ErrorAction::Retry => return VoteOutcome::Retry,
+            ErrorAction::Drop => {}
+            ErrorAction::Suspend => s.suspend_neuron_pair(pair_id, error.clone(), now),
+            ErrorAction::Alert => s.raise_alert(Alert {
+                timestamp: now,
+                pair_id: Some(pair_id),
+                kind: AlertKind::GovernanceError {
+                    nns_proposal_id: wtn_vote.nns_proposal_id,
+                    wtn_proposal_id: wtn_vote.wtn_proposal_id,
+                    error: error.clone(),
+                },
+            }),
+        }
+        record_skipped_event(s, pair_id, &wtn_vote, VoteSkippedReason::SnsError(error));
+        VoteOutcome::Done
+    })
+}
+
+fn record_skipped_event(
+    state: &mut State,
+    pair_id: u64,
+    wtn_vote: &WtnVote,
+    reason: VoteSkippedReason,
+) {
+    state.record_pair_event(
+        pair_id,
+        PairEvent {
+            timestamp: ic_cdk::api::time(),
+            nns_proposal_id: wtn_vote.nns_proposal_id,
+            wtn_proposal_id: Some(wtn_vote.wtn_proposal_id),
+            kind: PairEventKind::VoteSkipped(reason),
+        },
+    )
+}
+
+// Checks that the payload of the WTN proposal references the NNS proposal it has been mapped to,
+// quarantining the mapping and raising an alert if it doesn't. Returns true if the mapping was verified.
+fn verify_mapping(wtn_vote: &WtnVote, proposal: &ProposalData) -> bool {
+    let referenced_nns_proposal_id = proposal.referenced_nns_proposal_id();
+    let verified = referenced_nns_proposal_id == Some(wtn_vote.nns_proposal_id);
+    let status = if verified {
+        WtnProposalMappingStatus::Verified
+    } else {
+        WtnProposalMappingStatus::Quarantined
+    };
+
+    state::mutate(|s| {
+        let changed = s.set_wtn_proposal_mapping_status(
+            wtn_vote.nns_proposal_id,
+            wtn_vote.wtn_proposal_id,
+            status,
+        );
+        if changed && !verified {
+            s.raise_alert(Alert {
+                timestamp: ic_cdk::api::time(),
+                pair_id: None,
+                kind: AlertKind::MappingMismatch {
+                    nns_proposal_id: wtn_vote.nns_proposal_id,
+                    wtn_proposal_id: wtn_vote.wtn_proposal_id,
+                    referenced_nns_proposal_id,
+                },
+            });
+        }
+    });
+    verified
+}
+
+enum FollowUp {
+    Retry,
+    ConfirmVote,
+    RecordPreemption,
+    HandleError(SnsError),
+}
+
+// Looks up the vote which was cast before the relay's vote so that members can see whether their
+// following setup is voting the same way as the NNS neuron
+async fn record_preemption(
+    canister_id: Principal,
+    pair_id: u64,
+    neuron_id: [u8; 32],
+    wtn_vote: WtnVote,
+) {
+    let earlier_vote = get_wtn_ballot(canister_id, pair_id, neuron_id, &wtn_vote).await;
+
+    state::mutate(|s| {
+        record_preempted_event(s, pair_id, &wtn_vote, earlier_vote, ic_cdk::api::time())
+    });
+}
+
+fn record_preempted_event(
+    state: &mut State,
+    pair_id: u64,
+    wtn_vote: &WtnVote,
+    earlier_vote: Option<Vote>,
+    now: u64,
+) {
+    state.record_pair_event(
+        pair_id,
+        PairEvent {
+            timestamp: now,
+            nns_proposal_id: wtn_vote.nns_proposal_id,
+            wtn_proposal_id: Some(wtn_vote.wtn_proposal_id),
+            kind: preempted_event_kind(earlier_vote, wtn_vote.vote()),
+        },
+    )
+}
+
+// An earlier vote can only agree or disagree with the relayed vote if one was actually cast
+fn preempted_event_kind(earlier_vote: Option<Vote>, relayed_vote: Vote) -> PairEventKind {
+    PairEventKind::Preempted {
+        earlier_vote,
+        agreed: earlier_vote
+            .filter(|v| *v != Vote::Unspecified)
+            .map(|v| v == relayed_vote),
+    }
+}
+
+// Returns the WTN neuron's current ballot on the proposal, or None if it can't be retrieved
+async fn get_wtn_ballot(
+    canister_id: Principal,
+    pair_id: u64,
+    neuron_id: [u8; 32],
+    wtn_vote: &WtnVote,
+) -> Option<Vote> {
+    match wtn_governance::get_proposal(canister_id, wtn_vote.wtn_proposal_id).await {
+        Ok(Ok(proposal)) => Some(
+            proposal
+                .ballot(neuron_id)
+                .and_then(|b| Vote::try_from(b.vote).ok())
+                .unwrap_or(Vote::Unspecified),
+        ),
+        error => {
+            log(format!(
+                "Unable to retrieve WTN ballot, error calling `get_proposal`: {error:?}. PairId: {pair_id}"
+            ));
+            None
+        }
+    }
+}
+
+// Reads back the WTN neuron's ballot on the proposal to check that the vote actually landed in
+// the intended direction, raising an alert if the neuron voted the other way
+async fn confirm_wtn_vote(
+    canister_id: Principal,
+    pair_id: u64,
+    neuron_id: [u8; 32],
+    wtn_vote: WtnVote,
+) {
+    let Some(actual) = get_wtn_ballot(canister_id, pair_id, neuron_id, &wtn_vote).await else {
+        return;
+    };
+
+    let expected = wtn_vote.vote();
+    let confirmation = vote_confirmation(expected, actual);
+
+    let now = ic_cdk::api::time();
+    state::mutate(|s| {
+        if let VoteConfirmation::Mismatched { actual } = confirmation {
+            s.raise_alert(Alert {
+                timestamp: now,
+                pair_id: Some(pair_id),
+                kind: AlertKind::VoteMismatch {
+                    nns_proposal_id: wtn_vote.nns_proposal_id,
+                    wtn_proposal_id: wtn_vote.wtn_proposal_id,
+                    expected,
+                    actual,
+                },
+            });
+        }
+        s.record_pair_event(
+            pair_id,
+            PairEvent {
+                timestamp: now,
+                nns_proposal_id: wtn_vote.nns_proposal_id,
+                wtn_proposal_id: Some(wtn_vote.wtn_proposal_id),
+                kind: PairEventKind::Confirmation(confirmation),
+            },
+        );
+    });
+}
+
+fn vote_confirmation(expected: Vote, actual: Vote) -> VoteConfirmation {
+    match actual {
+        Vote::Unspecified => VoteConfirmation::Missing,
+        actual if actual == expected => VoteConfirmation::Confirmed,
+        actual => VoteConfirmation::Mismatched { actual },
+    }
+}
+
+enum ProposalCheckResult {
+    Vote,
+    Skip(VoteSkippedReason),
+    Failed(SnsError),
+    Retry,
+}
+
+// Checks that the WTN proposal is still accepting votes and is a "Vote for NNS Proposals"
+// proposal. The proposal is served from the cache unless the cached deadline has passed, since the
+// deadline may since have been extended.
+async fn check_wtn_proposal(canister_id: Principal, wtn_vote: &WtnVote) -> ProposalCheckResult {
+    let wtn_proposal_id = wtn_vote.wtn_proposal_id;
+    let (cached, mapping_status) = state::read(|s| {
+        (
+            s.get_cached_wtn_proposal(wtn_proposal_id).cloned(),
+            s.wtn_proposal_mapping_status(
+                wtn_vote.nns_proposal_id,
+                wtn_proposal_id,
+                ic_cdk::api::time(),
+            ),
+        )
+    });
+
+    if mapping_status == WtnProposalMappingStatus::Quarantined {
+        return ProposalCheckResult::Skip(VoteSkippedReason::MappingQuarantined);
+    }
+    let Some(expected_function_id) = super::wtn_vote_for_nns_proposals_function_id().await else {
+        return ProposalCheckResult::Retry;
+    };
+
+    // Unverified mappings always fetch the proposal, since the cached details don't include the
+    // NNS proposal which it references
+    let proposal = match cached {
+        Some(p)
+            if mapping_status == WtnProposalMappingStatus::Verified
+                && (p.is_open(now_seconds()) || p.is_known_to_be_closed(now_seconds())) =>
+        {
+            p
+        }
+        _ => match wtn_governance::get_proposal(canister_id, wtn_proposal_id).await {
+            Ok(Ok(proposal_data)) => {
+                if mapping_status == WtnProposalMappingStatus::Unverified
+                    && !verify_mapping(wtn_vote, &proposal_data)
+                {
+                    return ProposalCheckResult::Skip(VoteSkippedReason::MappingQuarantined);
+                }
+                let proposal = WtnProposal::new(&proposal_data, now_seconds());
+                state::mutate(|s| s.record_wtn_proposal(wtn_proposal_id, proposal.clone()));
+                proposal
+            }
+            Ok(Err(error)) => return ProposalCheckResult::Failed(error.into_sns_error()),
+            Err(error) => {
+                log(format!("Error calling `get_proposal`: {error:?}"));
+                return ProposalCheckResult::Retry;
             }
+        },
+    };
+
+    if proposal.function_id != expected_function_id {
+        ProposalCheckResult::Skip(VoteSkippedReason::UnexpectedProposalFunction(
+            proposal.function_id,
+        ))
+    } else if !proposal.is_open(now_seconds()) {
+        ProposalCheckResult::Skip(VoteSkippedReason::ProposalClosed)
+    } else {
+        ProposalCheckResult::Vote
+    }
+}
+
+fn now_seconds() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000
+}
+
+// Determines the WTN proposal for the NNS proposal by calling into the WTN protocol canister.
+// Returns false if the protocol canister is unavailable or hasn't yet processed the NNS proposal.
+async fn resolve_with_protocol_canister(nns_proposal_id: u64) -> bool {
+    let Some(wtn_proposal_id) = lookup_wtn_proposal_id(nns_proposal_id).await else {
+        return false;
+    };
+
+    state::mutate(|s| {
+        s.record_wtn_proposal_for_nns_proposal(
+            nns_proposal_id,
+            wtn_proposal_id,
+            WtnProposalMappingSource::ProtocolCanister,
+            ic_cdk::api::time(),
+        )
+    });
+    true
+}
+
+// Asks the WTN protocol canister which WTN proposal it created for the NNS proposal, returning
+// Some(None) if it processed the NNS proposal without creating one, or None if it is unavailable
+// or hasn't yet processed the NNS proposal
+pub(super) async fn lookup_wtn_proposal_id(nns_proposal_id: u64) -> Option<Option<u64>> {
+    let canister_id = state::read(|s| s.wtn_protocol_canister_id());
+    // Lookups of the same NNS proposal, eg. by this job and by the job watching WTN proposals,
+    // share a single call
+    let lookups = WTN_PROPOSAL_ID_LOOKUPS.with(|l| l.clone());
+    let response = lookups
+        .run(nns_proposal_id, || {
+            get_wtn_proposal_id(canister_id, nns_proposal_id)
+        })
+        .await;
+    match response {
+        Ok(Ok(wtn_proposal_id)) => Some(Some(wtn_proposal_id.id)),
+        Ok(Err(latest_processed_nns_proposal_id))
+            if latest_processed_nns_proposal_id.id >= nns_proposal_id =>
+        {
+            log(format!(
+                "No WTN proposal found for NNS proposal {nns_proposal_id}"
+            ));
+            Some(None)
+        }
+        Ok(Err(latest_processed_nns_proposal_id)) => {
+            log(format!(
+                "WTN canister has not processed NNS proposal yet. ProposalId: {nns_proposal_id}. Latest processed: {}",
+                latest_processed_nns_proposal_id.id
+            ));
+            None
+        }
+        Err(error) => {
+            log(format!("Error calling `get_wtn_proposal_id`: {error:?}"));
+            None
         }
     }
+}
 
-    log(format!("Finished processing vote: {vote_string}"));
+// Searches a single page of WTN governance's recent proposals for the mirrors of the given NNS
+// proposals, returning the NNS proposals which were found. Those which weren't found aren't
+// cached, since their mirror proposals may not have been created yet.
+async fn resolve_with_wtn_governance(nns_proposal_ids: &BTreeSet<u64>) -> BTreeSet<u64> {
+    let Some(function_id) = super::wtn_vote_for_nns_proposals_function_id().await else {
+        return BTreeSet::new();
+    };
+    let wtn_governance_canister_id = state::read(|s| s.wtn_governance_canister_id());
+    let proposals = match wtn_governance::list_recent_proposals(
+        wtn_governance_canister_id,
+        MIRROR_PROPOSAL_SEARCH_LIMIT,
+    )
+    .await
+    {
+        Ok(proposals) => proposals,
+        Err(error) => {
+            log(format!("Error calling `list_proposals`: {error:?}"));
+            return BTreeSet::new();
+        }
+    };
 
-    state::read(start_job_if_required);
+    let mirrors = wtn_governance::find_mirror_proposals(&proposals, function_id, nns_proposal_ids);
+    let now = ic_cdk::api::time();
+    state::mutate(|s| {
+        for (nns_proposal_id, proposal) in mirrors.iter() {
+            let Some(wtn_proposal_id) = proposal.id() else {
+                continue;
+            };
+            log(format!(
+                "WTN proposal {wtn_proposal_id} found in WTN governance for NNS proposal {nns_proposal_id}"
+            ));
+            s.record_wtn_proposal_for_nns_proposal(
+                *nns_proposal_id,
+                Some(wtn_proposal_id),
+                WtnProposalMappingSource::WtnGovernance,
+                now,
+            );
+            s.record_wtn_proposal(wtn_proposal_id, WtnProposal::new(proposal, now_seconds()));
+        }
+    });
+    mirrors.into_keys().collect()
+}
+
+// Tracks when each NNS proposal was first left unresolved by the protocol canister, forgetting
+// those which have since been resolved, and returns those which have been unresolved for at least
+// `PROTOCOL_CANISTER_LAG_THRESHOLD`
+fn lagged_past_threshold(
+    first_unresolved_at: &mut BTreeMap<u64, u64>,
+    unresolved: &BTreeSet<u64>,
+    now: u64,
+) -> BTreeSet<u64> {
+    first_unresolved_at.retain(|id, _| unresolved.contains(id));
+    for id in unresolved {
+        first_unresolved_at.entry(*id).or_insert(now);
+    }
+
+    let threshold = PROTOCOL_CANISTER_LAG_THRESHOLD.as_nanos() as u64;
+    first_unresolved_at
+        .iter()
+        .filter(|(_, since)| now.saturating_sub(**since) >= threshold)
+        .map(|(id, _)| *id)
+        .collect()
 }
 
 async fn get_wtn_proposal_id(
@@ -159,7 +873,7 @@ async fn get_wtn_proposal_id(
     response.map(|r| r.0)
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 struct ProposalId {
     id: u64,
 }
@@ -195,8 +909,103 @@ enum CommandResponse {
 #[derive(CandidType, Serialize, Deserialize, Debug)]
 struct RegisterVoteResponse {}
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
-struct GovernanceError {
-    error_type: i32,
-    error_message: String,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_nns_proposals_lagging_past_the_threshold_are_searched_for() {
+        let threshold = PROTOCOL_CANISTER_LAG_THRESHOLD.as_nanos() as u64;
+        let mut first_unresolved_at = BTreeMap::new();
+
+        let lagged = lagged_past_threshold(&mut first_unresolved_at, &BTreeSet::from([1, 2]), 0);
+        assert!(lagged.is_empty());
+
+        let lagged = lagged_past_threshold(
+            &mut first_unresolved_at,
+            &BTreeSet::from([1, 3]),
+            threshold - 1,
+        );
+        assert!(lagged.is_empty());
+
+        // Proposal 2 was resolved in the meantime, so it is forgotten
+        let lagged =
+            lagged_past_threshold(&mut first_unresolved_at, &BTreeSet::from([1, 3]), threshold);
+        assert_eq!(lagged, BTreeSet::from([1]));
+        assert_eq!(
+            first_unresolved_at,
+            BTreeMap::from([(1, 0), (3, threshold - 1)])
+        );
+
+        // Once resolved, a proposal starts afresh if it is ever unresolved again
+        lagged_past_threshold(&mut first_unresolved_at, &BTreeSet::new(), threshold);
+        let lagged = lagged_past_threshold(
+            &mut first_unresolved_at,
+            &BTreeSet::from([1]),
+            threshold + 1,
+        );
+        assert!(lagged.is_empty());
+    }
+
+    #[test]
+    fn vote_confirmation_detects_mismatches() {
+        assert!(matches!(
+            vote_confirmation(Vote::Adopt, Vote::Adopt),
+            VoteConfirmation::Confirmed
+        ));
+        assert!(matches!(
+            vote_confirmation(Vote::Reject, Vote::Reject),
+            VoteConfirmation::Confirmed
+        ));
+        assert!(matches!(
+            vote_confirmation(Vote::Adopt, Vote::Reject),
+            VoteConfirmation::Mismatched {
+                actual: Vote::Reject
+            }
+        ));
+        assert!(matches!(
+            vote_confirmation(Vote::Reject, Vote::Unspecified),
+            VoteConfirmation::Missing
+        ));
+    }
+
+    #[test]
+    fn preemption_only_agrees_or_disagrees_with_a_cast_vote() {
+        let agreed = |earlier_vote| match preempted_event_kind(earlier_vote, Vote::Adopt) {
+            PairEventKind::Preempted { agreed, .. } => agreed,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(agreed(Some(Vote::Adopt)), Some(true));
+        assert_eq!(agreed(Some(Vote::Reject)), Some(false));
+        assert_eq!(agreed(Some(Vote::Unspecified)), None);
+        assert_eq!(agreed(None), None);
+    }
+
+    #[test]
+    fn vote_slots_limit_the_votes_in_flight_per_canister() {
+        let canister_id = Principal::from_slice(&[1]);
+        let other_canister_id = Principal::from_slice(&[2]);
+        let busy = || IN_FLIGHT.with_borrow(|f| f.busy_wtn_neuron_ids(canister_id));
+
+        let mut slots: Vec<_> = (0..MAX_CONCURRENT_VOTES_PER_CANISTER as u8)
+            .map(|i| VoteSlot::acquire(canister_id, Some([i; 32]), i as u64))
+            .collect();
+        assert!(busy().is_none());
+        assert!(IN_FLIGHT
+            .with_borrow(|f| f.busy_wtn_neuron_ids(other_canister_id))
+            .is_some());
+
+        drop(slots.remove(0));
+        let busy_wtn_neuron_ids = busy().unwrap();
+        assert_eq!(
+            busy_wtn_neuron_ids.len(),
+            MAX_CONCURRENT_VOTES_PER_CANISTER - 1
+        );
+        assert!(!busy_wtn_neuron_ids.contains(&[0; 32]));
+        assert!(busy_wtn_neuron_ids.contains(&[1; 32]));
+
+        drop(slots);
+        assert!(busy().unwrap().is_empty());
+    }
 }
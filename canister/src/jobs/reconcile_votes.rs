@@ -0,0 +1,305 @@
+use crate::logs::log;
+use crate::neuron_pair::NeuronPair;
+use crate::wtn_governance::{ProposalData, REGISTER_VOTE_PERMISSION};
+use crate::{state, wtn_governance, Vote, VoteCoverage, WtnVote};
+use ic_principal::Principal;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const RECONCILE_VOTES_INTERVAL: Duration = Duration::from_secs(30 * 60); // 30 minutes
+const RECENT_WTN_PROPOSALS_LIMIT: u32 = 50;
+
+pub fn start_job() {
+    ic_cdk_timers::set_timer_interval(RECONCILE_VOTES_INTERVAL, || ic_cdk::spawn(run()));
+}
+
+// A recent WTN proposal which mirrors an NNS proposal
+struct MirrorProposal {
+    nns_proposal_id: u64,
+    wtn_proposal_id: u64,
+    is_open: bool,
+    // Whether the protocol canister has confirmed the mapping, otherwise the NNS proposal is only
+    // the one referenced by the WTN proposal, and missed votes are reported but not re-queued
+    confirmed: bool,
+    proposal: ProposalData,
+}
+
+// Resumes the suspended pairs whose WTN neurons have since granted this canister permission to
+// vote on their behalf
+async fn resume_suspended_pairs(wtn_governance_canister_id: Principal) {
+    let suspended: Vec<_> = state::read(|s| {
+        s.neuron_pairs()
+            .values()
+            .filter(|p| p.is_suspended())
+            .map(|p| (p.id(), p.wtn_neuron_id()))
+            .collect()
+    });
+
+    for (pair_id, wtn_neuron_id) in suspended {
+        match wtn_governance::get_neuron(wtn_governance_canister_id, wtn_neuron_id).await {
+            Ok(Ok(neuron)) if neuron.has_permission(ic_cdk::id(), REGISTER_VOTE_PERMISSION) => {
+                state::mutate(|s| s.resume_neuron_pair(pair_id));
+            }
+            Ok(_) => {}
+            Err(error) => {
+                log(format!(
+                    "Error calling `get_neuron`: {error:?}. PairId: {pair_id}"
+                ));
+            }
+        }
+    }
+}
+
+// Compares the recent WTN "Vote for NNS Proposals" proposals against the ballots of each pair's
+// NNS and WTN neurons, re-queueing any missed votes whose WTN proposals are still open and have
+// been confirmed by the protocol canister. The NNS neurons' ballots are those seen by their latest
+// poll, so pairs whose NNS neurons haven't been polled since the last upgrade are skipped.
+async fn run() {
+    let (wtn_governance_canister_id, nns_neuron_ids) =
+        state::read(|s| (s.wtn_governance_canister_id(), s.nns_neuron_ids()));
+
+    if nns_neuron_ids.is_empty() {
+        return;
+    }
+
+    log("Reconciling votes");
+
+    // Resumed before reconciling, so that the votes which were skipped while the pairs were
+    // suspended are re-queued
+    resume_suspended_pairs(wtn_governance_canister_id).await;
+
+    let Some(function_id) = super::wtn_vote_for_nns_proposals_function_id().await else {
+        return;
+    };
+
+    let proposals = match wtn_governance::list_recent_proposals(
+        wtn_governance_canister_id,
+        RECENT_WTN_PROPOSALS_LIMIT,
+    )
+    .await
+    {
+        Ok(proposals) => proposals,
+        Err(error) => {
+            log(format!("Error calling `list_proposals`: {error:?}"));
+            return;
+        }
+    };
+
+    let now = ic_cdk::api::time();
+    let now_seconds = now / 1_000_000_000;
+    let mirrors: Vec<_> = state::read(|s| {
+        proposals
+            .into_iter()
+            .filter(|p| p.action == function_id)
+            .filter_map(|proposal| {
+                let wtn_proposal_id = proposal.id()?;
+                let nns_proposal_id = s
+                    .get_cached_nns_proposal_for_wtn_proposal(wtn_proposal_id, now)
+                    .or_else(|| proposal.referenced_nns_proposal_id())?;
+                Some(MirrorProposal {
+                    nns_proposal_id,
+                    wtn_proposal_id,
+                    is_open: now_seconds < proposal.deadline_timestamp_seconds(),
+                    confirmed: s.is_wtn_proposal_mapping_confirmed(
+                        nns_proposal_id,
+                        wtn_proposal_id,
+                        now,
+                    ),
+                    proposal,
+                })
+            })
+            .collect()
+    });
+
+    state::mutate(|s| {
+        let results: Vec<_> = s
+            .neuron_pairs()
+            .values()
+            .filter_map(|pair| {
+                let nns_votes = s.latest_nns_votes(pair.nns_neuron_id())?;
+                Some((pair.id(), reconcile_pair(pair, &mirrors, nns_votes, now)))
+            })
+            .collect();
+
+        for (pair_id, (mut coverage, missed_votes)) in results {
+            for vote in missed_votes {
+                if s.requeue_missed_vote(pair_id, vote) {
+                    coverage.requeued += 1;
+                }
+            }
+            s.record_vote_coverage(pair_id, coverage);
+        }
+    });
+
+    log(format!(
+        "Reconciliation completed. WTN proposals checked: {}",
+        mirrors.len()
+    ));
+}
+
+// Returns the pair's coverage of the mirror proposals along with the votes which were missed but
+// can still be cast
+fn reconcile_pair(
+    pair: &NeuronPair,
+    mirrors: &[MirrorProposal],
+    nns_votes: &BTreeMap<u64, bool>,
+    now: u64,
+) -> (VoteCoverage, Vec<WtnVote>) {
+    let mut coverage = VoteCoverage {
+        timestamp: now,
+        expected: 0,
+        relayed: 0,
+        requeued: 0,
+        unconfirmed: 0,
+    };
+    let mut missed_votes = Vec::new();
+
+    for mirror in mirrors {
+        if !pair.should_relay_nns_ballot(mirror.nns_proposal_id, mirror.is_open, false) {
+            continue;
+        }
+        let Some(&adopt) = nns_votes.get(&mirror.nns_proposal_id) else {
+            continue;
+        };
+        // If there is no ballot then the WTN neuron isn't eligible to vote on the proposal
+        let Some(ballot) = mirror.proposal.ballot(pair.wtn_neuron_id()) else {
+            continue;
+        };
+
+        coverage.expected += 1;
+        let expected = if adopt { Vote::Adopt } else { Vote::Reject };
+        match Vote::try_from(ballot.vote) {
+            Ok(vote) if vote == expected => coverage.relayed += 1,
+            // Suspended pairs' missed votes are re-queued once the pair has been resumed
+            Ok(Vote::Unspecified) if mirror.is_open && !pair.is_suspended() => {
+                if mirror.confirmed {
+                    missed_votes.push(WtnVote {
+                        nns_proposal_id: mirror.nns_proposal_id,
+                        wtn_proposal_id: mirror.wtn_proposal_id,
+                        adopt,
+                    })
+                } else {
+                    coverage.unconfirmed += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (coverage, missed_votes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neuron_pair::NewNeuronPair;
+    use crate::wtn_governance::{Ballot, ProposalId};
+    use crate::NnsVote;
+    use std::collections::BTreeSet;
+
+    const WTN_NEURON_ID: [u8; 32] = [1; 32];
+
+    // A pair whose NNS neuron had already voted on proposal 1 when it was first polled
+    fn pair() -> NeuronPair {
+        let args = NewNeuronPair {
+            name: "Pair".to_string(),
+            nns_neuron_id: 1,
+            nns_known_neuron_name: None,
+            wtn_neuron_id: WTN_NEURON_ID,
+            backfill_open_proposals: false,
+        };
+        let mut pair = NeuronPair::new(Principal::from_slice(&[1]), args, 0);
+        let seen = NnsVote {
+            proposal_id: 1,
+            vote: Vote::Adopt,
+        };
+        pair.take_nns_votes_to_relay(vec![seen], &BTreeSet::new(), &BTreeSet::new());
+        pair
+    }
+
+    fn mirror(nns_proposal_id: u64, is_open: bool, wtn_ballot: Vote) -> MirrorProposal {
+        let wtn_proposal_id = nns_proposal_id + 100;
+        let neuron_id_hex: String = WTN_NEURON_ID.iter().map(|b| format!("{b:02x}")).collect();
+        MirrorProposal {
+            nns_proposal_id,
+            wtn_proposal_id,
+            is_open,
+            confirmed: true,
+            proposal: ProposalData {
+                id: Some(ProposalId {
+                    id: wtn_proposal_id,
+                }),
+                action: 1000,
+                decided_timestamp_seconds: 0,
+                proposal_creation_timestamp_seconds: 0,
+                initial_voting_period_seconds: 0,
+                wait_for_quiet_state: None,
+                ballots: vec![(
+                    neuron_id_hex,
+                    Ballot {
+                        vote: wtn_ballot as i32,
+                    },
+                )],
+                proposal: None,
+                payload_text_rendering: None,
+            },
+        }
+    }
+
+    fn missed_proposal_ids(missed_votes: &[WtnVote]) -> Vec<(u64, u64, bool)> {
+        missed_votes
+            .iter()
+            .map(|v| (v.nns_proposal_id, v.wtn_proposal_id, v.adopt))
+            .collect()
+    }
+
+    #[test]
+    fn missed_votes_on_open_confirmed_proposals_are_requeued() {
+        let nns_votes = BTreeMap::from([(10, true), (11, false)]);
+        let mut unconfirmed = mirror(11, true, Vote::Unspecified);
+        unconfirmed.confirmed = false;
+        let mirrors = [mirror(10, true, Vote::Unspecified), unconfirmed];
+
+        let (coverage, missed_votes) = reconcile_pair(&pair(), &mirrors, &nns_votes, 5);
+
+        assert_eq!(missed_proposal_ids(&missed_votes), vec![(10, 110, true)]);
+        assert_eq!(coverage.timestamp, 5);
+        assert_eq!(coverage.expected, 2);
+        assert_eq!(coverage.relayed, 0);
+        assert_eq!(coverage.unconfirmed, 1);
+    }
+
+    #[test]
+    fn votes_which_were_already_cast_are_counted_as_relayed() {
+        let nns_votes = BTreeMap::from([(10, true), (11, false), (12, true)]);
+        let mirrors = [
+            mirror(10, true, Vote::Adopt),
+            mirror(11, true, Vote::Reject),
+            // Cast differently, eg. by the neuron's owner, so it is neither relayed nor re-queued
+            mirror(12, true, Vote::Reject),
+        ];
+
+        let (coverage, missed_votes) = reconcile_pair(&pair(), &mirrors, &nns_votes, 0);
+
+        assert!(missed_votes.is_empty());
+        assert_eq!(coverage.expected, 3);
+        assert_eq!(coverage.relayed, 2);
+    }
+
+    #[test]
+    fn missed_votes_on_closed_proposals_are_not_requeued() {
+        let nns_votes = BTreeMap::from([(10, true), (1, true)]);
+        // Proposal 1 was voted on before the pair was first polled, so only counts while open
+        let mirrors = [
+            mirror(10, false, Vote::Unspecified),
+            mirror(1, false, Vote::Unspecified),
+        ];
+
+        let (coverage, missed_votes) = reconcile_pair(&pair(), &mirrors, &nns_votes, 0);
+
+        assert!(missed_votes.is_empty());
+        assert_eq!(coverage.expected, 1);
+        assert_eq!(coverage.relayed, 0);
+        assert_eq!(coverage.unconfirmed, 0);
+    }
+}
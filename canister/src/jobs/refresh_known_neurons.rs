@@ -0,0 +1,23 @@
+use crate::logs::log;
+use crate::{nns_governance, state};
+use std::time::Duration;
+
+const REFRESH_KNOWN_NEURONS_INTERVAL: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+pub fn start_job() {
+    ic_cdk_timers::set_timer_interval(REFRESH_KNOWN_NEURONS_INTERVAL, || ic_cdk::spawn(run()));
+}
+
+async fn run() {
+    let Some(nns_governance_canister_id) = state::read(|s| {
+        s.has_known_neuron_pairs()
+            .then_some(s.nns_governance_canister_id())
+    }) else {
+        return;
+    };
+
+    match nns_governance::list_known_neurons(nns_governance_canister_id).await {
+        Ok(known_neurons) => state::mutate(|s| s.update_known_neuron_flags(&known_neurons)),
+        Err(error) => log(format!("Error calling `list_known_neurons`: {error:?}")),
+    }
+}
@@ -0,0 +1,17 @@
+use crate::logs::log;
+use crate::state;
+use std::time::Duration;
+
+const SWEEP_VOTE_LEASES_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn start_job() {
+    ic_cdk_timers::set_timer_interval(SWEEP_VOTE_LEASES_INTERVAL, run);
+}
+
+// Returns any votes which were lost while in flight, eg. due to a trap, to the queue
+fn run() {
+    let requeued = state::mutate(|s| s.requeue_expired_vote_leases(ic_cdk::api::time()));
+    if requeued > 0 {
+        log(format!("Requeued votes whose leases expired: {requeued}"));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::jobs::check_for_new_nns_votes;
+use crate::logs::log;
+use crate::{nns_governance, state};
+use std::time::Duration;
+
+const TRACK_OPEN_NNS_PROPOSALS_INTERVAL: Duration = Duration::from_secs(5 * 60); // 5 minutes
+
+pub fn start_job() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(run()));
+    ic_cdk_timers::set_timer_interval(TRACK_OPEN_NNS_PROPOSALS_INTERVAL, || ic_cdk::spawn(run()));
+}
+
+async fn run() {
+    let nns_governance_canister_id = state::read(|s| s.nns_governance_canister_id());
+
+    match nns_governance::list_open_proposals(nns_governance_canister_id).await {
+        Ok(proposals) => {
+            let newly_opened =
+                state::mutate(|s| s.update_open_nns_proposals(proposals, ic_cdk::api::time()));
+            // The followed neurons are likely to vote on new proposals soon, so bring forward
+            // the next poll rather than waiting for the slow heartbeat
+            if newly_opened > 0 {
+                log(format!("New NNS proposals opened: {newly_opened}"));
+                check_for_new_nns_votes::run_within(check_for_new_nns_votes::MIN_POLL_INTERVAL);
+            }
+        }
+        Err(error) => log(format!("Error calling `list_proposals`: {error:?}")),
+    }
+}
@@ -0,0 +1,116 @@
+use super::check_for_new_nns_votes::{self, MIN_POLL_INTERVAL};
+use crate::logs::log;
+use crate::wtn_governance::WtnProposal;
+use crate::{state, wtn_governance};
+use std::time::Duration;
+
+const WATCH_WTN_PROPOSALS_INTERVAL: Duration = Duration::from_secs(120);
+const RECENT_WTN_PROPOSALS_LIMIT: u32 = 50;
+
+pub fn start_job() {
+    ic_cdk_timers::set_timer_interval(WATCH_WTN_PROPOSALS_INTERVAL, || ic_cdk::spawn(run()));
+}
+
+// Watches for new WTN "Vote for NNS Proposals" proposals and, once the protocol canister confirms
+// which NNS proposal each one mirrors, checks the followed NNS neurons' ballots on the NNS proposal
+// while the WTN proposal is open, queueing the votes as soon as they exist. Unlike the NNS polling,
+// this relays votes even if the NNS ballot was cast before the WTN proposal was created. The
+// ballots are those fetched by the NNS polling, which also relays to the watched proposals after
+// each run, so once a proposal is confirmed an early poll is requested rather than polling here.
+async fn run() {
+    let (wtn_governance_canister_id, nns_neuron_ids) =
+        state::read(|s| (s.wtn_governance_canister_id(), s.nns_neuron_ids()));
+
+    if nns_neuron_ids.is_empty() {
+        return;
+    }
+    let Some(function_id) = super::wtn_vote_for_nns_proposals_function_id().await else {
+        return;
+    };
+
+    let proposals = match wtn_governance::list_recent_proposals(
+        wtn_governance_canister_id,
+        RECENT_WTN_PROPOSALS_LIMIT,
+    )
+    .await
+    {
+        Ok(proposals) => proposals,
+        Err(error) => {
+            log(format!("Error calling `list_proposals`: {error:?}"));
+            return;
+        }
+    };
+
+    let now = ic_cdk::api::time();
+    let now_seconds = now / 1_000_000_000;
+    let unconfirmed = state::mutate(|s| {
+        for proposal in proposals.iter().filter(|p| p.action == function_id) {
+            let Some(wtn_proposal_id) = proposal.id() else {
+                continue;
+            };
+            let wtn_proposal = WtnProposal::new(proposal, now_seconds);
+            if !wtn_proposal.is_open(now_seconds) {
+                continue;
+            }
+            let Some(nns_proposal_id) = s
+                .get_cached_nns_proposal_for_wtn_proposal(wtn_proposal_id, now)
+                .or_else(|| proposal.referenced_nns_proposal_id())
+                .or_else(|| proposal.parsed_nns_proposal_id())
+            else {
+                log(format!(
+                    "Unable to determine the NNS proposal for WTN proposal {wtn_proposal_id}"
+                ));
+                continue;
+            };
+            if s.watch_wtn_proposal(wtn_proposal_id, nns_proposal_id, wtn_proposal) {
+                log(format!(
+                    "Watching WTN proposal {wtn_proposal_id} for NNS proposal {nns_proposal_id}"
+                ));
+            }
+        }
+        s.unconfirmed_watched_wtn_proposals(now_seconds)
+    });
+
+    let futures: Vec<_> = unconfirmed
+        .into_iter()
+        .map(|(wtn_proposal_id, nns_proposal_id)| confirm_single(wtn_proposal_id, nns_proposal_id))
+        .collect();
+    let confirmed = futures::future::join_all(futures).await;
+    if confirmed.into_iter().any(|c| c) {
+        check_for_new_nns_votes::run_within(MIN_POLL_INTERVAL);
+    }
+
+    let queued = state::mutate(|s| s.record_nns_votes_for_watched_wtn_proposals(now_seconds));
+    if queued > 0 {
+        log(format!("Votes queued from watched WTN proposals: {queued}"));
+    }
+}
+
+// Checks the watched WTN proposal's NNS proposal with the protocol canister, leaving it to be
+// checked again on the next run if the protocol canister can't yet say. Returns true if the WTN
+// proposal was confirmed.
+async fn confirm_single(wtn_proposal_id: u64, nns_proposal_id: u64) -> bool {
+    let Some(protocol_canister_wtn_proposal_id) =
+        super::process_votes::lookup_wtn_proposal_id(nns_proposal_id).await
+    else {
+        return false;
+    };
+
+    let confirmed = state::mutate(|s| {
+        s.confirm_watched_wtn_proposal(
+            wtn_proposal_id,
+            protocol_canister_wtn_proposal_id,
+            ic_cdk::api::time(),
+        )
+    });
+    if confirmed {
+        log(format!(
+            "WTN proposal {wtn_proposal_id} confirmed for NNS proposal {nns_proposal_id}"
+        ));
+    } else {
+        log(format!(
+            "WTN proposal {wtn_proposal_id} does not mirror NNS proposal {nns_proposal_id}. Protocol canister mapping: {protocol_canister_wtn_proposal_id:?}"
+        ));
+    }
+    confirmed
+}
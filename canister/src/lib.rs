@@ -3,14 +3,26 @@ use ic_cdk::api::management_canister::main::{CanisterIdRecord, CanisterStatusRes
 use ic_principal::Principal;
 use serde::{Deserialize, Serialize};
 
+mod guards;
+mod idempotency_keys;
 mod jobs;
 mod lifecycle;
 mod logs;
 mod memory;
 mod neuron_pair;
+mod neuron_pair_index;
+mod nns_ballot_gaps;
+mod nns_governance;
+mod open_nns_proposals;
 mod queries;
+mod rate_limiter;
+mod single_flight;
 mod state;
 mod updates;
+mod vote_leases;
+mod vote_retries;
+mod wtn_governance;
+mod wtn_proposal_mappings;
 
 #[derive(CandidType, Serialize, Deserialize, Debug)]
 enum InitOrUpgradeArgs {
@@ -18,15 +30,26 @@ enum InitOrUpgradeArgs {
     Upgrade(UpgradeArgs),
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Default)]
 struct InitArgs {
     nns_governance_canister_id: Option<Principal>,
     wtn_governance_canister_id: Option<Principal>,
     wtn_protocol_canister_id: Option<Principal>,
+    wtn_vote_for_nns_proposals_function_id: Option<u64>,
+    nns_polling_batch_size: Option<u32>,
+    wtn_proposal_mappings_max_entries: Option<u32>,
+    wtn_proposal_mappings_positive_ttl_seconds: Option<u64>,
+    wtn_proposal_mappings_negative_ttl_seconds: Option<u64>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug, Default)]
-struct UpgradeArgs {}
+struct UpgradeArgs {
+    wtn_vote_for_nns_proposals_function_id: Option<u64>,
+    nns_polling_batch_size: Option<u32>,
+    wtn_proposal_mappings_max_entries: Option<u32>,
+    wtn_proposal_mappings_positive_ttl_seconds: Option<u64>,
+    wtn_proposal_mappings_negative_ttl_seconds: Option<u64>,
+}
 
 impl InitOrUpgradeArgs {
     fn into_init_args(self) -> InitArgs {
@@ -58,12 +81,76 @@ impl VoteToProcess {
             }
         }
     }
+
+    fn nns_proposal_id(&self) -> u64 {
+        match self {
+            VoteToProcess::NnsVote(_, vote) => vote.proposal_id,
+            VoteToProcess::PendingWtnVote(_, vote) => vote.nns_proposal_id,
+        }
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "StoredNnsVote")]
 struct NnsVote {
     proposal_id: u64,
-    adopt: bool,
+    vote: Vote,
+}
+
+// Votes which were queued before NNS ballots were stored as a `Vote` only record whether they were
+// to adopt the proposal
+#[derive(Deserialize)]
+struct StoredNnsVote {
+    proposal_id: u64,
+    vote: Option<Vote>,
+    adopt: Option<bool>,
+}
+
+impl From<StoredNnsVote> for NnsVote {
+    fn from(value: StoredNnsVote) -> Self {
+        let vote = match (value.vote, value.adopt) {
+            (Some(vote), _) => vote,
+            (None, Some(true)) => Vote::Adopt,
+            (None, Some(false)) => Vote::Reject,
+            (None, None) => Vote::Unspecified,
+        };
+        NnsVote {
+            proposal_id: value.proposal_id,
+            vote,
+        }
+    }
+}
+
+// A ballot as represented by both the NNS and SNS governance canisters
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum Vote {
+    Unspecified,
+    Adopt,
+    Reject,
+}
+
+impl Vote {
+    // Whether the vote is to adopt the proposal, or None if no vote has been cast
+    fn adopt(self) -> Option<bool> {
+        match self {
+            Vote::Unspecified => None,
+            Vote::Adopt => Some(true),
+            Vote::Reject => Some(false),
+        }
+    }
+}
+
+impl TryFrom<i32> for Vote {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Vote::Unspecified),
+            1 => Ok(Vote::Adopt),
+            2 => Ok(Vote::Reject),
+            _ => Err(value),
+        }
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -73,20 +160,321 @@ struct WtnVote {
     adopt: bool,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct PairEvent {
+    timestamp: u64,
+    nns_proposal_id: u64,
+    wtn_proposal_id: Option<u64>,
+    kind: PairEventKind,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum PairEventKind {
+    VoteRegistered {
+        adopt: bool,
+    },
+    VoteSkipped(VoteSkippedReason),
+    Confirmation(VoteConfirmation),
+    // The WTN neuron had already voted before the relay could, most likely due to its followees.
+    // `earlier_vote` is None if the existing ballot couldn't be retrieved, and `agreed` is also
+    // None if the retrieved ballot hadn't been cast.
+    Preempted {
+        earlier_vote: Option<Vote>,
+        agreed: Option<bool>,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct PreemptionStats {
+    preempted: u32,
+    // The number of pre-emptions where the earlier vote matched the NNS neuron's vote
+    agreed: u32,
+    disagreed: u32,
+}
+
+// The result of reading back the WTN neuron's ballot after relaying a vote
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum VoteConfirmation {
+    Confirmed,
+    Mismatched { actual: Vote },
+    Missing,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct Alert {
+    timestamp: u64,
+    pair_id: Option<u64>,
+    kind: AlertKind,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum AlertKind {
+    VoteMismatch {
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        expected: Vote,
+        actual: Vote,
+    },
+    // The WTN proposal doesn't reference the NNS proposal it was mapped to, so no votes will be
+    // cast on it
+    MappingMismatch {
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        referenced_nns_proposal_id: Option<u64>,
+    },
+    // WTN governance returned an error which wasn't expected, so the vote was dropped
+    GovernanceError {
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        error: SnsError,
+    },
+    // The relay can no longer vote on behalf of the pair's WTN neuron, so its votes are skipped
+    // until this is resolved
+    PairSuspended {
+        error: SnsError,
+    },
+    // The vote kept failing with transient errors, so it was dropped
+    VoteRetriesExhausted {
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        attempts: u32,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum VoteSkippedReason {
+    ProposalClosed,
+    UnexpectedProposalFunction(u64),
+    // Recorded by earlier versions, which didn't categorise WTN governance errors
+    GovernanceError(i32, String),
+    MappingQuarantined,
+    SnsError(SnsError),
+    PairSuspended,
+    RetriesExhausted,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct SnsError {
+    kind: SnsErrorKind,
+    message: String,
+}
+
+// The categories of error returned by SNS governance. SNS governance reports both "already voted"
+// and "proposal closed" as failed preconditions, so these are distinguished by their messages.
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum SnsErrorKind {
+    Unavailable,
+    NotAuthorized,
+    NotFound,
+    InvalidCommand,
+    ResourceExhausted,
+    PreconditionFailed,
+    AlreadyVoted,
+    ProposalClosed,
+    NeuronLocked,
+    External,
+    Other,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct SnsErrorCount {
+    kind: SnsErrorKind,
+    count: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct PairSuspension {
+    timestamp: u64,
+    error: SnsError,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+struct Metrics {
+    neuron_pairs: u32,
+    distinct_nns_neurons: u32,
+    nns_neuron_info_calls: u64,
+    nns_neuron_info_calls_saved: u64,
+    nns_ballots_to_recover: u32,
+    // Votes which fell out of NNS neurons' recent ballots before being seen, and were only found
+    // once their proposals had closed
+    nns_ballots_unrecoverable: u64,
+    votes_to_process: u32,
+    votes_in_flight: u32,
+    votes_awaiting_retry: u32,
+    open_nns_proposals: u32,
+    watched_wtn_proposals: u32,
+    wtn_proposal_mappings_from_protocol_canister: u32,
+    wtn_proposal_mappings_from_wtn_governance: u32,
+    wtn_proposal_mappings_from_controller: u32,
+    suspended_neuron_pairs: u32,
+    // None until it has been looked up from WTN governance, during which time no votes are cast
+    wtn_vote_for_nns_proposals_function_id: Option<u64>,
+    paused: bool,
+    sns_errors: Vec<SnsErrorCount>,
+}
+
+// The WTN proposal which mirrors an NNS proposal, or None if there is no mirror proposal
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct WtnProposalMapping {
+    wtn_proposal_id: Option<u64>,
+    source: WtnProposalMappingSource,
+    #[serde(default)]
+    status: WtnProposalMappingStatus,
+}
+
+// How the WTN proposal for an NNS proposal was determined
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum WtnProposalMappingSource {
+    // Returned by `get_wtn_proposal_id` on the WTN protocol canister
+    ProtocolCanister,
+    // Found by scanning the proposals in WTN governance for a reference to the NNS proposal
+    WtnGovernance,
+    // Seeded by a controller via `seed_wtn_proposal_mappings`
+    Controller,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct WtnProposalMappingsArgs {
+    start_after: Option<u64>,
+    limit: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct WtnProposalMappingEntry {
+    nns_proposal_id: u64,
+    mapping: WtnProposalMapping,
+    recorded_at: u64,
+    expires_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct InvalidateWtnProposalMappingsArgs {
+    // If None, every mapping is invalidated
+    nns_proposal_ids: Option<Vec<u64>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct SeedWtnProposalMappingsArgs {
+    mappings: Vec<SeedWtnProposalMapping>,
+}
+
+// Returned by the updates which only the relay's controllers can call
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+enum ControllerUpdateError {
+    NotAuthorized,
+    InvalidArgument(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct SeedWtnProposalMapping {
+    nns_proposal_id: u64,
+    wtn_proposal_id: Option<u64>,
+}
+
+// Mappings are verified before their first use by checking that the WTN proposal references the
+// same NNS proposal, any which don't are quarantined
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum WtnProposalMappingStatus {
+    #[default]
+    Unverified,
+    Verified,
+    Quarantined,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct NeuronPairHistoryArgs {
+    pair_id: u64,
+}
+
+impl WtnVote {
+    fn vote(&self) -> Vote {
+        if self.adopt {
+            Vote::Adopt
+        } else {
+            Vote::Reject
+        }
+    }
+}
+
+impl SnsErrorKind {
+    // Whether the same request may succeed if it is retried
+    fn is_transient(self) -> bool {
+        matches!(
+            self,
+            SnsErrorKind::Unavailable
+                | SnsErrorKind::ResourceExhausted
+                | SnsErrorKind::NeuronLocked
+                | SnsErrorKind::External
+        )
+    }
+}
+
 #[derive(CandidType, Serialize, Deserialize)]
 struct RegisterNeuronPairArgs {
     name: String,
-    nns_neuron_id: u64,
+    nns_neuron_id: Option<u64>,
+    nns_known_neuron_name: Option<String>,
     wtn_neuron_id: [u8; 32],
+    // By default, ballots cast by the NNS neuron before the pair was registered are only relayed
+    // for proposals which are still open on WTN. Set this to relay those ballots for any NNS
+    // proposals which are still open, even if their WTN proposals aren't known yet.
+    backfill_open_proposals: Option<bool>,
+    // Allows a registration to be safely retried, eg. after a timeout. Repeated calls from the
+    // same caller with the same key return the outcome of the original call for up to a day.
+    idempotency_key: Option<String>,
 }
 
-#[derive(CandidType, Serialize, Deserialize)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 enum RegisterNeuronPairError {
     AlreadyRegistered,
     NotPermittedToVote,
     RegistrationLimitExceeded(u32),
+    // Returned by earlier versions, which didn't categorise WTN governance errors
     GovernanceError(i32, String),
+    SnsError(SnsError),
     ErrorCallingGovernanceCanister(i32, String),
+    ErrorCallingNnsGovernanceCanister(i32, String),
+    KnownNeuronNotFound(String),
+    InvalidArgument(String),
+    NotAuthorized,
+    Paused,
+    RateLimited,
+    // The caller has already registered the maximum number of pairs
+    PairQuotaExceeded(u32),
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct DiscoverWtnNeuronsArgs {
+    register_for_nns_neuron: Option<RegisterDiscoveredNeuronsArgs>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct RegisterDiscoveredNeuronsArgs {
+    name: String,
+    nns_neuron_id: Option<u64>,
+    nns_known_neuron_name: Option<String>,
+    // As for `RegisterNeuronPairArgs`, applied to every pair registered
+    backfill_open_proposals: Option<bool>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct DiscoveredWtnNeuron {
+    wtn_neuron_id: [u8; 32],
+    registration_result: Option<Result<u64, RegisterNeuronPairError>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+enum DiscoverWtnNeuronsError {
+    ErrorCallingGovernanceCanister(i32, String),
+    // The caller has more WTN neurons than the given maximum which can be listed
+    TooManyNeurons(u32),
+    NotAuthorized,
+    Paused,
+    RateLimited,
 }
 
 #[derive(CandidType, Serialize, Deserialize)]
@@ -94,13 +482,115 @@ struct DeregisterNeuronPairArgs {
     pair_id: u64,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+enum DeregisterNeuronPairError {
+    NotFound,
+    // The pair was registered by a different principal
+    NotAuthorized,
+}
+
+// Removes all of the caller's pairs which match every filter provided
+#[derive(CandidType, Serialize, Deserialize)]
+struct DeregisterNeuronPairsArgs {
+    nns_neuron_id: Option<u64>,
+    wtn_neuron_id: Option<[u8; 32]>,
+    name: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+enum DeregisterNeuronPairsError {
+    InvalidArgument(String),
+    NotAuthorized,
+    // None of the caller's pairs match the filter
+    NotFound,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+enum StatusError {
+    ErrorCallingManagementCanister(i32, String),
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct SetPausedArgs {
+    paused: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+enum SetPausedError {
+    NotAuthorized,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Debug)]
 struct NeuronPairPublic {
     id: u64,
     name: String,
     admin: Principal,
     nns_neuron_id: u64,
+    nns_known_neuron_name: Option<String>,
+    nns_known_neuron_flag: Option<KnownNeuronFlag>,
     wtn_neuron_id: [u8; 32],
+    state: NeuronPairState,
+    registered_at: u64,
+    nns_ballot_watermark: Option<u64>,
+    coverage: Option<VoteCoverage>,
+    preemption_stats: PreemptionStats,
+    suspension: Option<PairSuspension>,
+}
+
+// The outcome of the most recent reconciliation of a pair's votes against the recent WTN
+// "Vote for NNS Proposals" proposals
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct VoteCoverage {
+    timestamp: u64,
+    // The number of proposals where the NNS neuron voted and the WTN neuron was eligible to vote
+    expected: u32,
+    // The number of those proposals where the WTN neuron voted the same way as the NNS neuron
+    relayed: u32,
+    // The number of missed votes which were re-queued as the WTN proposal was still open
+    requeued: u32,
+    // The number of missed votes which weren't re-queued as the protocol canister is yet to
+    // confirm which NNS proposal the WTN proposal mirrors
+    #[serde(default)]
+    unconfirmed: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum NeuronPairState {
+    Active,
+    // The known neuron which the pair was registered against has been renamed or removed
+    Flagged,
+    // WTN governance rejected a vote in a way which indicates the relay can no longer vote on
+    // behalf of the WTN neuron
+    Suspended,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct QueryNeuronPairsArgs {
+    admin: Option<AdminFilter>,
+    nns_neuron_id: Option<u64>,
+    wtn_neuron_id: Option<[u8; 32]>,
+    state: Option<NeuronPairState>,
+    name_contains: Option<String>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+enum AdminFilter {
+    Caller,
+    Principal(Principal),
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct QueryNeuronPairsResponse {
+    pairs: Vec<NeuronPairPublic>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum KnownNeuronFlag {
+    Renamed(String),
+    Removed,
 }
 
 #[cfg(test)]
@@ -0,0 +1,85 @@
+use crate::guards::caller_is_controller;
+use crate::state::MAX_NEURON_PAIRS_PER_PRINCIPAL;
+use crate::updates::register_neuron_pair::validate_idempotency_key;
+use crate::{
+    state, DeregisterNeuronPairArgs, DeregisterNeuronPairsArgs, DiscoverWtnNeuronsArgs,
+    RegisterNeuronPairArgs,
+};
+use candid::CandidType;
+use ic_cdk::inspect_message;
+use ic_principal::Principal;
+use serde::de::DeserializeOwned;
+
+// Drops ingress messages which would be rejected anyway before they are executed, so that they
+// don't cost the relay any cycles. This isn't called for inter-canister calls, so each endpoint
+// still makes the same checks itself.
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let caller = ic_cdk::caller();
+
+    let accept = match method.as_str() {
+        "register_neuron_pair" => {
+            caller != Principal::anonymous()
+                && decode::<RegisterNeuronPairArgs>().is_some_and(|args| {
+                    is_valid_registration(&args)
+                        && (is_replay(caller, &args) || !is_rate_limited(caller))
+                })
+        }
+        "register_neuron_pairs" => {
+            caller != Principal::anonymous()
+                && decode::<Vec<RegisterNeuronPairArgs>>().is_some_and(|args| {
+                    !args.is_empty()
+                        && args.len() <= MAX_NEURON_PAIRS_PER_PRINCIPAL as usize
+                        && args.iter().all(is_valid_registration)
+                        && (args.iter().all(|a| is_replay(caller, a)) || !is_rate_limited(caller))
+                })
+        }
+        "discover_wtn_neurons" => {
+            caller != Principal::anonymous()
+                && !is_rate_limited(caller)
+                && decode::<DiscoverWtnNeuronsArgs>().is_some()
+        }
+        "deregister_neuron_pair" | "deregister_neuron_pair_v2" => {
+            caller != Principal::anonymous() && decode::<DeregisterNeuronPairArgs>().is_some()
+        }
+        "deregister_neuron_pairs" => {
+            caller != Principal::anonymous() && decode::<DeregisterNeuronPairsArgs>().is_some()
+        }
+        "invalidate_wtn_proposal_mappings" | "seed_wtn_proposal_mappings" | "set_paused" => {
+            caller_is_controller().is_ok()
+        }
+        _ => true,
+    };
+
+    if accept {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+fn decode<T: CandidType + DeserializeOwned>() -> Option<T> {
+    candid::decode_one(&ic_cdk::api::call::arg_data_raw()).ok()
+}
+
+fn is_valid_registration(args: &RegisterNeuronPairArgs) -> bool {
+    args.nns_neuron_id.is_some() != args.nns_known_neuron_name.is_some()
+        && args
+            .idempotency_key
+            .as_deref()
+            .map_or(Ok(()), validate_idempotency_key)
+            .is_ok()
+}
+
+// Calls which only replay the outcome of an earlier registration aren't rate limited
+fn is_replay(caller: Principal, args: &RegisterNeuronPairArgs) -> bool {
+    args.idempotency_key.as_deref().is_some_and(|key| {
+        state::read(|s| {
+            s.registration_outcome(caller, key, ic_cdk::api::time())
+                .is_some()
+        })
+    })
+}
+
+fn is_rate_limited(caller: Principal) -> bool {
+    state::read(|s| s.is_rate_limited(caller, ic_cdk::api::time()))
+}
@@ -1,4 +1,5 @@
 mod init;
+mod inspect_message;
 mod post_upgrade;
 mod pre_upgrade;
 
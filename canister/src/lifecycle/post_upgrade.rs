@@ -11,16 +11,23 @@ type Serialized = (State, Vec<String>);
 
 #[post_upgrade]
 fn post_upgrade(args: InitOrUpgradeArgs) {
-    let _args = args.into_upgrade_args();
+    let upgrade_args = args.into_upgrade_args();
     let memory = get_upgrades_memory();
     let reader = BufferedReader::new(READER_WRITER_BUFFER_SIZE, Reader::new(&memory, 0));
     let mut deserializer = rmp_serde::Deserializer::new(reader);
 
-    let (state, logs) = Serialized::deserialize(&mut deserializer).unwrap();
+    let (mut state, logs) = Serialized::deserialize(&mut deserializer).unwrap();
+    state.rebuild_indexes();
+    state.migrate_legacy_wtn_proposal_mappings(ic_cdk::api::time());
+    state.apply_upgrade_args(upgrade_args);
 
     crate::jobs::start_jobs(&state);
     crate::state::init(state);
     crate::logs::init(logs);
 
+    // No calls can still be in flight once the canister has been upgraded, so any votes which
+    // were in flight are returned to the queue
+    crate::state::mutate(|s| s.requeue_expired_vote_leases(u64::MAX));
+
     log("Canister upgrade complete");
 }
@@ -4,6 +4,10 @@ use ic_stable_structures::{
 };
 
 const UPGRADES: MemoryId = MemoryId::new(0);
+const WTN_PROPOSAL_MAPPINGS: MemoryId = MemoryId::new(1);
+const WTN_PROPOSAL_MAPPINGS_BY_WTN_PROPOSAL: MemoryId = MemoryId::new(2);
+const WTN_PROPOSAL_MAPPINGS_BY_EXPIRY: MemoryId = MemoryId::new(3);
+const QUARANTINED_WTN_PROPOSAL_MAPPINGS: MemoryId = MemoryId::new(4);
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -16,6 +20,22 @@ pub fn get_upgrades_memory() -> Memory {
     get_memory(UPGRADES)
 }
 
+pub fn get_wtn_proposal_mappings_memory() -> Memory {
+    get_memory(WTN_PROPOSAL_MAPPINGS)
+}
+
+pub fn get_wtn_proposal_mappings_by_wtn_proposal_memory() -> Memory {
+    get_memory(WTN_PROPOSAL_MAPPINGS_BY_WTN_PROPOSAL)
+}
+
+pub fn get_wtn_proposal_mappings_by_expiry_memory() -> Memory {
+    get_memory(WTN_PROPOSAL_MAPPINGS_BY_EXPIRY)
+}
+
+pub fn get_quarantined_wtn_proposal_mappings_memory() -> Memory {
+    get_memory(QUARANTINED_WTN_PROPOSAL_MAPPINGS)
+}
+
 fn get_memory(id: MemoryId) -> Memory {
     MEMORY_MANAGER.with(|m| m.get(id))
 }
@@ -1,10 +1,22 @@
-use crate::{NeuronPairPublic, WtnVote};
+use crate::{
+    KnownNeuronFlag, NeuronPairPublic, NeuronPairState, NnsVote, PairEvent, PairEventKind,
+    PairSuspension, PreemptionStats, SnsError, VoteCoverage, WtnVote,
+};
 use candid::Deserialize;
 use ic_principal::Principal;
 use serde::Serialize;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, VecDeque};
 
 const MAX_NAME_LEN: usize = 100;
+const MAX_HISTORY_LEN: usize = 1000;
+
+pub struct NewNeuronPair {
+    pub name: String,
+    pub nns_neuron_id: u64,
+    pub nns_known_neuron_name: Option<String>,
+    pub wtn_neuron_id: [u8; 32],
+    pub backfill_open_proposals: bool,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NeuronPair {
@@ -12,18 +24,40 @@ pub struct NeuronPair {
     name: String,
     admin: Principal,
     nns_neuron_id: u64,
+    #[serde(default)]
+    nns_known_neuron_name: Option<String>,
+    #[serde(default)]
+    nns_known_neuron_flag: Option<KnownNeuronFlag>,
     wtn_neuron_id: [u8; 32],
+    #[serde(default)]
+    registered_at: u64,
+    // The highest NNS proposal id amongst the ballots which the NNS neuron had already cast when
+    // the pair was first polled. Ballots up to this point are only relayed if backfilling.
+    #[serde(default)]
+    nns_ballot_watermark: Option<u64>,
+    #[serde(default)]
+    backfill_open_proposals: bool,
     already_seen_nns_votes: BTreeSet<u64>,
     wtn_votes: Vec<WtnVote>,
+    #[serde(default)]
+    history: VecDeque<PairEvent>,
+    #[serde(default)]
+    coverage: Option<VoteCoverage>,
+    #[serde(default)]
+    preemption_stats: PreemptionStats,
+    #[serde(default)]
+    suspension: Option<PairSuspension>,
 }
 
 impl NeuronPair {
-    pub fn new(
-        mut name: String,
-        admin: Principal,
-        nns_neuron_id: u64,
-        wtn_neuron_id: [u8; 32],
-    ) -> NeuronPair {
+    pub fn new(admin: Principal, args: NewNeuronPair, now: u64) -> NeuronPair {
+        let NewNeuronPair {
+            mut name,
+            nns_neuron_id,
+            nns_known_neuron_name,
+            wtn_neuron_id,
+            backfill_open_proposals,
+        } = args;
         name.truncate(MAX_NAME_LEN);
 
         let mut bytes = Vec::new();
@@ -37,9 +71,18 @@ impl NeuronPair {
             name,
             admin,
             nns_neuron_id,
+            nns_known_neuron_name,
+            nns_known_neuron_flag: None,
             wtn_neuron_id,
+            registered_at: now,
+            nns_ballot_watermark: None,
+            backfill_open_proposals,
             already_seen_nns_votes: BTreeSet::new(),
             wtn_votes: Vec::new(),
+            history: VecDeque::new(),
+            coverage: None,
+            preemption_stats: PreemptionStats::default(),
+            suspension: None,
         }
     }
 
@@ -47,6 +90,10 @@ impl NeuronPair {
         self.id
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn admin(&self) -> Principal {
         self.admin
     }
@@ -59,6 +106,131 @@ impl NeuronPair {
         self.wtn_neuron_id
     }
 
+    pub fn state(&self) -> NeuronPairState {
+        if self.suspension.is_some() {
+            NeuronPairState::Suspended
+        } else if self.nns_known_neuron_flag.is_some() {
+            NeuronPairState::Flagged
+        } else {
+            NeuronPairState::Active
+        }
+    }
+
+    pub fn nns_known_neuron_name(&self) -> Option<&str> {
+        self.nns_known_neuron_name.as_deref()
+    }
+
+    // Flags the pair if the known neuron it was registered against has since been renamed or
+    // removed. Returns true if the flag changed.
+    pub fn update_known_neuron_flag(&mut self, current_name: Option<&str>) -> bool {
+        let Some(registered_name) = self.nns_known_neuron_name.as_deref() else {
+            return false;
+        };
+        let flag = match current_name {
+            Some(name) if name == registered_name => None,
+            Some(name) => Some(KnownNeuronFlag::Renamed(name.to_string())),
+            None => Some(KnownNeuronFlag::Removed),
+        };
+        if flag != self.nns_known_neuron_flag {
+            self.nns_known_neuron_flag = flag;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspension.is_some()
+    }
+
+    // Returns false if the pair was already suspended
+    pub fn suspend(&mut self, error: SnsError, now: u64) -> bool {
+        if self.suspension.is_some() {
+            return false;
+        }
+        self.suspension = Some(PairSuspension {
+            timestamp: now,
+            error,
+        });
+        true
+    }
+
+    // Returns false if the pair wasn't suspended
+    pub fn resume(&mut self) -> bool {
+        self.suspension.take().is_some()
+    }
+
+    // Pairs registered before watermarks were introduced have been relaying every ballot they
+    // have seen, so they are treated as having a watermark of 0
+    pub fn nns_ballot_watermark(&self) -> Option<u64> {
+        self.nns_ballot_watermark
+            .or_else(|| (!self.already_seen_nns_votes.is_empty()).then_some(0))
+    }
+
+    // Whether the NNS neuron's ballot on the given proposal should be relayed. Ballots cast before
+    // the pair was first polled are only relayed while the proposal is still open on WTN, or if
+    // the pair opted in to backfilling, while it is still open on the NNS.
+    pub fn should_relay_nns_ballot(
+        &self,
+        nns_proposal_id: u64,
+        open_on_wtn: bool,
+        open_on_nns: bool,
+    ) -> bool {
+        match self.nns_ballot_watermark() {
+            Some(watermark) if nns_proposal_id > watermark => true,
+            Some(_) => open_on_wtn || (self.backfill_open_proposals && open_on_nns),
+            None => false,
+        }
+    }
+
+    // Marks the NNS neuron's ballots as seen, returning those which are newly seen and should be
+    // relayed. The first time the pair is polled, its latest ballot is taken as the watermark.
+    pub fn take_nns_votes_to_relay(
+        &mut self,
+        votes: Vec<NnsVote>,
+        open_on_wtn: &BTreeSet<u64>,
+        open_on_nns: &BTreeSet<u64>,
+    ) -> Vec<NnsVote> {
+        let first_poll = self.nns_ballot_watermark().is_none();
+        if first_poll {
+            let watermark = votes
+                .iter()
+                .map(|v| v.proposal_id)
+                .max()
+                .unwrap_or_default();
+            self.nns_ballot_watermark = Some(watermark);
+        }
+
+        let newly_seen: Vec<_> = votes
+            .into_iter()
+            .filter(|v| self.is_newly_seen_nns_vote(v.proposal_id))
+            .collect();
+
+        if !first_poll {
+            return newly_seen;
+        }
+        newly_seen
+            .into_iter()
+            .filter(|v| {
+                self.should_relay_nns_ballot(
+                    v.proposal_id,
+                    open_on_wtn.contains(&v.proposal_id),
+                    open_on_nns.contains(&v.proposal_id),
+                )
+            })
+            .collect()
+    }
+
+    pub fn backfill_open_proposals(&self) -> bool {
+        self.backfill_open_proposals
+    }
+
+    pub fn has_wtn_vote(&self, wtn_proposal_id: u64) -> bool {
+        self.wtn_votes
+            .iter()
+            .any(|v| v.wtn_proposal_id == wtn_proposal_id)
+    }
+
     pub fn is_newly_seen_nns_vote(&mut self, proposal_id: u64) -> bool {
         if self.already_seen_nns_votes.insert(proposal_id) {
             self.prune_old_nns_votes();
@@ -72,6 +244,30 @@ impl NeuronPair {
         self.wtn_votes.push(vote);
     }
 
+    pub fn record_event(&mut self, event: PairEvent) {
+        if let PairEventKind::Preempted { agreed, .. } = event.kind {
+            self.preemption_stats.preempted += 1;
+            match agreed {
+                Some(true) => self.preemption_stats.agreed += 1,
+                Some(false) => self.preemption_stats.disagreed += 1,
+                None => {}
+            }
+        }
+        self.history.push_back(event);
+
+        while self.history.len() > MAX_HISTORY_LEN {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn history(&self) -> &VecDeque<PairEvent> {
+        &self.history
+    }
+
+    pub fn set_coverage(&mut self, coverage: VoteCoverage) {
+        self.coverage = Some(coverage);
+    }
+
     fn prune_old_nns_votes(&mut self) {
         while self.already_seen_nns_votes.len() > 1000 {
             self.already_seen_nns_votes.pop_first();
@@ -92,7 +288,160 @@ impl From<&NeuronPair> for NeuronPairPublic {
             name: value.name.clone(),
             admin: value.admin,
             nns_neuron_id: value.nns_neuron_id,
+            nns_known_neuron_name: value.nns_known_neuron_name.clone(),
+            nns_known_neuron_flag: value.nns_known_neuron_flag.clone(),
             wtn_neuron_id: value.wtn_neuron_id,
+            state: value.state(),
+            registered_at: value.registered_at,
+            nns_ballot_watermark: value.nns_ballot_watermark(),
+            coverage: value.coverage.clone(),
+            preemption_stats: value.preemption_stats.clone(),
+            suspension: value.suspension.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Vote;
+
+    fn pair(backfill_open_proposals: bool) -> NeuronPair {
+        let args = NewNeuronPair {
+            name: "Pair".to_string(),
+            nns_neuron_id: 1,
+            nns_known_neuron_name: None,
+            wtn_neuron_id: [1; 32],
+            backfill_open_proposals,
+        };
+        NeuronPair::new(Principal::from_slice(&[1]), args, 0)
+    }
+
+    fn votes(proposal_ids: &[u64]) -> Vec<NnsVote> {
+        proposal_ids
+            .iter()
+            .map(|id| NnsVote {
+                proposal_id: *id,
+                vote: Vote::Adopt,
+            })
+            .collect()
+    }
+
+    fn proposal_ids(votes: Vec<NnsVote>) -> Vec<u64> {
+        votes.into_iter().map(|v| v.proposal_id).collect()
+    }
+
+    #[test]
+    fn known_neuron_flag_follows_the_known_neurons_name() {
+        let mut pair = pair(false);
+        assert!(!pair.update_known_neuron_flag(None));
+        assert_eq!(pair.nns_known_neuron_flag, None);
+
+        pair.nns_known_neuron_name = Some("CodeGov".to_string());
+        assert!(!pair.update_known_neuron_flag(Some("CodeGov")));
+
+        assert!(pair.update_known_neuron_flag(Some("CodeGov Project")));
+        assert_eq!(
+            pair.nns_known_neuron_flag,
+            Some(KnownNeuronFlag::Renamed("CodeGov Project".to_string()))
+        );
+        assert!(!pair.update_known_neuron_flag(Some("CodeGov Project")));
+
+        assert!(pair.update_known_neuron_flag(None));
+        assert_eq!(pair.nns_known_neuron_flag, Some(KnownNeuronFlag::Removed));
+
+        // The flag is cleared if the original name is restored
+        assert!(pair.update_known_neuron_flag(Some("CodeGov")));
+        assert_eq!(pair.nns_known_neuron_flag, None);
+    }
+
+    #[test]
+    fn first_poll_only_relays_ballots_open_on_wtn() {
+        let mut pair = pair(false);
+        let open_on_wtn = BTreeSet::from([8]);
+        let open_on_nns = BTreeSet::from([8, 9, 10]);
+
+        let relayed =
+            pair.take_nns_votes_to_relay(votes(&[7, 8, 9, 10]), &open_on_wtn, &open_on_nns);
+        assert_eq!(proposal_ids(relayed), vec![8]);
+        assert_eq!(pair.nns_ballot_watermark(), Some(10));
+
+        // Later polls relay every newly seen ballot
+        let relayed = pair.take_nns_votes_to_relay(
+            votes(&[7, 8, 9, 10, 11]),
+            &BTreeSet::new(),
+            &BTreeSet::new(),
+        );
+        assert_eq!(proposal_ids(relayed), vec![11]);
+
+        assert!(pair.should_relay_nns_ballot(9, true, false));
+        assert!(!pair.should_relay_nns_ballot(9, false, true));
+        assert!(pair.should_relay_nns_ballot(12, false, false));
+    }
+
+    #[test]
+    fn first_poll_backfills_ballots_open_on_nns() {
+        let mut pair = pair(true);
+        let open_on_wtn = BTreeSet::from([8]);
+        let open_on_nns = BTreeSet::from([9, 10]);
+
+        let relayed =
+            pair.take_nns_votes_to_relay(votes(&[7, 8, 9, 10]), &open_on_wtn, &open_on_nns);
+        assert_eq!(proposal_ids(relayed), vec![8, 9, 10]);
+        assert!(pair.should_relay_nns_ballot(9, false, true));
+        assert!(!pair.should_relay_nns_ballot(7, false, false));
+    }
+
+    #[test]
+    fn first_poll_without_ballots_sets_a_zero_watermark() {
+        let mut pair = pair(false);
+        assert!(!pair.should_relay_nns_ballot(1, true, true));
+
+        let relayed = pair.take_nns_votes_to_relay(Vec::new(), &BTreeSet::new(), &BTreeSet::new());
+        assert!(relayed.is_empty());
+        assert_eq!(pair.nns_ballot_watermark(), Some(0));
+        assert!(pair.should_relay_nns_ballot(1, false, false));
+    }
+
+    #[test]
+    fn legacy_pairs_keep_relaying_every_newly_seen_ballot() {
+        // Pairs registered before watermarks were introduced have seen ballots but no watermark
+        let mut pair = pair(false);
+        pair.is_newly_seen_nns_vote(5);
+        assert_eq!(pair.nns_ballot_watermark(), Some(0));
+
+        let relayed =
+            pair.take_nns_votes_to_relay(votes(&[3, 5, 6]), &BTreeSet::new(), &BTreeSet::new());
+        assert_eq!(proposal_ids(relayed), vec![3, 6]);
+        assert!(pair.should_relay_nns_ballot(4, false, false));
+    }
+
+    fn preempted(earlier_vote: Option<Vote>, agreed: Option<bool>) -> PairEvent {
+        PairEvent {
+            timestamp: 0,
+            nns_proposal_id: 1,
+            wtn_proposal_id: Some(2),
+            kind: PairEventKind::Preempted {
+                earlier_vote,
+                agreed,
+            },
         }
     }
+
+    #[test]
+    fn preemption_stats_count_agreed_and_disagreed_votes() {
+        let mut pair = pair(false);
+
+        pair.record_event(preempted(Some(Vote::Adopt), Some(true)));
+        pair.record_event(preempted(Some(Vote::Adopt), Some(true)));
+        pair.record_event(preempted(Some(Vote::Reject), Some(false)));
+        pair.record_event(preempted(Some(Vote::Unspecified), None));
+        pair.record_event(preempted(None, None));
+
+        let stats = &pair.preemption_stats;
+        assert_eq!(stats.preempted, 5);
+        assert_eq!(stats.agreed, 2);
+        assert_eq!(stats.disagreed, 1);
+        assert_eq!(pair.history().len(), 5);
+    }
 }
@@ -0,0 +1,69 @@
+use crate::neuron_pair::NeuronPair;
+use ic_principal::Principal;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Secondary indexes over the neuron pairs, allowing them to be looked up by admin, NNS neuron or
+// WTN neuron without scanning every pair. These are not persisted across upgrades, instead they
+// are rebuilt from the neuron pairs after the state has been deserialized.
+#[derive(Default)]
+pub struct NeuronPairIndex {
+    by_admin: BTreeMap<Principal, BTreeSet<u64>>,
+    by_nns_neuron: BTreeMap<u64, BTreeSet<u64>>,
+    by_wtn_neuron: BTreeMap<[u8; 32], BTreeSet<u64>>,
+}
+
+impl NeuronPairIndex {
+    pub fn build<'a>(pairs: impl Iterator<Item = &'a NeuronPair>) -> NeuronPairIndex {
+        let mut index = NeuronPairIndex::default();
+        for pair in pairs {
+            index.add(pair);
+        }
+        index
+    }
+
+    pub fn add(&mut self, pair: &NeuronPair) {
+        self.by_admin
+            .entry(pair.admin())
+            .or_default()
+            .insert(pair.id());
+        self.by_nns_neuron
+            .entry(pair.nns_neuron_id())
+            .or_default()
+            .insert(pair.id());
+        self.by_wtn_neuron
+            .entry(pair.wtn_neuron_id())
+            .or_default()
+            .insert(pair.id());
+    }
+
+    pub fn remove(&mut self, pair: &NeuronPair) {
+        remove_from(&mut self.by_admin, &pair.admin(), pair.id());
+        remove_from(&mut self.by_nns_neuron, &pair.nns_neuron_id(), pair.id());
+        remove_from(&mut self.by_wtn_neuron, &pair.wtn_neuron_id(), pair.id());
+    }
+
+    pub fn by_admin(&self, admin: Principal) -> Option<&BTreeSet<u64>> {
+        self.by_admin.get(&admin)
+    }
+
+    pub fn by_nns_neuron(&self, nns_neuron_id: u64) -> Option<&BTreeSet<u64>> {
+        self.by_nns_neuron.get(&nns_neuron_id)
+    }
+
+    pub fn nns_neuron_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.by_nns_neuron.keys().copied()
+    }
+
+    pub fn by_wtn_neuron(&self, wtn_neuron_id: [u8; 32]) -> Option<&BTreeSet<u64>> {
+        self.by_wtn_neuron.get(&wtn_neuron_id)
+    }
+}
+
+fn remove_from<K: Ord>(map: &mut BTreeMap<K, BTreeSet<u64>>, key: &K, pair_id: u64) {
+    if let Some(ids) = map.get_mut(key) {
+        ids.remove(&pair_id);
+        if ids.is_empty() {
+            map.remove(key);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// The number of proposal ids which can be awaiting recovery per NNS neuron. If a gap is larger
+// than this, only the most recent proposals are recovered.
+const MAX_GAP_PROPOSALS_PER_NEURON: usize = 2000;
+
+// `get_neuron_info` only returns an NNS neuron's most recent ballots, so if polling stalls while
+// many proposals are being voted on, some ballots fall out of that window before they are seen.
+// This tracks the highest proposal id seen in each neuron's ballots so that when a poll no longer
+// overlaps with the previous one, the proposal ids in between can be looked up individually.
+// Most of those proposal ids won't have had a ballot for the neuron, so a proposal only counts as
+// unrecoverable once its lookup finds a vote which can no longer be relayed.
+#[derive(Serialize, Deserialize, Default)]
+pub struct NnsBallotGaps {
+    neurons: BTreeMap<u64, NeuronBallots>,
+    #[serde(default)]
+    unrecoverable_count: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct NeuronBallots {
+    highest_proposal_id_seen: u64,
+    proposals_to_recover: BTreeSet<u64>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BallotPollOutcome {
+    // The proposal ids newly found to be missing from the neuron's ballots
+    pub added: usize,
+    // The missing proposal ids whose ballots have since shown up in a poll
+    pub recovered: usize,
+}
+
+// The result of looking up a missing proposal for one of the neurons awaiting its ballot
+#[derive(Debug, PartialEq, Eq)]
+pub enum BallotLookupOutcome {
+    // The neuron voted while the proposal was still open, so its vote can be relayed
+    Recovered,
+    // The neuron voted but the proposal has since closed, so its vote can't be relayed
+    Unrecoverable,
+    // The neuron had no ballot on the proposal, or hadn't voted on it
+    NoVote,
+}
+
+impl NnsBallotGaps {
+    // Records the proposal ids of the ballots returned by a single poll of the neuron
+    pub fn record_poll(
+        &mut self,
+        nns_neuron_id: u64,
+        ballot_proposal_ids: &[u64],
+    ) -> BallotPollOutcome {
+        let (Some(&lowest), Some(&highest)) = (
+            ballot_proposal_ids.iter().min(),
+            ballot_proposal_ids.iter().max(),
+        ) else {
+            return BallotPollOutcome::default();
+        };
+
+        let Some(neuron) = self.neurons.get_mut(&nns_neuron_id) else {
+            // The first poll of a neuron sets the baseline, there is nothing to compare it with
+            self.neurons.insert(
+                nns_neuron_id,
+                NeuronBallots {
+                    highest_proposal_id_seen: highest,
+                    proposals_to_recover: BTreeSet::new(),
+                },
+            );
+            return BallotPollOutcome::default();
+        };
+
+        let mut outcome = BallotPollOutcome::default();
+        for proposal_id in ballot_proposal_ids {
+            if neuron.proposals_to_recover.remove(proposal_id) {
+                outcome.recovered += 1;
+            }
+        }
+
+        if lowest > neuron.highest_proposal_id_seen + 1 {
+            let gap_start = (neuron.highest_proposal_id_seen + 1)
+                .max(lowest.saturating_sub(MAX_GAP_PROPOSALS_PER_NEURON as u64));
+
+            for proposal_id in gap_start..lowest {
+                if neuron.proposals_to_recover.insert(proposal_id) {
+                    outcome.added += 1;
+                }
+            }
+            while neuron.proposals_to_recover.len() > MAX_GAP_PROPOSALS_PER_NEURON {
+                neuron.proposals_to_recover.pop_first();
+            }
+        }
+        neuron.highest_proposal_id_seen = neuron.highest_proposal_id_seen.max(highest);
+        outcome
+    }
+
+    // Returns up to `limit` proposal ids awaiting recovery, most recent first, along with the NNS
+    // neurons whose ballots on each proposal are needed
+    pub fn next_proposals_to_recover(&self, limit: usize) -> BTreeMap<u64, Vec<u64>> {
+        let proposal_ids: BTreeSet<u64> = self
+            .neurons
+            .values()
+            .flat_map(|n| n.proposals_to_recover.iter().copied())
+            .collect();
+
+        proposal_ids
+            .into_iter()
+            .rev()
+            .take(limit)
+            .map(|proposal_id| {
+                let neuron_ids = self
+                    .neurons
+                    .iter()
+                    .filter(|(_, n)| n.proposals_to_recover.contains(&proposal_id))
+                    .map(|(id, _)| *id)
+                    .collect();
+                (proposal_id, neuron_ids)
+            })
+            .collect()
+    }
+
+    // Records the outcome of looking up the neuron's ballot on a missing proposal, which is then
+    // no longer awaiting recovery
+    pub fn record_lookup(
+        &mut self,
+        nns_neuron_id: u64,
+        proposal_id: u64,
+        outcome: &BallotLookupOutcome,
+    ) {
+        let Some(neuron) = self.neurons.get_mut(&nns_neuron_id) else {
+            return;
+        };
+        if neuron.proposals_to_recover.remove(&proposal_id)
+            && *outcome == BallotLookupOutcome::Unrecoverable
+        {
+            self.unrecoverable_count += 1;
+        }
+    }
+
+    pub fn proposals_to_recover_count(&self) -> usize {
+        self.neurons
+            .values()
+            .map(|n| n.proposals_to_recover.len())
+            .sum()
+    }
+
+    pub fn unrecoverable_count(&self) -> u64 {
+        self.unrecoverable_count
+    }
+
+    // Stops tracking any neurons which are no longer followed by a neuron pair
+    pub fn retain_neurons(&mut self, nns_neuron_ids: &BTreeSet<u64>) {
+        self.neurons.retain(|id, _| nns_neuron_ids.contains(id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(added: usize, recovered: usize) -> BallotPollOutcome {
+        BallotPollOutcome { added, recovered }
+    }
+
+    #[test]
+    fn overlapping_polls_do_not_create_gaps() {
+        let mut gaps = NnsBallotGaps::default();
+        assert_eq!(gaps.record_poll(1, &[10, 11, 12]), outcome(0, 0));
+        assert_eq!(gaps.record_poll(1, &[12, 13, 14]), outcome(0, 0));
+        assert_eq!(gaps.record_poll(1, &[15, 16]), outcome(0, 0));
+        assert_eq!(gaps.proposals_to_recover_count(), 0);
+    }
+
+    #[test]
+    fn missing_proposals_between_polls_are_looked_up_most_recent_first() {
+        let mut gaps = NnsBallotGaps::default();
+        gaps.record_poll(1, &[10, 11, 12]);
+        gaps.record_poll(2, &[11]);
+        assert_eq!(gaps.record_poll(1, &[16, 17]), outcome(3, 0));
+        assert_eq!(gaps.record_poll(2, &[14, 15]), outcome(2, 0));
+
+        let next = gaps.next_proposals_to_recover(2);
+        assert_eq!(next.get(&15), Some(&vec![1]));
+        assert_eq!(next.get(&14), Some(&vec![1]));
+        assert_eq!(next.len(), 2);
+        assert_eq!(
+            gaps.next_proposals_to_recover(10).get(&13),
+            Some(&vec![1, 2])
+        );
+
+        gaps.record_lookup(1, 13, &BallotLookupOutcome::Recovered);
+        assert_eq!(gaps.next_proposals_to_recover(10).get(&13), Some(&vec![2]));
+        assert_eq!(gaps.proposals_to_recover_count(), 4);
+
+        // Neuron 1 votes late on proposal 14, so its ballot shows up in the next poll
+        assert_eq!(gaps.record_poll(1, &[14, 17, 18]), outcome(0, 1));
+        assert_eq!(gaps.proposals_to_recover_count(), 3);
+    }
+
+    #[test]
+    fn only_votes_which_can_no_longer_be_relayed_are_unrecoverable() {
+        let mut gaps = NnsBallotGaps::default();
+        gaps.record_poll(1, &[10]);
+        assert_eq!(gaps.record_poll(1, &[15]), outcome(4, 0));
+
+        gaps.record_lookup(1, 11, &BallotLookupOutcome::NoVote);
+        gaps.record_lookup(1, 12, &BallotLookupOutcome::Recovered);
+        gaps.record_lookup(1, 13, &BallotLookupOutcome::Unrecoverable);
+        // Lookups of proposals which are no longer awaiting recovery aren't counted again
+        gaps.record_lookup(1, 13, &BallotLookupOutcome::Unrecoverable);
+        gaps.record_lookup(2, 14, &BallotLookupOutcome::Unrecoverable);
+
+        assert_eq!(gaps.proposals_to_recover_count(), 1);
+        assert_eq!(gaps.unrecoverable_count(), 1);
+    }
+
+    #[test]
+    fn large_gaps_are_capped() {
+        let mut gaps = NnsBallotGaps::default();
+        gaps.record_poll(1, &[1]);
+        gaps.record_poll(1, &[100_000]);
+        assert_eq!(
+            gaps.proposals_to_recover_count(),
+            MAX_GAP_PROPOSALS_PER_NEURON
+        );
+        assert_eq!(gaps.unrecoverable_count(), 0);
+    }
+}
@@ -0,0 +1,470 @@
+use crate::{NnsVote, Vote};
+use candid::CandidType;
+use ic_cdk::api::call::CallResult;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const PROPOSAL_STATUS_OPEN: i32 = 1;
+const LIST_PROPOSALS_PAGE_SIZE: u32 = 100;
+
+// Returns the names of all known neurons, keyed by neuron id
+pub async fn list_known_neurons(
+    nns_governance_canister_id: Principal,
+) -> CallResult<BTreeMap<u64, String>> {
+    let response: CallResult<(ListKnownNeuronsResponse,)> =
+        ic_cdk::call(nns_governance_canister_id, "list_known_neurons", ()).await;
+
+    response.map(|r| {
+        r.0.known_neurons
+            .into_iter()
+            .filter_map(|n| Some((n.id?.id, n.known_neuron_data?.name)))
+            .collect()
+    })
+}
+
+// Returns the deadline (in seconds) of every open proposal, keyed by proposal id
+pub async fn list_open_proposals(
+    nns_governance_canister_id: Principal,
+) -> CallResult<BTreeMap<u64, u64>> {
+    let mut proposals = BTreeMap::new();
+    let mut before_proposal = None;
+
+    loop {
+        let args = ListProposalInfo {
+            limit: LIST_PROPOSALS_PAGE_SIZE,
+            before_proposal,
+            exclude_topic: Vec::new(),
+            include_reward_status: Vec::new(),
+            include_status: vec![PROPOSAL_STATUS_OPEN],
+            include_all_manage_neuron_proposals: Some(false),
+            omit_large_fields: Some(true),
+        };
+        let response: CallResult<(ListProposalInfoResponse,)> =
+            ic_cdk::call(nns_governance_canister_id, "list_proposals", (args,)).await;
+
+        let page = response?.0.proposal_info;
+        let is_last_page = page.len() < LIST_PROPOSALS_PAGE_SIZE as usize;
+        before_proposal = page
+            .iter()
+            .filter_map(|p| p.id.as_ref())
+            .map(|id| id.id)
+            .min()
+            .map(|id| ProposalId { id });
+
+        for proposal in page {
+            if let Some(id) = proposal.id {
+                proposals.insert(
+                    id.id,
+                    proposal.deadline_timestamp_seconds.unwrap_or_default(),
+                );
+            }
+        }
+
+        if is_last_page || before_proposal.is_none() {
+            return Ok(proposals);
+        }
+    }
+}
+
+pub async fn get_neuron_info(
+    nns_governance_canister_id: Principal,
+    nns_neuron_id: u64,
+) -> CallResult<Result<NeuronInfo, GovernanceError>> {
+    let response: CallResult<(Result<NeuronInfo, GovernanceError>,)> = ic_cdk::call(
+        nns_governance_canister_id,
+        "get_neuron_info",
+        (nns_neuron_id,),
+    )
+    .await;
+
+    response.map(|r| r.0)
+}
+
+// Returns None if the proposal doesn't exist. The ballots included are only those of the neurons
+// which this canister controls or is a hotkey of.
+pub async fn get_proposal_info(
+    nns_governance_canister_id: Principal,
+    proposal_id: u64,
+) -> CallResult<Option<ProposalInfo>> {
+    let response: CallResult<(Option<ProposalInfo>,)> = ic_cdk::call(
+        nns_governance_canister_id,
+        "get_proposal_info",
+        (proposal_id,),
+    )
+    .await;
+
+    response.map(|r| r.0)
+}
+
+pub fn find_known_neuron_by_name(known_neurons: &BTreeMap<u64, String>, name: &str) -> Option<u64> {
+    let name = name.trim();
+    known_neurons
+        .iter()
+        .find(|(_, n)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(id, _)| *id)
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct ListKnownNeuronsResponse {
+    known_neurons: Vec<KnownNeuron>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct KnownNeuron {
+    id: Option<NeuronId>,
+    known_neuron_data: Option<KnownNeuronData>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct NeuronId {
+    id: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+struct ProposalId {
+    id: u64,
+}
+
+#[derive(CandidType, Serialize, Debug)]
+struct ListProposalInfo {
+    limit: u32,
+    before_proposal: Option<ProposalId>,
+    exclude_topic: Vec<i32>,
+    include_reward_status: Vec<i32>,
+    include_status: Vec<i32>,
+    include_all_manage_neuron_proposals: Option<bool>,
+    omit_large_fields: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct ListProposalInfoResponse {
+    proposal_info: Vec<ProposalInfo>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ProposalInfo {
+    id: Option<ProposalId>,
+    deadline_timestamp_seconds: Option<u64>,
+    ballots: Vec<(u64, Ballot)>,
+}
+
+impl ProposalInfo {
+    // The vote of the given neuron, if its ballot is included and it has voted
+    pub fn vote(&self, nns_neuron_id: u64) -> Option<NnsVote> {
+        let (_, ballot) = self.ballots.iter().find(|(id, _)| *id == nns_neuron_id)?;
+        Some(NnsVote {
+            proposal_id: self.id.as_ref()?.id,
+            vote: Vote::try_from(ballot.vote).ok()?,
+        })
+        .filter(|v| v.vote != Vote::Unspecified)
+    }
+
+    pub fn is_open(&self, now_seconds: u64) -> bool {
+        self.deadline_timestamp_seconds
+            .is_some_and(|deadline| now_seconds < deadline)
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct Ballot {
+    vote: i32,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct KnownNeuronData {
+    name: String,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct NeuronInfo {
+    recent_ballots: Vec<BallotInfo>,
+}
+
+impl NeuronInfo {
+    // The proposal ids of all of the neuron's recent ballots, including those where it has not yet
+    // voted
+    pub fn ballot_proposal_ids(&self) -> Vec<u64> {
+        self.recent_ballots
+            .iter()
+            .filter_map(|b| b.proposal_id.as_ref())
+            .map(|id| id.id)
+            .collect()
+    }
+
+    // The neuron's recent votes, excluding any ballots where it has not yet voted, so that they
+    // are not marked as seen and will be picked up again once the neuron votes
+    pub fn votes(self) -> Vec<NnsVote> {
+        self.recent_ballots
+            .into_iter()
+            .filter_map(|b| NnsVote::try_from(b).ok())
+            .filter(|v| v.vote != Vote::Unspecified)
+            .collect()
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct BallotInfo {
+    vote: i32,
+    proposal_id: Option<ProposalId>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GovernanceError {
+    error_message: String,
+    error_type: i32,
+}
+
+impl TryFrom<BallotInfo> for NnsVote {
+    type Error = ();
+
+    fn try_from(value: BallotInfo) -> Result<Self, Self::Error> {
+        Ok(NnsVote {
+            proposal_id: value.proposal_id.ok_or(())?.id,
+            vote: Vote::try_from(value.vote).map_err(|_| ())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_neurons_are_found_by_name_ignoring_case_and_whitespace() {
+        let known_neurons = BTreeMap::from([
+            (1, "DFINITY Foundation".to_string()),
+            (2, " CodeGov ".to_string()),
+        ]);
+
+        assert_eq!(
+            find_known_neuron_by_name(&known_neurons, "codegov"),
+            Some(2)
+        );
+        assert_eq!(
+            find_known_neuron_by_name(&known_neurons, "  DFINITY FOUNDATION"),
+            Some(1)
+        );
+        assert_eq!(find_known_neuron_by_name(&known_neurons, "DFINITY"), None);
+        assert_eq!(find_known_neuron_by_name(&BTreeMap::new(), "CodeGov"), None);
+    }
+
+    fn ballot(vote: i32) -> BallotInfo {
+        BallotInfo {
+            vote,
+            proposal_id: Some(ProposalId { id: 123 }),
+        }
+    }
+
+    #[test]
+    fn adopt_ballot_is_relayed_as_adopt() {
+        let vote = NnsVote::try_from(ballot(1)).unwrap();
+        assert_eq!(vote.proposal_id, 123);
+        assert_eq!(vote.vote, Vote::Adopt);
+    }
+
+    #[test]
+    fn reject_ballot_is_relayed_as_reject() {
+        let vote = NnsVote::try_from(ballot(2)).unwrap();
+        assert_eq!(vote.proposal_id, 123);
+        assert_eq!(vote.vote, Vote::Reject);
+    }
+
+    #[test]
+    fn unspecified_ballot_is_not_relayed() {
+        let vote = NnsVote::try_from(ballot(0)).unwrap();
+        assert_eq!(vote.vote, Vote::Unspecified);
+
+        let neuron = NeuronInfo {
+            recent_ballots: vec![ballot(0)],
+        };
+        assert!(neuron.votes().is_empty());
+    }
+
+    #[test]
+    fn votes_queued_before_upgrade_keep_their_direction() {
+        #[derive(Serialize)]
+        struct LegacyNnsVote {
+            proposal_id: u64,
+            adopt: bool,
+        }
+
+        for (adopt, expected) in [(true, Vote::Adopt), (false, Vote::Reject)] {
+            let bytes = rmp_serde::to_vec_named(&LegacyNnsVote {
+                proposal_id: 123,
+                adopt,
+            })
+            .unwrap();
+            let vote: NnsVote = rmp_serde::from_slice(&bytes).unwrap();
+            assert_eq!(vote.proposal_id, 123);
+            assert_eq!(vote.vote, expected);
+        }
+
+        let bytes = rmp_serde::to_vec_named(&NnsVote {
+            proposal_id: 123,
+            vote: Vote::Reject,
+        })
+        .unwrap();
+        let vote: NnsVote = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(vote.vote, Vote::Reject);
+    }
+
+    #[test]
+    fn unknown_ballot_is_not_relayed() {
+        assert!(NnsVote::try_from(ballot(3)).is_err());
+        assert!(NnsVote::try_from(ballot(-1)).is_err());
+    }
+
+    #[test]
+    fn ballot_without_proposal_id_is_not_relayed() {
+        let ballot = BallotInfo {
+            vote: 1,
+            proposal_id: None,
+        };
+        assert!(NnsVote::try_from(ballot).is_err());
+    }
+
+    // Encodes a `get_neuron_info` response using the full NNS governance types, so that decoding
+    // is checked against the fields which the relay ignores
+    fn encode_neuron_info(recent_ballots: Vec<(u64, i32)>) -> Vec<u8> {
+        #[derive(CandidType)]
+        struct FullNeuronInfo {
+            dissolve_delay_seconds: u64,
+            recent_ballots: Vec<FullBallotInfo>,
+            voting_power_refreshed_timestamp_seconds: Option<u64>,
+            potential_voting_power: Option<u64>,
+            neuron_type: Option<i32>,
+            deciding_voting_power: Option<u64>,
+            created_timestamp_seconds: u64,
+            state: i32,
+            stake_e8s: u64,
+            joined_community_fund_timestamp_seconds: Option<u64>,
+            retrieved_at_timestamp_seconds: u64,
+            visibility: Option<i32>,
+            known_neuron_data: Option<FullKnownNeuronData>,
+            voting_power: u64,
+            age_seconds: u64,
+        }
+        #[derive(CandidType)]
+        struct FullBallotInfo {
+            vote: i32,
+            proposal_id: Option<ProposalId>,
+        }
+        #[derive(CandidType)]
+        struct FullKnownNeuronData {
+            name: String,
+            description: Option<String>,
+        }
+        #[derive(CandidType)]
+        struct FullGovernanceError {
+            error_message: String,
+            error_type: i32,
+        }
+
+        let neuron: Result<FullNeuronInfo, FullGovernanceError> = Ok(FullNeuronInfo {
+            dissolve_delay_seconds: 252_460_800,
+            recent_ballots: recent_ballots
+                .into_iter()
+                .map(|(id, vote)| FullBallotInfo {
+                    vote,
+                    proposal_id: Some(ProposalId { id }),
+                })
+                .collect(),
+            voting_power_refreshed_timestamp_seconds: Some(1_730_000_000),
+            potential_voting_power: Some(500_000_000_000),
+            neuron_type: None,
+            deciding_voting_power: Some(500_000_000_000),
+            created_timestamp_seconds: 1_620_000_000,
+            state: 1,
+            stake_e8s: 100_000_000_000,
+            joined_community_fund_timestamp_seconds: None,
+            retrieved_at_timestamp_seconds: 1_730_000_100,
+            visibility: Some(2),
+            known_neuron_data: Some(FullKnownNeuronData {
+                name: "CodeGov".to_string(),
+                description: None,
+            }),
+            voting_power: 500_000_000_000,
+            age_seconds: 100_000_000,
+        });
+        candid::encode_one(neuron).unwrap()
+    }
+
+    #[test]
+    fn neuron_info_ballots_include_the_neurons_votes() {
+        // Recent ballots are ordered by when the neuron voted, most recent first, so a late vote
+        // on an older proposal can appear ahead of newer proposals
+        let bytes = encode_neuron_info(vec![(1004, 2), (1001, 1), (1003, 1), (1002, 0)]);
+        let response: Result<NeuronInfo, GovernanceError> = candid::decode_one(&bytes).unwrap();
+        let neuron = response.unwrap();
+
+        assert_eq!(neuron.ballot_proposal_ids(), vec![1004, 1001, 1003, 1002]);
+        let votes: Vec<_> = neuron
+            .votes()
+            .into_iter()
+            .map(|v| (v.proposal_id, v.vote))
+            .collect();
+        assert_eq!(
+            votes,
+            vec![
+                (1004, Vote::Reject),
+                (1001, Vote::Adopt),
+                (1003, Vote::Adopt)
+            ]
+        );
+    }
+
+    #[test]
+    fn proposal_info_includes_the_votes_of_the_returned_ballots() {
+        #[derive(CandidType)]
+        struct FullProposalInfo {
+            id: Option<ProposalId>,
+            status: i32,
+            topic: i32,
+            ballots: Vec<(u64, FullBallot)>,
+            proposal_timestamp_seconds: u64,
+            reward_event_round: u64,
+            deadline_timestamp_seconds: Option<u64>,
+            decided_timestamp_seconds: u64,
+            reject_cost_e8s: u64,
+            reward_status: i32,
+        }
+        #[derive(CandidType)]
+        struct FullBallot {
+            vote: i32,
+            voting_power: u64,
+        }
+
+        let ballot = |vote| FullBallot {
+            vote,
+            voting_power: 1_000,
+        };
+        let bytes = candid::encode_one(Some(FullProposalInfo {
+            id: Some(ProposalId { id: 123 }),
+            status: 1,
+            topic: 4,
+            ballots: vec![(1, ballot(1)), (2, ballot(2)), (3, ballot(0))],
+            proposal_timestamp_seconds: 1_730_000_000,
+            reward_event_round: 0,
+            deadline_timestamp_seconds: Some(1_730_345_600),
+            decided_timestamp_seconds: 0,
+            reject_cost_e8s: 2_500_000_000,
+            reward_status: 1,
+        }))
+        .unwrap();
+        let proposal: Option<ProposalInfo> = candid::decode_one(&bytes).unwrap();
+        let proposal = proposal.unwrap();
+
+        let vote = |nns_neuron_id| {
+            proposal
+                .vote(nns_neuron_id)
+                .map(|v| (v.proposal_id, v.vote))
+        };
+        assert_eq!(vote(1), Some((123, Vote::Adopt)));
+        assert_eq!(vote(2), Some((123, Vote::Reject)));
+        assert_eq!(vote(3), None);
+        assert_eq!(vote(4), None);
+        assert!(proposal.is_open(1_730_345_599));
+        assert!(!proposal.is_open(1_730_345_600));
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// The NNS proposals which are currently open, along with which of them each followed NNS neuron
+// has already voted on. This determines which neurons need polling and how urgently.
+#[derive(Serialize, Deserialize, Default)]
+pub struct OpenNnsProposals {
+    updated_at: Option<u64>,
+    // Keyed by proposal id, with the deadline in seconds as the value
+    deadlines: BTreeMap<u64, u64>,
+    votes_seen: BTreeMap<u64, BTreeSet<u64>>,
+}
+
+impl OpenNnsProposals {
+    // Replaces the set of open proposals, returning the number of proposals which weren't
+    // previously known to be open
+    pub fn update(&mut self, deadlines: BTreeMap<u64, u64>, now: u64) -> usize {
+        let newly_opened = deadlines
+            .keys()
+            .filter(|id| !self.deadlines.contains_key(id))
+            .count();
+
+        for proposal_ids in self.votes_seen.values_mut() {
+            proposal_ids.retain(|id| deadlines.contains_key(id));
+        }
+        self.votes_seen.retain(|_, ids| !ids.is_empty());
+        self.deadlines = deadlines;
+        self.updated_at = Some(now);
+        newly_opened
+    }
+
+    // Returns None until the open proposals have been retrieved for the first time
+    pub fn proposal_ids(&self) -> Option<BTreeSet<u64>> {
+        self.updated_at?;
+        Some(self.deadlines.keys().copied().collect())
+    }
+
+    pub fn count(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn record_votes(&mut self, nns_neuron_id: u64, proposal_ids: impl Iterator<Item = u64>) {
+        let open_proposal_ids: Vec<_> = proposal_ids
+            .filter(|id| self.deadlines.contains_key(id))
+            .collect();
+
+        if !open_proposal_ids.is_empty() {
+            self.votes_seen
+                .entry(nns_neuron_id)
+                .or_default()
+                .extend(open_proposal_ids);
+        }
+    }
+
+    // The proposals which are still open and which the neuron has not yet been seen voting on,
+    // along with their deadlines
+    pub fn pending(
+        &self,
+        nns_neuron_id: u64,
+        now_seconds: u64,
+    ) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let votes_seen = self.votes_seen.get(&nns_neuron_id);
+        self.deadlines
+            .iter()
+            .filter(move |(_, deadline)| **deadline > now_seconds)
+            .filter(move |(id, _)| !votes_seen.is_some_and(|v| v.contains(id)))
+            .map(|(id, deadline)| (*id, *deadline))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_excludes_voted_and_expired_proposals() {
+        let mut open_proposals = OpenNnsProposals::default();
+        assert!(open_proposals.proposal_ids().is_none());
+
+        let newly_opened = open_proposals.update(BTreeMap::from([(1, 100), (2, 200), (3, 300)]), 0);
+        assert_eq!(newly_opened, 3);
+
+        open_proposals.record_votes(10, [2, 4].into_iter());
+        let pending: Vec<_> = open_proposals.pending(10, 150).collect();
+        assert_eq!(pending, vec![(3, 300)]);
+
+        let pending: Vec<_> = open_proposals.pending(11, 150).collect();
+        assert_eq!(pending, vec![(2, 200), (3, 300)]);
+    }
+
+    #[test]
+    fn update_drops_votes_on_closed_proposals() {
+        let mut open_proposals = OpenNnsProposals::default();
+        open_proposals.update(BTreeMap::from([(1, 100), (2, 200)]), 0);
+        open_proposals.record_votes(10, [1, 2].into_iter());
+
+        let newly_opened = open_proposals.update(BTreeMap::from([(2, 200), (3, 300)]), 50);
+        assert_eq!(newly_opened, 1);
+        assert_eq!(
+            open_proposals.votes_seen.get(&10),
+            Some(&BTreeSet::from([2]))
+        );
+        assert_eq!(open_proposals.proposal_ids(), Some(BTreeSet::from([2, 3])));
+    }
+}
@@ -0,0 +1,7 @@
+use crate::{state, Alert};
+use ic_cdk::query;
+
+#[query]
+fn alerts() -> Vec<Alert> {
+    state::read(|s| s.alerts())
+}
@@ -0,0 +1,7 @@
+use crate::{state, Metrics};
+use ic_cdk::query;
+
+#[query]
+fn metrics() -> Metrics {
+    state::read(|s| s.metrics())
+}
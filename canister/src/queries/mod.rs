@@ -1,3 +1,8 @@
+mod alerts;
 mod list_neuron_pairs;
 mod logs;
+mod metrics;
+mod neuron_pair_history;
+mod query_neuron_pairs;
 mod votes_to_process;
+mod wtn_proposal_mappings;
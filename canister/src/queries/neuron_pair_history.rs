@@ -0,0 +1,12 @@
+use crate::{state, NeuronPairHistoryArgs, PairEvent};
+use ic_cdk::query;
+
+#[query]
+fn neuron_pair_history(args: NeuronPairHistoryArgs) -> Vec<PairEvent> {
+    state::read(|s| {
+        s.neuron_pairs()
+            .get(&args.pair_id)
+            .map(|p| p.history().iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
@@ -0,0 +1,29 @@
+use crate::state::NeuronPairsFilter;
+use crate::{state, AdminFilter, QueryNeuronPairsArgs, QueryNeuronPairsResponse};
+use ic_cdk::query;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[query]
+fn query_neuron_pairs(args: QueryNeuronPairsArgs) -> QueryNeuronPairsResponse {
+    let filter = NeuronPairsFilter {
+        admin: args.admin.map(|a| match a {
+            AdminFilter::Caller => ic_cdk::caller(),
+            AdminFilter::Principal(p) => p,
+        }),
+        nns_neuron_id: args.nns_neuron_id,
+        wtn_neuron_id: args.wtn_neuron_id,
+        state: args.state,
+        name_contains: args.name_contains,
+    };
+    let limit = args.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize;
+
+    state::read(|s| {
+        let (pairs, next_cursor) = s.query_neuron_pairs(&filter, args.start_after, limit);
+        QueryNeuronPairsResponse {
+            pairs: pairs.into_iter().map(|p| p.into()).collect(),
+            next_cursor,
+        }
+    })
+}
@@ -0,0 +1,12 @@
+use crate::guards::caller_is_controller;
+use crate::{state, WtnProposalMappingEntry, WtnProposalMappingsArgs};
+use ic_cdk::query;
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 500;
+
+#[query(guard = "caller_is_controller")]
+fn wtn_proposal_mappings(args: WtnProposalMappingsArgs) -> Vec<WtnProposalMappingEntry> {
+    let limit = args.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    state::read(|s| s.wtn_proposal_mappings(args.start_after, limit as usize))
+}
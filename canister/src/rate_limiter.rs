@@ -0,0 +1,66 @@
+use ic_principal::Principal;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+const WINDOW: Duration = Duration::from_secs(10 * 60); // 10 minutes
+const MAX_CALLS_PER_WINDOW: usize = 10;
+
+// Limits how often each principal can call the endpoints which make inter-canister calls at the
+// relay's expense. This isn't persisted, so the limits are reset by upgrades.
+#[derive(Default)]
+pub struct RateLimiter {
+    calls: BTreeMap<Principal, VecDeque<u64>>,
+}
+
+impl RateLimiter {
+    pub fn is_limited(&self, principal: Principal, now: u64) -> bool {
+        self.calls.get(&principal).is_some_and(|calls| {
+            calls.iter().filter(|t| !Self::is_expired(**t, now)).count() >= MAX_CALLS_PER_WINDOW
+        })
+    }
+
+    // Records a call by the principal, returning false if it exceeds the limit, in which case
+    // the call isn't recorded
+    pub fn try_record(&mut self, principal: Principal, now: u64) -> bool {
+        for calls in self.calls.values_mut() {
+            while calls.front().is_some_and(|t| Self::is_expired(*t, now)) {
+                calls.pop_front();
+            }
+        }
+        self.calls.retain(|_, calls| !calls.is_empty());
+
+        let calls = self.calls.entry(principal).or_default();
+        if calls.len() >= MAX_CALLS_PER_WINDOW {
+            return false;
+        }
+        calls.push_back(now);
+        true
+    }
+
+    fn is_expired(timestamp: u64, now: u64) -> bool {
+        now >= timestamp + WINDOW.as_nanos() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_are_limited_per_principal_within_the_window() {
+        let principal1 = Principal::from_slice(&[1]);
+        let principal2 = Principal::from_slice(&[2]);
+        let mut rate_limiter = RateLimiter::default();
+
+        for now in 0..MAX_CALLS_PER_WINDOW as u64 {
+            assert!(rate_limiter.try_record(principal1, now));
+        }
+        assert!(rate_limiter.is_limited(principal1, 100));
+        assert!(!rate_limiter.try_record(principal1, 100));
+        assert!(rate_limiter.try_record(principal2, 100));
+
+        let window_end = WINDOW.as_nanos() as u64;
+        assert!(!rate_limiter.is_limited(principal1, window_end));
+        assert!(rate_limiter.try_record(principal1, window_end));
+    }
+}
@@ -0,0 +1,159 @@
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::rc::Rc;
+
+// Coalesces concurrent calls made for the same key, so that while a call is in flight, any other
+// callers with that key wait for its result rather than making a call of their own. Once the call
+// completes the key is released, so the next caller makes a fresh call.
+pub struct SingleFlight<K, V: Clone> {
+    in_flight: Rc<RefCell<BTreeMap<K, Shared<LocalBoxFuture<'static, V>>>>>,
+}
+
+impl<K, V: Clone> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        SingleFlight {
+            in_flight: Rc::default(),
+        }
+    }
+}
+
+impl<K, V: Clone> Clone for SingleFlight<K, V> {
+    fn clone(&self) -> Self {
+        SingleFlight {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone + 'static> SingleFlight<K, V> {
+    pub async fn run<F: Future<Output = V> + 'static>(
+        &self,
+        key: K,
+        call: impl FnOnce() -> F,
+    ) -> V {
+        let shared = self
+            .in_flight
+            .borrow_mut()
+            .entry(key.clone())
+            .or_insert_with(|| call().boxed_local().shared())
+            .clone();
+
+        // Released once the call completes, or if the waiter is dropped, eg. when cleaning up
+        // after a trap, so that the key is never left waiting on a call which won't complete
+        let _release = Release {
+            in_flight: &self.in_flight,
+            key,
+            shared: shared.clone(),
+        };
+        shared.await
+    }
+}
+
+struct Release<'a, K: Ord, V: Clone> {
+    in_flight: &'a RefCell<BTreeMap<K, Shared<LocalBoxFuture<'static, V>>>>,
+    key: K,
+    shared: Shared<LocalBoxFuture<'static, V>>,
+}
+
+impl<K: Ord, V: Clone> Drop for Release<'_, K, V> {
+    fn drop(&mut self) {
+        // Only the first of the waiters to complete releases the key, a later waiter must not
+        // release a newer call made for the same key
+        let mut in_flight = self.in_flight.borrow_mut();
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|f| f.ptr_eq(&self.shared))
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+
+    #[test]
+    fn concurrent_calls_with_the_same_key_share_one_call() {
+        let single_flight: SingleFlight<u64, Result<u64, String>> = SingleFlight::default();
+        let calls = Cell::new(0);
+        let (sender, receiver) = oneshot::channel();
+        let receiver = Rc::new(RefCell::new(Some(receiver)));
+
+        let call = || {
+            calls.set(calls.get() + 1);
+            let receiver = receiver.borrow_mut().take().unwrap();
+            async move { receiver.await.unwrap() }
+        };
+        let (first, second, ()) = block_on(async {
+            futures::join!(
+                single_flight.run(1, call),
+                single_flight.run(1, call),
+                async { sender.send(Ok(10)).unwrap() }
+            )
+        });
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(first, Ok(10));
+        assert_eq!(second, Ok(10));
+        assert!(single_flight.in_flight.borrow().is_empty());
+    }
+
+    #[test]
+    fn errors_are_returned_to_every_waiter_and_the_next_caller_calls_again() {
+        let single_flight: SingleFlight<u64, Result<u64, String>> = SingleFlight::default();
+        let calls = Cell::new(0);
+        let (sender, receiver) = oneshot::channel();
+        let receiver = Rc::new(RefCell::new(Some(receiver)));
+
+        let call = || {
+            calls.set(calls.get() + 1);
+            let receiver = receiver.borrow_mut().take().unwrap();
+            async move { receiver.await.unwrap() }
+        };
+        let (first, second, ()) = block_on(async {
+            futures::join!(
+                single_flight.run(1, call),
+                single_flight.run(1, call),
+                async { sender.send(Err("Unavailable".to_string())).unwrap() }
+            )
+        });
+
+        let error = Err("Unavailable".to_string());
+        assert_eq!(calls.get(), 1);
+        assert_eq!(first, error);
+        assert_eq!(second, error);
+
+        let result = block_on(single_flight.run(1, || {
+            calls.set(calls.get() + 1);
+            async { Ok(11) }
+        }));
+        assert_eq!(calls.get(), 2);
+        assert_eq!(result, Ok(11));
+    }
+
+    #[test]
+    fn calls_with_different_keys_are_not_shared() {
+        let single_flight: SingleFlight<u64, u64> = SingleFlight::default();
+        let calls = Cell::new(0);
+        let call = |value| {
+            calls.set(calls.get() + 1);
+            async move { value }
+        };
+
+        let results = block_on(async {
+            futures::join!(
+                single_flight.run(1, || call(10)),
+                single_flight.run(2, || call(20))
+            )
+        });
+
+        assert_eq!(calls.get(), 2);
+        assert_eq!(results, (10, 20));
+    }
+}
@@ -1,11 +1,27 @@
+use crate::idempotency_keys::IdempotencyKeys;
 use crate::logs::log;
-use crate::neuron_pair::NeuronPair;
-use crate::{InitArgs, NnsVote, VoteToProcess, WtnVote};
+use crate::neuron_pair::{NeuronPair, NewNeuronPair};
+use crate::neuron_pair_index::NeuronPairIndex;
+use crate::nns_ballot_gaps::{BallotLookupOutcome, BallotPollOutcome, NnsBallotGaps};
+use crate::open_nns_proposals::OpenNnsProposals;
+use crate::rate_limiter::RateLimiter;
+use crate::vote_leases::VoteLeases;
+use crate::vote_retries::{VoteRetries, MAX_VOTE_ATTEMPTS};
+use crate::wtn_governance::WtnProposal;
+use crate::wtn_proposal_mappings::{WtnProposalMappings, WtnProposalMappingsConfig};
+use crate::{
+    Alert, AlertKind, DeregisterNeuronPairError, DeregisterNeuronPairsArgs, InitArgs, Metrics,
+    NeuronPairState, NnsVote, PairEvent, PairEventKind, RegisterNeuronPairError, SnsError,
+    SnsErrorCount, SnsErrorKind, UpgradeArgs, VoteCoverage, VoteToProcess, WtnProposalMapping,
+    WtnProposalMappingEntry, WtnProposalMappingSource, WtnProposalMappingStatus, WtnVote,
+};
 use ic_principal::Principal;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::btree_map::Entry::{Occupied, Vacant};
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::Duration;
 
 const DEFAULT_NNS_GOVERNANCE_CANISTER_ID: Principal =
     Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
@@ -14,6 +30,15 @@ const DEFAULT_WTN_GOVERNANCE_CANISTER_ID: Principal =
 const DEFAULT_WTN_PROTOCOL_CANISTER_ID: Principal =
     Principal::from_slice(&[0, 0, 0, 0, 2, 48, 1, 106, 1, 1]);
 
+// The number of NNS neurons polled concurrently by `check_for_new_nns_votes`
+const DEFAULT_NNS_POLLING_BATCH_SIZE: u32 = 20;
+const CACHED_WTN_PROPOSALS_LIMIT: usize = 500;
+const ALERTS_LIMIT: usize = 1000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+pub const REGISTRATIONS_LIMIT: u32 = 100;
+pub const MAX_NEURON_PAIRS_PER_PRINCIPAL: u32 = 10;
+
 thread_local! {
     static STATE: RefCell<Option<State>> = RefCell::default();
 }
@@ -23,10 +48,88 @@ pub struct State {
     nns_governance_canister_id: Principal,
     wtn_governance_canister_id: Principal,
     wtn_protocol_canister_id: Principal,
+    // The id of the generic nervous system function used by the WTN protocol canister to create
+    // its "Vote for NNS Proposals" proposals. Unless set by the init or upgrade args, this is
+    // looked up from WTN governance's nervous system functions.
+    #[serde(default)]
+    wtn_vote_for_nns_proposals_function_id: Option<u64>,
+    #[serde(default = "default_nns_polling_batch_size")]
+    nns_polling_batch_size: u32,
     neuron_pairs: BTreeMap<u64, NeuronPair>,
+    #[serde(skip)]
+    neuron_pair_index: NeuronPairIndex,
     votes_to_process: VecDeque<VoteToProcess>,
     #[serde(default)]
+    vote_leases: VoteLeases,
+    #[serde(default)]
+    vote_retries: VoteRetries,
+    // Stored in stable memory rather than being serialized on upgrade
+    #[serde(skip)]
+    wtn_proposal_mappings: WtnProposalMappings,
+    // The protocol canister's mappings as cached by earlier versions, which are only read so that
+    // they can be migrated into `wtn_proposal_mappings` on upgrade
+    #[serde(default, skip_serializing)]
     cached_wtn_proposals_per_nns_proposal: BTreeMap<u64, Option<u64>>,
+    #[serde(default)]
+    wtn_proposal_mappings_config: WtnProposalMappingsConfig,
+    #[serde(default)]
+    cached_wtn_proposals: BTreeMap<u64, WtnProposal>,
+    #[serde(default)]
+    alerts: VecDeque<Alert>,
+    #[serde(default)]
+    nns_ballot_gaps: NnsBallotGaps,
+    #[serde(default)]
+    nns_polling_stats: NnsPollingStats,
+    #[serde(default)]
+    open_nns_proposals: OpenNnsProposals,
+    // The votes seen in the latest poll of each NNS neuron, keyed by proposal id, which are read
+    // when reconciling and when relaying to watched WTN proposals rather than polling again. These
+    // are repopulated by the first full poll after an upgrade.
+    #[serde(skip)]
+    latest_nns_votes: BTreeMap<u64, BTreeMap<u64, bool>>,
+    #[serde(default)]
+    watched_wtn_proposals: BTreeMap<u64, WatchedWtnProposal>,
+    #[serde(default)]
+    sns_error_counts: BTreeMap<SnsErrorKind, u64>,
+    // While paused, registering and deregistering neuron pairs is rejected
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    idempotency_keys: IdempotencyKeys,
+    #[serde(skip)]
+    rate_limiter: RateLimiter,
+}
+
+// An open WTN "Vote for NNS Proposals" proposal, along with the pairs whose votes on it have
+// already been queued. Until the protocol canister confirms the mapping, `nns_proposal_id` is only
+// the NNS proposal which the WTN proposal appears to mirror.
+#[derive(Serialize, Deserialize)]
+struct WatchedWtnProposal {
+    nns_proposal_id: u64,
+    deadline_timestamp_seconds: u64,
+    pairs_queued: BTreeSet<u64>,
+    #[serde(default)]
+    status: WatchedWtnProposalStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum WatchedWtnProposalStatus {
+    #[default]
+    Unconfirmed,
+    Confirmed,
+    // The protocol canister mapped the NNS proposal to a different WTN proposal, or to none
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct NnsPollingStats {
+    neuron_info_calls: u64,
+    // The number of calls avoided by polling each NNS neuron once rather than once per pair
+    neuron_info_calls_saved: u64,
+}
+
+fn default_nns_polling_batch_size() -> u32 {
+    DEFAULT_NNS_POLLING_BATCH_SIZE
 }
 
 const STATE_ALREADY_INITIALIZED: &str = "State has already been initialized";
@@ -56,7 +159,7 @@ pub fn take() -> State {
 
 impl State {
     pub fn new(args: InitArgs) -> State {
-        State {
+        let mut state = State {
             nns_governance_canister_id: args
                 .nns_governance_canister_id
                 .unwrap_or(DEFAULT_NNS_GOVERNANCE_CANISTER_ID),
@@ -66,10 +169,42 @@ impl State {
             wtn_protocol_canister_id: args
                 .wtn_protocol_canister_id
                 .unwrap_or(DEFAULT_WTN_PROTOCOL_CANISTER_ID),
+            wtn_vote_for_nns_proposals_function_id: args.wtn_vote_for_nns_proposals_function_id,
+            nns_polling_batch_size: args
+                .nns_polling_batch_size
+                .unwrap_or(DEFAULT_NNS_POLLING_BATCH_SIZE)
+                .max(1),
             neuron_pairs: BTreeMap::new(),
+            neuron_pair_index: NeuronPairIndex::default(),
             votes_to_process: VecDeque::new(),
+            vote_leases: VoteLeases::default(),
+            vote_retries: VoteRetries::default(),
+            wtn_proposal_mappings: WtnProposalMappings::default(),
             cached_wtn_proposals_per_nns_proposal: BTreeMap::new(),
-        }
+            wtn_proposal_mappings_config: WtnProposalMappingsConfig::default(),
+            cached_wtn_proposals: BTreeMap::new(),
+            alerts: VecDeque::new(),
+            nns_ballot_gaps: NnsBallotGaps::default(),
+            nns_polling_stats: NnsPollingStats::default(),
+            open_nns_proposals: OpenNnsProposals::default(),
+            latest_nns_votes: BTreeMap::new(),
+            watched_wtn_proposals: BTreeMap::new(),
+            sns_error_counts: BTreeMap::new(),
+            paused: false,
+            idempotency_keys: IdempotencyKeys::default(),
+            rate_limiter: RateLimiter::default(),
+        };
+        state.configure_wtn_proposal_mappings(
+            args.wtn_proposal_mappings_max_entries,
+            args.wtn_proposal_mappings_positive_ttl_seconds,
+            args.wtn_proposal_mappings_negative_ttl_seconds,
+        );
+        state
+    }
+
+    // Rebuilds the data which is derived from the rest of the state and so isn't persisted
+    pub fn rebuild_indexes(&mut self) {
+        self.neuron_pair_index = NeuronPairIndex::build(self.neuron_pairs.values());
     }
 
     pub fn nns_governance_canister_id(&self) -> Principal {
@@ -84,66 +219,963 @@ impl State {
         self.wtn_protocol_canister_id
     }
 
+    pub fn wtn_vote_for_nns_proposals_function_id(&self) -> Option<u64> {
+        self.wtn_vote_for_nns_proposals_function_id
+    }
+
+    pub fn set_wtn_vote_for_nns_proposals_function_id(&mut self, function_id: u64) {
+        log(format!(
+            "WTN \"Vote for NNS Proposals\" function id set: {function_id}"
+        ));
+        self.wtn_vote_for_nns_proposals_function_id = Some(function_id);
+    }
+
+    pub fn nns_polling_batch_size(&self) -> u32 {
+        self.nns_polling_batch_size
+    }
+
+    pub fn apply_upgrade_args(&mut self, args: UpgradeArgs) {
+        if let Some(function_id) = args.wtn_vote_for_nns_proposals_function_id {
+            self.wtn_vote_for_nns_proposals_function_id = Some(function_id);
+        }
+        if let Some(batch_size) = args.nns_polling_batch_size {
+            self.nns_polling_batch_size = batch_size.max(1);
+        }
+        self.configure_wtn_proposal_mappings(
+            args.wtn_proposal_mappings_max_entries,
+            args.wtn_proposal_mappings_positive_ttl_seconds,
+            args.wtn_proposal_mappings_negative_ttl_seconds,
+        );
+    }
+
+    fn configure_wtn_proposal_mappings(
+        &mut self,
+        max_entries: Option<u32>,
+        positive_ttl_seconds: Option<u64>,
+        negative_ttl_seconds: Option<u64>,
+    ) {
+        let config = &mut self.wtn_proposal_mappings_config;
+        if let Some(max_entries) = max_entries {
+            config.max_entries = max_entries.max(1);
+        }
+        if let Some(seconds) = positive_ttl_seconds {
+            config.positive_ttl = Duration::from_secs(seconds);
+        }
+        if let Some(seconds) = negative_ttl_seconds {
+            config.negative_ttl = Duration::from_secs(seconds);
+        }
+    }
+
     pub fn register_neuron_pair(
         &mut self,
         caller: Principal,
-        name: String,
-        nns_neuron_id: u64,
-        wtn_neuron_id: [u8; 32],
-    ) -> Option<u64> {
-        let pair = NeuronPair::new(name, caller, nns_neuron_id, wtn_neuron_id);
+        args: NewNeuronPair,
+        now: u64,
+    ) -> Result<u64, RegisterNeuronPairError> {
+        if self.paused {
+            return Err(RegisterNeuronPairError::Paused);
+        }
+        if self.neuron_pairs.len() >= REGISTRATIONS_LIMIT as usize {
+            return Err(RegisterNeuronPairError::RegistrationLimitExceeded(
+                REGISTRATIONS_LIMIT,
+            ));
+        }
+        if self.neuron_pair_count_for_admin(caller) >= MAX_NEURON_PAIRS_PER_PRINCIPAL as usize {
+            return Err(RegisterNeuronPairError::PairQuotaExceeded(
+                MAX_NEURON_PAIRS_PER_PRINCIPAL,
+            ));
+        }
+
+        let pair = NeuronPair::new(caller, args, now);
         let id = pair.id();
         match self.neuron_pairs.entry(id) {
             Vacant(e) => {
-                e.insert(pair);
-                Some(id)
+                self.neuron_pair_index.add(e.insert(pair));
+                Ok(id)
             }
-            _ => None,
+            _ => Err(RegisterNeuronPairError::AlreadyRegistered),
         }
     }
 
-    pub fn deregister_neuron_pair(&mut self, caller: Principal, pair_id: u64) -> bool {
+    // The number of further pairs which the caller can register, along with the error returned for
+    // any beyond that, depending on whether the global limit or the caller's quota is reached first
+    pub fn remaining_registration_slots(
+        &self,
+        caller: Principal,
+    ) -> (usize, RegisterNeuronPairError) {
+        let remaining_global =
+            (REGISTRATIONS_LIMIT as usize).saturating_sub(self.neuron_pairs.len());
+        let remaining_quota = (MAX_NEURON_PAIRS_PER_PRINCIPAL as usize)
+            .saturating_sub(self.neuron_pair_count_for_admin(caller));
+        if remaining_quota < remaining_global {
+            (
+                remaining_quota,
+                RegisterNeuronPairError::PairQuotaExceeded(MAX_NEURON_PAIRS_PER_PRINCIPAL),
+            )
+        } else {
+            (
+                remaining_global,
+                RegisterNeuronPairError::RegistrationLimitExceeded(REGISTRATIONS_LIMIT),
+            )
+        }
+    }
+
+    pub fn neuron_pair_count_for_admin(&self, admin: Principal) -> usize {
+        self.neuron_pair_index
+            .by_admin(admin)
+            .map_or(0, |ids| ids.len())
+    }
+
+    pub fn is_rate_limited(&self, caller: Principal, now: u64) -> bool {
+        self.rate_limiter.is_limited(caller, now)
+    }
+
+    // Returns false if the caller has exceeded their rate limit
+    pub fn try_record_rate_limited_call(&mut self, caller: Principal, now: u64) -> bool {
+        self.rate_limiter.try_record(caller, now)
+    }
+
+    pub fn registration_outcome(
+        &self,
+        caller: Principal,
+        idempotency_key: &str,
+        now: u64,
+    ) -> Option<Result<u64, RegisterNeuronPairError>> {
+        self.idempotency_keys.get(caller, idempotency_key, now)
+    }
+
+    // Records the outcome of a registration made with an idempotency key. If a concurrent call
+    // with the same key has already recorded its outcome, that outcome is returned instead. Only
+    // successes and errors which are deterministic for the given args are recorded, so a client
+    // retrying after a transient failure gets its registration retried rather than replayed.
+    pub fn record_registration_outcome(
+        &mut self,
+        caller: Principal,
+        idempotency_key: String,
+        result: Result<u64, RegisterNeuronPairError>,
+        now: u64,
+    ) -> Result<u64, RegisterNeuronPairError> {
+        if let Some(previous) = self.idempotency_keys.get(caller, &idempotency_key, now) {
+            return previous;
+        }
+        if Self::is_replayable_registration_outcome(&result) {
+            self.idempotency_keys
+                .insert(caller, idempotency_key, result.clone(), now);
+        }
+        result
+    }
+
+    // `NotPermittedToVote` isn't replayed, since the caller is expected to fix it by adding the
+    // relay as a hotkey and then retry
+    fn is_replayable_registration_outcome(result: &Result<u64, RegisterNeuronPairError>) -> bool {
+        matches!(
+            result,
+            Ok(_)
+                | Err(RegisterNeuronPairError::AlreadyRegistered
+                    | RegisterNeuronPairError::KnownNeuronNotFound(_)
+                    | RegisterNeuronPairError::InvalidArgument(_))
+        )
+    }
+
+    pub fn deregister_neuron_pair(
+        &mut self,
+        caller: Principal,
+        pair_id: u64,
+    ) -> Result<(), DeregisterNeuronPairError> {
         match self.neuron_pairs.entry(pair_id) {
-            Occupied(e) => {
-                if e.get().admin() == caller {
-                    e.remove();
-                    self.votes_to_process.retain(|v| v.pair_id() != pair_id);
-                    true
-                } else {
-                    false
-                }
+            Occupied(e) if e.get().admin() == caller => {
+                self.neuron_pair_index.remove(&e.remove());
+                self.votes_to_process.retain(|v| v.pair_id() != pair_id);
+                self.vote_leases.remove_pair(pair_id);
+                self.vote_retries.remove_pair(pair_id);
+                Ok(())
             }
-            _ => false,
+            Occupied(_) => Err(DeregisterNeuronPairError::NotAuthorized),
+            Vacant(_) => Err(DeregisterNeuronPairError::NotFound),
         }
     }
 
+    pub fn deregister_neuron_pairs(
+        &mut self,
+        caller: Principal,
+        filter: &DeregisterNeuronPairsArgs,
+    ) -> Vec<u64> {
+        if filter.nns_neuron_id.is_none() && filter.wtn_neuron_id.is_none() && filter.name.is_none()
+        {
+            return Vec::new();
+        }
+
+        let pair_ids: Vec<_> = self
+            .neuron_pair_index
+            .by_admin(caller)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.neuron_pairs.get(id))
+            .filter(|p| !matches!(filter.nns_neuron_id, Some(id) if p.nns_neuron_id() != id))
+            .filter(|p| !matches!(filter.wtn_neuron_id, Some(id) if p.wtn_neuron_id() != id))
+            .filter(|p| !matches!(&filter.name, Some(n) if p.name() != n))
+            .map(|p| p.id())
+            .collect();
+
+        for pair_id in pair_ids.iter() {
+            let _ = self.deregister_neuron_pair(caller, *pair_id);
+        }
+        pair_ids
+    }
+
     pub fn neuron_pairs(&self) -> &BTreeMap<u64, NeuronPair> {
         &self.neuron_pairs
     }
 
-    pub fn record_nns_vote(&mut self, pair_id: u64, vote: NnsVote) {
-        if let Some(pair) = self.neuron_pairs.get_mut(&pair_id) {
-            if pair.is_newly_seen_nns_vote(vote.proposal_id) {
-                self.push_vote_to_process(VoteToProcess::NnsVote(pair_id, vote));
+    // Returns the pairs matching the filter, ordered by pair id and starting after `start_after`,
+    // along with the cursor to use to retrieve the next page if there are more results
+    pub fn query_neuron_pairs(
+        &self,
+        filter: &NeuronPairsFilter,
+        start_after: Option<u64>,
+        limit: usize,
+    ) -> (Vec<&NeuronPair>, Option<u64>) {
+        let lower_bound = start_after.map_or(Unbounded, Excluded);
+
+        // Use the most selective of the indexes available for the filter
+        let indexed: Vec<&BTreeSet<u64>> = [
+            filter.admin.map(|a| self.neuron_pair_index.by_admin(a)),
+            filter
+                .nns_neuron_id
+                .map(|id| self.neuron_pair_index.by_nns_neuron(id)),
+            filter
+                .wtn_neuron_id
+                .map(|id| self.neuron_pair_index.by_wtn_neuron(id)),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|ids| ids.unwrap_or(&EMPTY_PAIR_IDS))
+        .collect();
+
+        let candidates: Box<dyn Iterator<Item = &NeuronPair>> =
+            match indexed.into_iter().min_by_key(|ids| ids.len()) {
+                Some(ids) => Box::new(
+                    ids.range((lower_bound, Unbounded))
+                        .filter_map(|id| self.neuron_pairs.get(id)),
+                ),
+                None => Box::new(
+                    self.neuron_pairs
+                        .range((lower_bound, Unbounded))
+                        .map(|(_, p)| p),
+                ),
+            };
+
+        let mut pairs: Vec<_> = candidates
+            .filter(|p| filter.matches(p))
+            .take(limit + 1)
+            .collect();
+
+        let next_cursor = if pairs.len() > limit {
+            pairs.truncate(limit);
+            pairs.last().map(|p| p.id())
+        } else {
+            None
+        };
+        (pairs, next_cursor)
+    }
+
+    pub fn nns_neuron_ids(&self) -> Vec<u64> {
+        self.neuron_pair_index.nns_neuron_ids().collect()
+    }
+
+    pub fn has_known_neuron_pairs(&self) -> bool {
+        self.neuron_pairs
+            .values()
+            .any(|p| p.nns_known_neuron_name().is_some())
+    }
+
+    pub fn update_known_neuron_flags(&mut self, known_neurons: &BTreeMap<u64, String>) {
+        for pair in self.neuron_pairs.values_mut() {
+            let current_name = known_neurons.get(&pair.nns_neuron_id()).map(|n| n.as_str());
+            if pair.update_known_neuron_flag(current_name) {
+                log(format!(
+                    "Known neuron changed for pair. PairId: {}. NnsNeuronId: {}. Current name: {current_name:?}",
+                    pair.id(),
+                    pair.nns_neuron_id()
+                ));
             }
         }
     }
 
-    pub fn record_wtn_vote_registered(&mut self, pair_id: u64, vote: WtnVote) {
+    // Records the NNS neuron's ballots for the pair. The first time a pair is polled, its latest
+    // ballot is taken as the registration watermark, and earlier ballots are only relayed for
+    // proposals which are still open on WTN, or still open on the NNS if the pair opted in to
+    // backfilling.
+    pub fn record_nns_votes(
+        &mut self,
+        pair_id: u64,
+        votes: Vec<NnsVote>,
+        open_nns_proposals: &BTreeSet<u64>,
+        now: u64,
+    ) {
+        let Some(first_poll) = self
+            .neuron_pairs
+            .get(&pair_id)
+            .map(|p| p.nns_ballot_watermark().is_none())
+        else {
+            return;
+        };
+
+        let open_on_wtn = if first_poll {
+            votes
+                .iter()
+                .map(|v| v.proposal_id)
+                .filter(|id| self.is_nns_proposal_open_on_wtn(*id, now))
+                .collect()
+        } else {
+            BTreeSet::new()
+        };
+
+        let Some(pair) = self.neuron_pairs.get_mut(&pair_id) else {
+            return;
+        };
+        let votes = pair.take_nns_votes_to_relay(votes, &open_on_wtn, open_nns_proposals);
+
+        if first_poll {
+            log(format!(
+                "NNS ballot watermark set. PairId: {pair_id}. Watermark: {}. Relaying: {}",
+                pair.nns_ballot_watermark().unwrap_or_default(),
+                votes.len()
+            ));
+        }
+
+        for vote in votes {
+            self.push_vote_to_process(VoteToProcess::NnsVote(pair_id, vote));
+        }
+    }
+
+    // Whether the WTN proposal mirroring the NNS proposal is known to still be accepting votes
+    fn is_nns_proposal_open_on_wtn(&self, nns_proposal_id: u64, now: u64) -> bool {
+        let now_seconds = now / NANOS_PER_SECOND;
+        let cached = self
+            .get_cached_wtn_proposal_for_nns_proposal(nns_proposal_id, now)
+            .flatten()
+            .and_then(|id| self.cached_wtn_proposals.get(&id))
+            .is_some_and(|p| p.is_open(now_seconds));
+
+        cached
+            || self.watched_wtn_proposals.values().any(|p| {
+                p.status == WatchedWtnProposalStatus::Confirmed
+                    && p.nns_proposal_id == nns_proposal_id
+                    && now_seconds < p.deadline_timestamp_seconds
+            })
+    }
+
+    // Compares the proposal ids of the NNS neuron's recent ballots with those seen previously, so
+    // that any ballots which fell out of the neuron's recent ballots before being seen can be
+    // looked up. Those which show up again are relayed along with the rest of the poll's ballots.
+    pub fn record_nns_ballot_poll(&mut self, nns_neuron_id: u64, ballot_proposal_ids: &[u64]) {
+        let outcome = self
+            .nns_ballot_gaps
+            .record_poll(nns_neuron_id, ballot_proposal_ids);
+
+        if outcome != BallotPollOutcome::default() {
+            log(format!(
+                "NNS ballot gaps updated. NnsNeuronId: {nns_neuron_id}. {outcome:?}"
+            ));
+        }
+    }
+
+    // Returns the NNS proposals whose ballots need to be looked up, along with the NNS neurons
+    // whose ballots are missing for each of them
+    pub fn next_nns_proposals_to_recover(&self, limit: usize) -> BTreeMap<u64, Vec<u64>> {
+        self.nns_ballot_gaps.next_proposals_to_recover(limit)
+    }
+
+    // Records the NNS neuron's vote found by looking up a missing proposal, queueing it for each
+    // pair following the neuron if the proposal is still open, skipping pairs whose watermark shows
+    // they were registered afterwards
+    pub fn record_nns_ballot_lookup(
+        &mut self,
+        nns_neuron_id: u64,
+        proposal_id: u64,
+        vote: Option<NnsVote>,
+        is_open: bool,
+    ) -> BallotLookupOutcome {
+        let outcome = match vote {
+            Some(_) if is_open => BallotLookupOutcome::Recovered,
+            Some(_) => BallotLookupOutcome::Unrecoverable,
+            None => BallotLookupOutcome::NoVote,
+        };
+        self.nns_ballot_gaps
+            .record_lookup(nns_neuron_id, proposal_id, &outcome);
+
+        let Some(vote) = vote.filter(|_| is_open) else {
+            return outcome;
+        };
+
+        let pair_ids: Vec<_> = self
+            .neuron_pair_index
+            .by_nns_neuron(nns_neuron_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.neuron_pairs.get(id))
+            .filter(|p| p.nns_ballot_watermark().is_some_and(|w| w < proposal_id))
+            .map(|p| p.id())
+            .collect();
+
+        for pair_id in pair_ids {
+            let Some(pair) = self.neuron_pairs.get_mut(&pair_id) else {
+                continue;
+            };
+            if pair.is_newly_seen_nns_vote(proposal_id) {
+                self.push_vote_to_process(VoteToProcess::NnsVote(pair_id, vote.clone()));
+            }
+        }
+        outcome
+    }
+
+    // Stops tracking the ballot gaps and votes of NNS neurons which are no longer followed by any
+    // pair
+    pub fn prune_unfollowed_nns_neurons(&mut self) {
+        let nns_neuron_ids: BTreeSet<_> = self.neuron_pair_index.nns_neuron_ids().collect();
+        self.nns_ballot_gaps.retain_neurons(&nns_neuron_ids);
+        self.latest_nns_votes
+            .retain(|id, _| nns_neuron_ids.contains(id));
+    }
+
+    pub fn update_open_nns_proposals(&mut self, deadlines: BTreeMap<u64, u64>, now: u64) -> usize {
+        self.open_nns_proposals.update(deadlines, now)
+    }
+
+    // Returns None if the open NNS proposals haven't yet been retrieved
+    pub fn open_nns_proposal_ids(&self) -> Option<BTreeSet<u64>> {
+        self.open_nns_proposals.proposal_ids()
+    }
+
+    pub fn record_nns_neuron_votes(&mut self, nns_neuron_id: u64, votes: &[NnsVote]) {
+        self.open_nns_proposals
+            .record_votes(nns_neuron_id, votes.iter().map(|v| v.proposal_id));
+        self.latest_nns_votes.insert(
+            nns_neuron_id,
+            votes
+                .iter()
+                .filter_map(|v| Some((v.proposal_id, v.vote.adopt()?)))
+                .collect(),
+        );
+    }
+
+    // The votes seen in the latest poll of the NNS neuron, or None if it hasn't been polled since
+    // the last upgrade
+    pub fn latest_nns_votes(&self, nns_neuron_id: u64) -> Option<&BTreeMap<u64, bool>> {
+        self.latest_nns_votes.get(&nns_neuron_id)
+    }
+
+    // Determines which NNS neurons need polling, being those which have yet to vote on an open
+    // proposal plus those followed by pairs which are still waiting for their first poll. Also
+    // returns the earliest deadline of the pending proposals, using the deadline of the WTN
+    // proposal when it is known since that closes before the NNS proposal does.
+    pub fn nns_polling_plan(&self, now_seconds: u64) -> NnsPollingPlan {
+        let mut plan = NnsPollingPlan::default();
+
+        for nns_neuron_id in self.neuron_pair_index.nns_neuron_ids() {
+            let awaiting_first_poll = self
+                .neuron_pair_index
+                .by_nns_neuron(nns_neuron_id)
+                .into_iter()
+                .flatten()
+                .filter_map(|id| self.neuron_pairs.get(id))
+                .any(|p| p.nns_ballot_watermark().is_none());
+
+            let next_deadline = self
+                .open_nns_proposals
+                .pending(nns_neuron_id, now_seconds)
+                .map(|(proposal_id, deadline)| {
+                    self.get_cached_wtn_proposal_for_nns_proposal(
+                        proposal_id,
+                        now_seconds * NANOS_PER_SECOND,
+                    )
+                    .flatten()
+                    .and_then(|id| self.cached_wtn_proposals.get(&id))
+                    .map_or(deadline, |p| p.deadline_timestamp_seconds.min(deadline))
+                })
+                .min();
+
+            if awaiting_first_poll || next_deadline.is_some() {
+                plan.nns_neuron_ids.insert(nns_neuron_id);
+            }
+            plan.awaiting_first_poll |= awaiting_first_poll;
+            if let Some(deadline) = next_deadline {
+                plan.next_deadline_seconds = Some(
+                    plan.next_deadline_seconds
+                        .map_or(deadline, |d| d.min(deadline)),
+                );
+            }
+        }
+        plan
+    }
+
+    pub fn record_nns_neuron_polls(&mut self, calls: usize, calls_saved: usize) {
+        self.nns_polling_stats.neuron_info_calls += calls as u64;
+        self.nns_polling_stats.neuron_info_calls_saved += calls_saved as u64;
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            neuron_pairs: self.neuron_pairs.len() as u32,
+            distinct_nns_neurons: self.neuron_pair_index.nns_neuron_ids().count() as u32,
+            nns_neuron_info_calls: self.nns_polling_stats.neuron_info_calls,
+            nns_neuron_info_calls_saved: self.nns_polling_stats.neuron_info_calls_saved,
+            nns_ballots_to_recover: self.nns_ballots_to_recover_count() as u32,
+            nns_ballots_unrecoverable: self.nns_ballot_gaps.unrecoverable_count(),
+            votes_to_process: self.votes_to_process.len() as u32,
+            votes_in_flight: self.vote_leases.count() as u32,
+            votes_awaiting_retry: self.vote_retries.count() as u32,
+            open_nns_proposals: self.open_nns_proposals.count() as u32,
+            watched_wtn_proposals: self.watched_wtn_proposals.len() as u32,
+            wtn_proposal_mappings_from_protocol_canister: self
+                .wtn_proposal_mappings
+                .count_by_source(WtnProposalMappingSource::ProtocolCanister),
+            wtn_proposal_mappings_from_wtn_governance: self
+                .wtn_proposal_mappings
+                .count_by_source(WtnProposalMappingSource::WtnGovernance),
+            wtn_proposal_mappings_from_controller: self
+                .wtn_proposal_mappings
+                .count_by_source(WtnProposalMappingSource::Controller),
+            wtn_vote_for_nns_proposals_function_id: self.wtn_vote_for_nns_proposals_function_id,
+            paused: self.paused,
+            suspended_neuron_pairs: self
+                .neuron_pairs
+                .values()
+                .filter(|p| p.is_suspended())
+                .count() as u32,
+            sns_errors: self
+                .sns_error_counts
+                .iter()
+                .map(|(kind, count)| SnsErrorCount {
+                    kind: *kind,
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn nns_ballots_to_recover_count(&self) -> usize {
+        self.nns_ballot_gaps.proposals_to_recover_count()
+    }
+
+    pub fn record_wtn_vote_registered(&mut self, pair_id: u64, vote: WtnVote, now: u64) {
         if let Some(pair) = self.neuron_pairs.get_mut(&pair_id) {
             log(format!("WTN vote registered: {vote:?}. PairId: {pair_id}"));
+            pair.record_event(PairEvent {
+                timestamp: now,
+                nns_proposal_id: vote.nns_proposal_id,
+                wtn_proposal_id: Some(vote.wtn_proposal_id),
+                kind: PairEventKind::VoteRegistered { adopt: vote.adopt },
+            });
             pair.record_wtn_vote_registered(vote);
         }
     }
 
+    pub fn record_pair_event(&mut self, pair_id: u64, event: PairEvent) {
+        if let Some(pair) = self.neuron_pairs.get_mut(&pair_id) {
+            log(format!("Pair event: {event:?}. PairId: {pair_id}"));
+            pair.record_event(event);
+        }
+    }
+
+    pub fn record_vote_coverage(&mut self, pair_id: u64, coverage: VoteCoverage) {
+        if let Some(pair) = self.neuron_pairs.get_mut(&pair_id) {
+            log(format!("Vote coverage: {coverage:?}. PairId: {pair_id}"));
+            pair.set_coverage(coverage);
+        }
+    }
+
+    // Queues a vote which reconciliation found to be missing, unless it is already queued
+    // Whether the pair's vote on the NNS proposal is either queued or currently being cast, in which
+    // case queueing it again would only see it rejected as already cast
+    fn is_vote_in_progress(&self, pair_id: u64, nns_proposal_id: u64) -> bool {
+        self.votes_to_process
+            .iter()
+            .any(|v| v.pair_id() == pair_id && v.nns_proposal_id() == nns_proposal_id)
+            || self.vote_leases.is_leased(pair_id, nns_proposal_id)
+    }
+
+    pub fn requeue_missed_vote(&mut self, pair_id: u64, vote: WtnVote) -> bool {
+        if self.is_vote_in_progress(pair_id, vote.nns_proposal_id) {
+            return false;
+        }
+        let Some(pair) = self.neuron_pairs.get_mut(&pair_id) else {
+            return false;
+        };
+        // Mark the NNS vote as seen so that it isn't queued a second time by the NNS polling
+        pair.is_newly_seen_nns_vote(vote.nns_proposal_id);
+        self.push_vote_to_process(VoteToProcess::PendingWtnVote(pair_id, vote));
+        true
+    }
+
+    // Starts watching a newly seen WTN proposal, returning true if it wasn't already being watched.
+    // No mapping is recorded, since the NNS proposal is only a guess until the protocol canister
+    // confirms it.
+    pub fn watch_wtn_proposal(
+        &mut self,
+        wtn_proposal_id: u64,
+        nns_proposal_id: u64,
+        proposal: WtnProposal,
+    ) -> bool {
+        let deadline_timestamp_seconds = proposal.deadline_timestamp_seconds;
+        self.record_wtn_proposal(wtn_proposal_id, proposal);
+
+        match self.watched_wtn_proposals.entry(wtn_proposal_id) {
+            Occupied(mut e) => {
+                e.get_mut().deadline_timestamp_seconds = deadline_timestamp_seconds;
+                false
+            }
+            Vacant(e) => {
+                e.insert(WatchedWtnProposal {
+                    nns_proposal_id,
+                    deadline_timestamp_seconds,
+                    pairs_queued: BTreeSet::new(),
+                    status: WatchedWtnProposalStatus::Unconfirmed,
+                });
+                true
+            }
+        }
+    }
+
+    // The open watched WTN proposals which are yet to be confirmed by the protocol canister, along
+    // with the NNS proposal ids they appear to mirror
+    pub fn unconfirmed_watched_wtn_proposals(&self, now_seconds: u64) -> Vec<(u64, u64)> {
+        self.watched_wtn_proposals
+            .iter()
+            .filter(|(_, p)| p.status == WatchedWtnProposalStatus::Unconfirmed)
+            .filter(|(_, p)| now_seconds < p.deadline_timestamp_seconds)
+            .map(|(wtn_proposal_id, p)| (*wtn_proposal_id, p.nns_proposal_id))
+            .collect()
+    }
+
+    // Records the WTN proposal which the protocol canister maps the watched proposal's NNS proposal
+    // to, returning true if it is the watched proposal. Otherwise the watched proposal is never
+    // relayed to.
+    pub fn confirm_watched_wtn_proposal(
+        &mut self,
+        wtn_proposal_id: u64,
+        protocol_canister_wtn_proposal_id: Option<u64>,
+        now: u64,
+    ) -> bool {
+        let Some(watched) = self.watched_wtn_proposals.get_mut(&wtn_proposal_id) else {
+            return false;
+        };
+        let confirmed = protocol_canister_wtn_proposal_id == Some(wtn_proposal_id);
+        watched.status = if confirmed {
+            WatchedWtnProposalStatus::Confirmed
+        } else {
+            WatchedWtnProposalStatus::Rejected
+        };
+        let nns_proposal_id = watched.nns_proposal_id;
+        self.record_wtn_proposal_for_nns_proposal(
+            nns_proposal_id,
+            protocol_canister_wtn_proposal_id,
+            WtnProposalMappingSource::ProtocolCanister,
+            now,
+        );
+        confirmed
+    }
+
+    // Stops watching any proposals which have closed and returns the remaining confirmed WTN
+    // proposal ids along with the NNS proposal ids they mirror
+    pub fn watched_wtn_proposals(&mut self, now_seconds: u64) -> Vec<(u64, u64)> {
+        self.watched_wtn_proposals
+            .retain(|_, p| now_seconds < p.deadline_timestamp_seconds);
+
+        self.watched_wtn_proposals
+            .iter()
+            .filter(|(_, p)| p.status == WatchedWtnProposalStatus::Confirmed)
+            .map(|(wtn_proposal_id, p)| (*wtn_proposal_id, p.nns_proposal_id))
+            .collect()
+    }
+
+    // Queues the votes on the confirmed watched WTN proposals for every pair whose NNS neuron's
+    // latest poll shows it has voted on the underlying NNS proposal, returning the number of votes
+    // queued
+    pub fn record_nns_votes_for_watched_wtn_proposals(&mut self, now_seconds: u64) -> usize {
+        self.watched_wtn_proposals(now_seconds)
+            .into_iter()
+            .map(|(wtn_proposal_id, _)| self.record_nns_votes_for_wtn_proposal(wtn_proposal_id))
+            .sum()
+    }
+
+    fn record_nns_votes_for_wtn_proposal(&mut self, wtn_proposal_id: u64) -> usize {
+        let mut queued = 0;
+        for (pair_id, vote) in self.nns_votes_to_queue_for_wtn_proposal(wtn_proposal_id) {
+            if self.requeue_missed_vote(pair_id, vote) {
+                queued += 1;
+            }
+            if let Some(watched) = self.watched_wtn_proposals.get_mut(&wtn_proposal_id) {
+                watched.pairs_queued.insert(pair_id);
+            }
+        }
+        queued
+    }
+
+    // The votes to queue on a confirmed watched WTN proposal, given the votes seen by the latest
+    // polls of the NNS neurons on the underlying NNS proposal
+    fn nns_votes_to_queue_for_wtn_proposal(&self, wtn_proposal_id: u64) -> Vec<(u64, WtnVote)> {
+        let Some(watched) = self
+            .watched_wtn_proposals
+            .get(&wtn_proposal_id)
+            .filter(|p| p.status == WatchedWtnProposalStatus::Confirmed)
+        else {
+            return Vec::new();
+        };
+        let nns_proposal_id = watched.nns_proposal_id;
+
+        self.latest_nns_votes
+            .iter()
+            .filter_map(|(nns_neuron_id, votes)| {
+                Some((nns_neuron_id, votes.get(&nns_proposal_id)?))
+            })
+            .flat_map(|(nns_neuron_id, adopt)| {
+                self.neuron_pair_index
+                    .by_nns_neuron(*nns_neuron_id)
+                    .into_iter()
+                    .flatten()
+                    .map(move |pair_id| (*pair_id, *adopt))
+            })
+            .filter(|(pair_id, _)| !watched.pairs_queued.contains(pair_id))
+            .filter(|(pair_id, _)| !self.is_vote_in_progress(*pair_id, nns_proposal_id))
+            .filter(|(pair_id, _)| {
+                self.neuron_pairs.get(pair_id).is_some_and(|p| {
+                    !p.has_wtn_vote(wtn_proposal_id)
+                        && p.should_relay_nns_ballot(nns_proposal_id, true, false)
+                })
+            })
+            .map(|(pair_id, adopt)| {
+                let vote = WtnVote {
+                    nns_proposal_id,
+                    wtn_proposal_id,
+                    adopt,
+                };
+                (pair_id, vote)
+            })
+            .collect()
+    }
+
+    pub fn raise_alert(&mut self, alert: Alert) {
+        log(format!("Alert raised: {alert:?}"));
+        self.alerts.push_back(alert);
+
+        while self.alerts.len() > ALERTS_LIMIT {
+            self.alerts.pop_front();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        log(format!("Paused: {paused}"));
+        self.paused = paused;
+    }
+
+    pub fn record_sns_error(&mut self, kind: SnsErrorKind) {
+        *self.sns_error_counts.entry(kind).or_default() += 1;
+    }
+
+    // Stops votes being cast on behalf of the pair's WTN neuron, raising an alert if the pair
+    // wasn't already suspended
+    pub fn suspend_neuron_pair(&mut self, pair_id: u64, error: SnsError, now: u64) {
+        let Some(pair) = self.neuron_pairs.get_mut(&pair_id) else {
+            return;
+        };
+        if pair.suspend(error.clone(), now) {
+            self.raise_alert(Alert {
+                timestamp: now,
+                pair_id: Some(pair_id),
+                kind: AlertKind::PairSuspended { error },
+            });
+        }
+    }
+
+    pub fn resume_neuron_pair(&mut self, pair_id: u64) -> bool {
+        let resumed = self
+            .neuron_pairs
+            .get_mut(&pair_id)
+            .is_some_and(|p| p.resume());
+        if resumed {
+            log(format!("Neuron pair resumed. PairId: {pair_id}"));
+        }
+        resumed
+    }
+
+    pub fn alerts(&self) -> Vec<Alert> {
+        self.alerts.iter().cloned().collect()
+    }
+
     pub fn push_vote_to_process(&mut self, vote: VoteToProcess) {
         log(format!("Vote queued for processing: {vote:?}"));
         self.votes_to_process.push_back(vote);
         crate::jobs::process_votes::start_job_if_required(self);
     }
 
-    pub fn pop_next_vote_to_process(&mut self) -> Option<VoteToProcess> {
-        self.votes_to_process.pop_front()
+    // Puts a vote which couldn't be processed back at the front of the queue, so that it is still
+    // cast ahead of any later votes for the same WTN neuron
+    pub fn requeue_vote_to_process(&mut self, vote: VoteToProcess) {
+        log(format!("Vote requeued for processing: {vote:?}"));
+        self.votes_to_process.push_front(vote);
+        crate::jobs::process_votes::start_job_if_required(self);
+    }
+
+    // Returns a vote which failed with a transient error to the front of the queue, to be cast
+    // again once its backoff has passed. Returns false if the vote has run out of attempts, in
+    // which case it isn't requeued.
+    pub fn retry_vote_to_process(&mut self, pair_id: u64, wtn_vote: WtnVote, now: u64) -> bool {
+        let Some(not_before) =
+            self.vote_retries
+                .record_failure(pair_id, wtn_vote.wtn_proposal_id, now)
+        else {
+            log(format!(
+                "Vote has failed {MAX_VOTE_ATTEMPTS} times, giving up: {wtn_vote:?}. PairId: {pair_id}"
+            ));
+            return false;
+        };
+        log(format!(
+            "Vote will be retried at {not_before}: {wtn_vote:?}. PairId: {pair_id}"
+        ));
+        self.votes_to_process
+            .push_front(VoteToProcess::PendingWtnVote(pair_id, wtn_vote));
+        crate::jobs::process_votes::start_job_if_required(self);
+        true
+    }
+
+    // Forgets the vote's failed attempts once it has been cast or given up on
+    pub fn clear_vote_retry(&mut self, pair_id: u64, wtn_proposal_id: u64) {
+        self.vote_retries.remove(pair_id, wtn_proposal_id);
+    }
+
+    // The earliest time after `now` at which a vote waiting to be retried becomes ready
+    pub fn next_vote_retry_at(&self, now: u64) -> Option<u64> {
+        self.vote_retries.next_retry_at(now)
+    }
+
+    // Replaces each queued NNS vote whose WTN proposal is known with the vote to cast on the WTN
+    // proposal, dropping those for which there is no WTN proposal. Returns the number promoted.
+    pub fn promote_nns_votes(&mut self, now: u64) -> usize {
+        let mut promoted = 0;
+        let votes = std::mem::take(&mut self.votes_to_process);
+        for vote in votes {
+            let VoteToProcess::NnsVote(pair_id, nns_vote) = vote else {
+                self.votes_to_process.push_back(vote);
+                continue;
+            };
+            // An NNS vote is only queued once the neuron has voted, but an unspecified vote can
+            // never be relayed so it is dropped
+            let Some(adopt) = nns_vote.vote.adopt() else {
+                continue;
+            };
+            match self.get_cached_wtn_proposal_for_nns_proposal(nns_vote.proposal_id, now) {
+                Some(Some(wtn_proposal_id)) => {
+                    promoted += 1;
+                    self.votes_to_process
+                        .push_back(VoteToProcess::PendingWtnVote(
+                            pair_id,
+                            WtnVote {
+                                nns_proposal_id: nns_vote.proposal_id,
+                                wtn_proposal_id,
+                                adopt,
+                            },
+                        ));
+                }
+                Some(None) => {}
+                None => self
+                    .votes_to_process
+                    .push_back(VoteToProcess::NnsVote(pair_id, nns_vote)),
+            }
+        }
+        promoted
+    }
+
+    // The NNS proposals which have queued votes but whose WTN proposals are yet to be determined
+    pub fn unresolved_nns_proposal_ids(&self, now: u64) -> BTreeSet<u64> {
+        self.votes_to_process
+            .iter()
+            .filter_map(|v| match v {
+                VoteToProcess::NnsVote(_, nns_vote) => Some(nns_vote.proposal_id),
+                VoteToProcess::PendingWtnVote(..) => None,
+            })
+            .filter(|id| {
+                self.get_cached_wtn_proposal_for_nns_proposal(*id, now)
+                    .is_none()
+            })
+            .collect()
+    }
+
+    // Returns the first vote in the queue which is ready to be cast on WTN and whose WTN neuron
+    // isn't in `busy_wtn_neuron_ids`. A vote waiting to be retried also holds up the later votes
+    // of its WTN neuron, so that each neuron's votes are cast in the order queued.
+    pub fn pop_next_pending_wtn_vote(
+        &mut self,
+        busy_wtn_neuron_ids: &BTreeSet<[u8; 32]>,
+        now: u64,
+    ) -> Option<(u64, WtnVote)> {
+        let mut blocked_wtn_neuron_ids = busy_wtn_neuron_ids.clone();
+        let index = self.votes_to_process.iter().position(|v| {
+            let VoteToProcess::PendingWtnVote(pair_id, wtn_vote) = v else {
+                return false;
+            };
+            let Some(wtn_neuron_id) = self.neuron_pairs.get(pair_id).map(|p| p.wtn_neuron_id())
+            else {
+                return true;
+            };
+            if blocked_wtn_neuron_ids.contains(&wtn_neuron_id) {
+                false
+            } else if self
+                .vote_retries
+                .is_ready(*pair_id, wtn_vote.wtn_proposal_id, now)
+            {
+                true
+            } else {
+                blocked_wtn_neuron_ids.insert(wtn_neuron_id);
+                false
+            }
+        })?;
+
+        match self.votes_to_process.remove(index) {
+            Some(VoteToProcess::PendingWtnVote(pair_id, wtn_vote)) => Some((pair_id, wtn_vote)),
+            _ => None,
+        }
+    }
+
+    // Records that the vote has been taken off the queue to be cast, returning the id of the lease
+    // which must be released once the vote's outcome has been recorded
+    pub fn lease_vote(&mut self, pair_id: u64, wtn_vote: WtnVote, now: u64) -> u64 {
+        self.vote_leases.acquire(pair_id, wtn_vote, now)
+    }
+
+    pub fn release_vote_lease(&mut self, lease_id: u64) {
+        self.vote_leases.release(lease_id);
+    }
+
+    // Returns the votes whose leases have expired without their outcomes being recorded to the
+    // front of the queue. Returns the number of votes requeued.
+    pub fn requeue_expired_vote_leases(&mut self, now: u64) -> usize {
+        let expired = self.vote_leases.take_expired(now);
+        let count = expired.len();
+        // Requeued newest first so that the oldest ends up at the front
+        for lease in expired.into_iter().rev() {
+            self.requeue_vote_to_process(VoteToProcess::PendingWtnVote(
+                lease.pair_id,
+                lease.wtn_vote,
+            ));
+        }
+        count
+    }
+
+    // Whether the vote was previously in flight and so may already have been cast
+    pub fn wtn_vote_requires_ballot_check(&self, pair_id: u64, wtn_proposal_id: u64) -> bool {
+        self.vote_leases
+            .requires_ballot_check(pair_id, wtn_proposal_id)
+    }
+
+    pub fn clear_wtn_vote_ballot_check(&mut self, pair_id: u64, wtn_proposal_id: u64) {
+        self.vote_leases
+            .clear_ballot_check(pair_id, wtn_proposal_id);
     }
 
     pub fn votes_to_process(&self) -> Vec<VoteToProcess> {
@@ -158,28 +1190,172 @@ impl State {
         &mut self,
         nns_proposal_id: u64,
         wtn_proposal_id: Option<u64>,
+        source: WtnProposalMappingSource,
+        now: u64,
     ) {
-        self.cached_wtn_proposals_per_nns_proposal
-            .insert(nns_proposal_id, wtn_proposal_id);
+        let mapping = WtnProposalMapping {
+            wtn_proposal_id,
+            source,
+            // Every mapping is verified against the WTN proposal before any votes are cast on it
+            status: WtnProposalMappingStatus::Unverified,
+        };
+        self.wtn_proposal_mappings.insert(
+            nns_proposal_id,
+            mapping,
+            &self.wtn_proposal_mappings_config,
+            now,
+        );
+    }
 
-        while self.cached_wtn_proposals_per_nns_proposal.len() > 500 {
-            self.cached_wtn_proposals_per_nns_proposal.pop_first();
+    // Moves the mappings cached by earlier versions into `wtn_proposal_mappings`. Those versions
+    // only ever cached the protocol canister's mappings.
+    pub fn migrate_legacy_wtn_proposal_mappings(&mut self, now: u64) {
+        for (nns_proposal_id, wtn_proposal_id) in
+            std::mem::take(&mut self.cached_wtn_proposals_per_nns_proposal)
+        {
+            self.record_wtn_proposal_for_nns_proposal(
+                nns_proposal_id,
+                wtn_proposal_id,
+                WtnProposalMappingSource::ProtocolCanister,
+                now,
+            );
         }
     }
 
+    // Mappings which are no longer cached are treated as unverified, unless quarantined
+    pub fn wtn_proposal_mapping_status(
+        &self,
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        now: u64,
+    ) -> WtnProposalMappingStatus {
+        self.wtn_proposal_mappings
+            .status(nns_proposal_id, wtn_proposal_id, now)
+    }
+
+    // Whether the protocol canister has mapped the NNS proposal to the WTN proposal, and the
+    // mapping hasn't been quarantined. Only these mappings are trusted to find missed votes.
+    pub fn is_wtn_proposal_mapping_confirmed(
+        &self,
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        now: u64,
+    ) -> bool {
+        self.wtn_proposal_mappings
+            .get(nns_proposal_id, now)
+            .is_some_and(|m| {
+                m.source == WtnProposalMappingSource::ProtocolCanister
+                    && m.wtn_proposal_id == Some(wtn_proposal_id)
+            })
+            && self.wtn_proposal_mapping_status(nns_proposal_id, wtn_proposal_id, now)
+                != WtnProposalMappingStatus::Quarantined
+    }
+
+    // Returns false if the mapping already had this status
+    pub fn set_wtn_proposal_mapping_status(
+        &mut self,
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        status: WtnProposalMappingStatus,
+    ) -> bool {
+        self.wtn_proposal_mappings
+            .set_status(nns_proposal_id, wtn_proposal_id, status)
+    }
+
+    pub fn wtn_proposal_mappings(
+        &self,
+        start_after: Option<u64>,
+        limit: usize,
+    ) -> Vec<WtnProposalMappingEntry> {
+        self.wtn_proposal_mappings
+            .page(start_after, limit)
+            .into_iter()
+            .map(|(nns_proposal_id, stored)| WtnProposalMappingEntry {
+                nns_proposal_id,
+                mapping: stored.mapping,
+                recorded_at: stored.recorded_at,
+                expires_at: stored.expires_at,
+            })
+            .collect()
+    }
+
+    // Removes the given mappings, or every mapping if None, returning the number removed. This is
+    // also the only way to release a quarantined mapping.
+    pub fn invalidate_wtn_proposal_mappings(&mut self, nns_proposal_ids: Option<Vec<u64>>) -> u32 {
+        match nns_proposal_ids {
+            Some(ids) => ids
+                .into_iter()
+                .filter(|id| self.wtn_proposal_mappings.remove(*id))
+                .count() as u32,
+            None => self.wtn_proposal_mappings.clear(),
+        }
+    }
+
+    pub fn record_wtn_proposal(&mut self, wtn_proposal_id: u64, proposal: WtnProposal) {
+        self.cached_wtn_proposals.insert(wtn_proposal_id, proposal);
+
+        while self.cached_wtn_proposals.len() > CACHED_WTN_PROPOSALS_LIMIT {
+            self.cached_wtn_proposals.pop_first();
+        }
+    }
+
+    pub fn get_cached_wtn_proposal(&self, wtn_proposal_id: u64) -> Option<&WtnProposal> {
+        self.cached_wtn_proposals.get(&wtn_proposal_id)
+    }
+
+    pub fn get_cached_nns_proposal_for_wtn_proposal(
+        &self,
+        wtn_proposal_id: u64,
+        now: u64,
+    ) -> Option<u64> {
+        self.wtn_proposal_mappings
+            .find_by_wtn_proposal(wtn_proposal_id, now)
+    }
+
     pub fn get_cached_wtn_proposal_for_nns_proposal(
         &self,
         nns_proposal_id: u64,
+        now: u64,
     ) -> Option<Option<u64>> {
-        self.cached_wtn_proposals_per_nns_proposal
-            .get(&nns_proposal_id)
-            .copied()
+        self.wtn_proposal_mappings
+            .get(nns_proposal_id, now)
+            .map(|m| m.wtn_proposal_id)
+    }
+}
+
+#[derive(Default)]
+pub struct NnsPollingPlan {
+    pub nns_neuron_ids: BTreeSet<u64>,
+    pub next_deadline_seconds: Option<u64>,
+    // Whether any pairs are yet to be polled for the first time
+    pub awaiting_first_poll: bool,
+}
+
+static EMPTY_PAIR_IDS: BTreeSet<u64> = BTreeSet::new();
+
+#[derive(Default)]
+pub struct NeuronPairsFilter {
+    pub admin: Option<Principal>,
+    pub nns_neuron_id: Option<u64>,
+    pub wtn_neuron_id: Option<[u8; 32]>,
+    pub state: Option<NeuronPairState>,
+    pub name_contains: Option<String>,
+}
+
+impl NeuronPairsFilter {
+    fn matches(&self, pair: &NeuronPair) -> bool {
+        !matches!(self.admin, Some(a) if pair.admin() != a)
+            && !matches!(self.nns_neuron_id, Some(id) if pair.nns_neuron_id() != id)
+            && !matches!(self.wtn_neuron_id, Some(id) if pair.wtn_neuron_id() != id)
+            && !matches!(self.state, Some(state) if pair.state() != state)
+            && !matches!(&self.name_contains, Some(n) if !pair.name().to_lowercase().contains(&n.to_lowercase()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Vote;
 
     #[test]
     fn canister_ids() {
@@ -196,4 +1372,476 @@ mod tests {
             Principal::from_text("tsbvt-pyaaa-aaaar-qafva-cai").unwrap()
         );
     }
+
+    #[test]
+    fn query_neuron_pairs_filters_and_paginates() {
+        let mut state = State::new(InitArgs::default());
+        let admin1 = Principal::from_slice(&[1]);
+        for i in 0..10u8 {
+            register_pair(&mut state, 1 + i % 2, &format!("Pair{i}"), 1, i);
+        }
+
+        let filter = NeuronPairsFilter {
+            admin: Some(admin1),
+            ..Default::default()
+        };
+        let (page1, cursor) = state.query_neuron_pairs(&filter, None, 3);
+        assert_eq!(page1.len(), 3);
+        let (page2, cursor) = state.query_neuron_pairs(&filter, cursor, 3);
+        assert_eq!(page2.len(), 2);
+        assert!(cursor.is_none());
+        assert!(page1
+            .iter()
+            .chain(page2.iter())
+            .all(|p| p.admin() == admin1));
+
+        let filter = NeuronPairsFilter {
+            nns_neuron_id: Some(1),
+            name_contains: Some("pair3".to_string()),
+            ..Default::default()
+        };
+        let (pairs, _) = state.query_neuron_pairs(&filter, None, 10);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].wtn_neuron_id(), [3; 32]);
+
+        let filter = NeuronPairsFilter {
+            nns_neuron_id: Some(2),
+            ..Default::default()
+        };
+        assert!(state.query_neuron_pairs(&filter, None, 10).0.is_empty());
+    }
+
+    #[test]
+    fn registration_slots_are_limited_by_the_quota_or_the_global_limit() {
+        let mut state = State::new(InitArgs::default());
+        let admin = Principal::from_slice(&[1]);
+        let (slots, error) = state.remaining_registration_slots(admin);
+        assert_eq!(slots, MAX_NEURON_PAIRS_PER_PRINCIPAL as usize);
+        assert!(matches!(
+            error,
+            RegisterNeuronPairError::PairQuotaExceeded(_)
+        ));
+
+        let pair_ids = register_pairs(&mut state, &[1, 2, 3]);
+        let (slots, _) = state.remaining_registration_slots(admin);
+        assert_eq!(
+            slots,
+            MAX_NEURON_PAIRS_PER_PRINCIPAL as usize - pair_ids.len()
+        );
+
+        // Once fewer pairs remain under the global limit than the caller's quota, the global
+        // limit applies
+        for i in 0..(REGISTRATIONS_LIMIT - 5) as u8 {
+            register_pair(&mut state, i + 2, &format!("Other{i}"), 1, 4);
+        }
+        let (slots, error) = state.remaining_registration_slots(admin);
+        assert_eq!(slots, 2);
+        assert!(matches!(
+            error,
+            RegisterNeuronPairError::RegistrationLimitExceeded(_)
+        ));
+    }
+
+    #[test]
+    fn deregistering_by_filter_only_removes_the_callers_matching_pairs() {
+        let mut state = State::new(InitArgs::default());
+        let pair_ids = register_pairs(&mut state, &[1, 2, 1]);
+        let other_pair_id = register_pair(&mut state, 2, "Pair0", 1, 1);
+        let admin = Principal::from_slice(&[1]);
+        let filter = |nns_neuron_id, wtn_neuron_id: Option<u8>, name: Option<&str>| {
+            DeregisterNeuronPairsArgs {
+                nns_neuron_id,
+                wtn_neuron_id: wtn_neuron_id.map(|id| [id; 32]),
+                name: name.map(|n| n.to_string()),
+            }
+        };
+
+        assert!(state
+            .deregister_neuron_pairs(admin, &filter(None, None, None))
+            .is_empty());
+        assert!(state
+            .deregister_neuron_pairs(admin, &filter(Some(1), Some(2), None))
+            .is_empty());
+        assert_eq!(
+            state.deregister_neuron_pairs(admin, &filter(None, Some(1), Some("Pair0"))),
+            vec![pair_ids[0]]
+        );
+        assert_eq!(
+            state.deregister_neuron_pairs(admin, &filter(None, Some(1), None)),
+            vec![pair_ids[2]]
+        );
+
+        let remaining: BTreeSet<_> = state.neuron_pairs().keys().copied().collect();
+        assert_eq!(remaining, BTreeSet::from([pair_ids[1], other_pair_id]));
+    }
+
+    #[test]
+    fn only_deterministic_registration_outcomes_are_replayed() {
+        let mut state = State::new(InitArgs::default());
+        let caller = Principal::from_slice(&[1]);
+        let transient_errors = [
+            RegisterNeuronPairError::ErrorCallingGovernanceCanister(2, "unreachable".to_string()),
+            RegisterNeuronPairError::ErrorCallingNnsGovernanceCanister(
+                2,
+                "unreachable".to_string(),
+            ),
+            RegisterNeuronPairError::SnsError(SnsError {
+                kind: SnsErrorKind::Unavailable,
+                message: "unavailable".to_string(),
+            }),
+            RegisterNeuronPairError::RegistrationLimitExceeded(1000),
+            RegisterNeuronPairError::NotPermittedToVote,
+            RegisterNeuronPairError::Paused,
+            RegisterNeuronPairError::RateLimited,
+        ];
+        for (i, error) in transient_errors.into_iter().enumerate() {
+            let key = format!("transient{i}");
+            let _ = state.record_registration_outcome(caller, key.clone(), Err(error), 0);
+            assert!(state.registration_outcome(caller, &key, 0).is_none());
+        }
+
+        let deterministic_outcomes = [
+            Ok(1),
+            Err(RegisterNeuronPairError::AlreadyRegistered),
+            Err(RegisterNeuronPairError::KnownNeuronNotFound(
+                "name".to_string(),
+            )),
+            Err(RegisterNeuronPairError::InvalidArgument("name".to_string())),
+        ];
+        for (i, outcome) in deterministic_outcomes.into_iter().enumerate() {
+            let key = format!("deterministic{i}");
+            let _ = state.record_registration_outcome(caller, key.clone(), outcome, 0);
+            assert!(state.registration_outcome(caller, &key, 0).is_some());
+        }
+
+        // A retry after a transient failure is processed again rather than replayed
+        let key = "transient0".to_string();
+        assert_eq!(
+            state
+                .record_registration_outcome(caller, key.clone(), Ok(7), 1)
+                .unwrap(),
+            7
+        );
+        assert_eq!(
+            state
+                .registration_outcome(caller, &key, 1)
+                .unwrap()
+                .unwrap(),
+            7
+        );
+    }
+
+    #[test]
+    fn pairs_awaiting_their_first_poll_are_included_in_the_polling_plan() {
+        let mut state = State::new(InitArgs::default());
+        let pair_id = register_pairs(&mut state, &[1])[0];
+
+        let plan = state.nns_polling_plan(0);
+        assert!(plan.awaiting_first_poll);
+        assert!(plan.nns_neuron_ids.contains(&1));
+
+        state
+            .neuron_pairs
+            .get_mut(&pair_id)
+            .unwrap()
+            .take_nns_votes_to_relay(Vec::new(), &BTreeSet::new(), &BTreeSet::new());
+
+        let plan = state.nns_polling_plan(0);
+        assert!(!plan.awaiting_first_poll);
+        assert!(plan.nns_neuron_ids.is_empty());
+    }
+
+    // Registers a pair for each WTN neuron, in order, returning their ids
+    fn register_pairs(state: &mut State, wtn_neuron_ids: &[u8]) -> Vec<u64> {
+        wtn_neuron_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| register_pair(state, 1, &format!("Pair{i}"), i as u64 + 1, *id))
+            .collect()
+    }
+
+    fn register_pair(
+        state: &mut State,
+        admin: u8,
+        name: &str,
+        nns_neuron_id: u64,
+        wtn_neuron_id: u8,
+    ) -> u64 {
+        let args = NewNeuronPair {
+            name: name.to_string(),
+            nns_neuron_id,
+            nns_known_neuron_name: None,
+            wtn_neuron_id: [wtn_neuron_id; 32],
+            backfill_open_proposals: false,
+        };
+        state
+            .register_neuron_pair(Principal::from_slice(&[admin]), args, 0)
+            .unwrap()
+    }
+
+    fn pending_wtn_vote(pair_id: u64, wtn_proposal_id: u64) -> VoteToProcess {
+        VoteToProcess::PendingWtnVote(
+            pair_id,
+            WtnVote {
+                nns_proposal_id: wtn_proposal_id + 1000,
+                wtn_proposal_id,
+                adopt: true,
+            },
+        )
+    }
+
+    fn pop_wtn_proposal_id(
+        state: &mut State,
+        busy_wtn_neuron_ids: &[u8],
+        now: u64,
+    ) -> Option<(u64, u64)> {
+        let busy = busy_wtn_neuron_ids.iter().map(|id| [*id; 32]).collect();
+        state
+            .pop_next_pending_wtn_vote(&busy, now)
+            .map(|(pair_id, v)| (pair_id, v.wtn_proposal_id))
+    }
+
+    #[test]
+    fn legacy_wtn_proposal_mappings_are_migrated() {
+        let mut state = State::new(InitArgs::default());
+        state.cached_wtn_proposals_per_nns_proposal = BTreeMap::from([(10, Some(100)), (11, None)]);
+
+        state.migrate_legacy_wtn_proposal_mappings(0);
+
+        assert!(state.cached_wtn_proposals_per_nns_proposal.is_empty());
+        let mappings = state.wtn_proposal_mappings(None, 10);
+        assert_eq!(mappings.len(), 2);
+        for (entry, (nns_proposal_id, wtn_proposal_id)) in
+            mappings.iter().zip([(10, Some(100)), (11, None)])
+        {
+            assert_eq!(entry.nns_proposal_id, nns_proposal_id);
+            assert_eq!(entry.mapping.wtn_proposal_id, wtn_proposal_id);
+            assert_eq!(
+                entry.mapping.source,
+                WtnProposalMappingSource::ProtocolCanister
+            );
+            assert_eq!(entry.mapping.status, WtnProposalMappingStatus::Unverified);
+        }
+    }
+
+    #[test]
+    fn only_protocol_canister_mappings_which_are_not_quarantined_are_confirmed() {
+        let mut state = State::new(InitArgs::default());
+        for (nns_proposal_id, wtn_proposal_id, source) in [
+            (10, Some(100), WtnProposalMappingSource::ProtocolCanister),
+            (11, Some(101), WtnProposalMappingSource::WtnGovernance),
+            (12, Some(102), WtnProposalMappingSource::ProtocolCanister),
+            (13, None, WtnProposalMappingSource::ProtocolCanister),
+        ] {
+            state.record_wtn_proposal_for_nns_proposal(nns_proposal_id, wtn_proposal_id, source, 0);
+        }
+        state.set_wtn_proposal_mapping_status(12, 102, WtnProposalMappingStatus::Quarantined);
+
+        assert!(state.is_wtn_proposal_mapping_confirmed(10, 100, 0));
+        assert!(!state.is_wtn_proposal_mapping_confirmed(10, 101, 0));
+        assert!(!state.is_wtn_proposal_mapping_confirmed(11, 101, 0));
+        assert!(!state.is_wtn_proposal_mapping_confirmed(12, 102, 0));
+        assert!(!state.is_wtn_proposal_mapping_confirmed(13, 103, 0));
+        assert!(!state.is_wtn_proposal_mapping_confirmed(14, 104, 0));
+    }
+
+    #[test]
+    fn watched_wtn_proposals_are_only_relayed_to_once_confirmed() {
+        let mut state = State::new(InitArgs::default());
+        let pair_ids = register_pairs(&mut state, &[1, 2]);
+        for pair_id in pair_ids.iter() {
+            let seen = NnsVote {
+                proposal_id: 1,
+                vote: Vote::Adopt,
+            };
+            let pair = state.neuron_pairs.get_mut(pair_id).unwrap();
+            pair.take_nns_votes_to_relay(vec![seen], &BTreeSet::new(), &BTreeSet::new());
+        }
+        let proposal = |deadline_timestamp_seconds| WtnProposal {
+            function_id: 1000,
+            deadline_timestamp_seconds,
+            decided_timestamp_seconds: 0,
+            retrieved_at_seconds: 0,
+        };
+        let vote = |proposal_id, vote| NnsVote { proposal_id, vote };
+        state.record_nns_neuron_votes(1, &[vote(10, Vote::Adopt), vote(11, Vote::Adopt)]);
+        state.record_nns_neuron_votes(2, &[vote(10, Vote::Reject)]);
+
+        assert!(state.watch_wtn_proposal(100, 10, proposal(50)));
+        assert!(state.watch_wtn_proposal(101, 11, proposal(50)));
+        assert!(!state.watch_wtn_proposal(100, 10, proposal(60)));
+
+        // Nothing is mapped or relayed to on the strength of the WTN proposals alone
+        assert_eq!(state.get_cached_wtn_proposal_for_nns_proposal(10, 0), None);
+        assert!(state.nns_votes_to_queue_for_wtn_proposal(100).is_empty());
+        assert!(state.watched_wtn_proposals(0).is_empty());
+        assert_eq!(
+            state.unconfirmed_watched_wtn_proposals(0),
+            vec![(100, 10), (101, 11)]
+        );
+
+        assert!(state.confirm_watched_wtn_proposal(100, Some(100), 0));
+        assert!(!state.confirm_watched_wtn_proposal(101, Some(102), 0));
+        assert!(state.unconfirmed_watched_wtn_proposals(0).is_empty());
+        assert_eq!(state.watched_wtn_proposals(0), vec![(100, 10)]);
+        assert_eq!(
+            state.get_cached_wtn_proposal_for_nns_proposal(10, 0),
+            Some(Some(100))
+        );
+        assert_eq!(
+            state.get_cached_wtn_proposal_for_nns_proposal(11, 0),
+            Some(Some(102))
+        );
+
+        let votes: Vec<_> = state
+            .nns_votes_to_queue_for_wtn_proposal(100)
+            .into_iter()
+            .map(|(pair_id, v)| (pair_id, v.nns_proposal_id, v.adopt))
+            .collect();
+        assert_eq!(
+            votes,
+            vec![(pair_ids[0], 10, true), (pair_ids[1], 10, false)]
+        );
+        assert!(state.nns_votes_to_queue_for_wtn_proposal(101).is_empty());
+
+        // Proposals stop being watched once they close
+        assert!(state.watched_wtn_proposals(60).is_empty());
+        assert!(state.watched_wtn_proposals.is_empty());
+    }
+
+    #[test]
+    fn votes_which_are_queued_or_being_cast_are_not_queued_again() {
+        let mut state = State::new(InitArgs::default());
+        let pair_ids = register_pairs(&mut state, &[1, 2, 3]);
+        for pair_id in pair_ids.iter() {
+            let seen = NnsVote {
+                proposal_id: 1,
+                vote: Vote::Adopt,
+            };
+            let pair = state.neuron_pairs.get_mut(pair_id).unwrap();
+            pair.take_nns_votes_to_relay(vec![seen], &BTreeSet::new(), &BTreeSet::new());
+        }
+        let vote = WtnVote {
+            nns_proposal_id: 1010,
+            wtn_proposal_id: 10,
+            adopt: true,
+        };
+        state.watch_wtn_proposal(
+            10,
+            1010,
+            WtnProposal {
+                function_id: 1000,
+                deadline_timestamp_seconds: 50,
+                decided_timestamp_seconds: 0,
+                retrieved_at_seconds: 0,
+            },
+        );
+        assert!(state.confirm_watched_wtn_proposal(10, Some(10), 0));
+
+        state
+            .votes_to_process
+            .push_back(pending_wtn_vote(pair_ids[0], 10));
+        let lease_id = state.vote_leases.acquire(pair_ids[1], vote.clone(), 0);
+
+        assert!(!state.requeue_missed_vote(pair_ids[0], vote.clone()));
+        assert!(!state.requeue_missed_vote(pair_ids[1], vote.clone()));
+
+        for nns_neuron_id in 1..=3 {
+            let vote = NnsVote {
+                proposal_id: 1010,
+                vote: Vote::Adopt,
+            };
+            state.record_nns_neuron_votes(nns_neuron_id, &[vote]);
+        }
+        let pairs_to_queue = |state: &State| -> Vec<u64> {
+            state
+                .nns_votes_to_queue_for_wtn_proposal(10)
+                .into_iter()
+                .map(|(pair_id, _)| pair_id)
+                .collect()
+        };
+        assert_eq!(pairs_to_queue(&state), vec![pair_ids[2]]);
+
+        state.vote_leases.release(lease_id);
+        assert_eq!(pairs_to_queue(&state), vec![pair_ids[1], pair_ids[2]]);
+    }
+
+    #[test]
+    fn votes_waiting_to_be_retried_hold_up_later_votes_of_their_wtn_neuron() {
+        let mut state = State::new(InitArgs::default());
+        let pair_ids = register_pairs(&mut state, &[1, 2]);
+        for vote in [
+            pending_wtn_vote(pair_ids[0], 10),
+            pending_wtn_vote(pair_ids[0], 11),
+            pending_wtn_vote(pair_ids[1], 10),
+        ] {
+            state.votes_to_process.push_back(vote);
+        }
+        let retry_at = state
+            .vote_retries
+            .record_failure(pair_ids[0], 10, 0)
+            .unwrap();
+
+        assert_eq!(
+            pop_wtn_proposal_id(&mut state, &[], 0),
+            Some((pair_ids[1], 10))
+        );
+        assert_eq!(pop_wtn_proposal_id(&mut state, &[], 0), None);
+        assert_eq!(state.next_vote_retry_at(0), Some(retry_at));
+
+        assert_eq!(
+            pop_wtn_proposal_id(&mut state, &[], retry_at),
+            Some((pair_ids[0], 10))
+        );
+        state.clear_vote_retry(pair_ids[0], 10);
+        assert_eq!(
+            pop_wtn_proposal_id(&mut state, &[], retry_at),
+            Some((pair_ids[0], 11))
+        );
+        assert_eq!(state.next_vote_retry_at(0), None);
+    }
+
+    #[test]
+    fn pending_wtn_votes_are_popped_in_order_skipping_busy_wtn_neurons() {
+        let mut state = State::new(InitArgs::default());
+        let pair_ids = register_pairs(&mut state, &[1, 2, 1]);
+        state.votes_to_process.push_back(VoteToProcess::NnsVote(
+            pair_ids[1],
+            NnsVote {
+                proposal_id: 5,
+                vote: Vote::Adopt,
+            },
+        ));
+        for vote in [
+            pending_wtn_vote(pair_ids[0], 10),
+            pending_wtn_vote(pair_ids[1], 10),
+            pending_wtn_vote(pair_ids[2], 11),
+            pending_wtn_vote(pair_ids[1], 11),
+        ] {
+            state.votes_to_process.push_back(vote);
+        }
+
+        // Pairs 0 and 2 share a WTN neuron, so while it is busy only pair 1's votes are popped
+        assert_eq!(
+            pop_wtn_proposal_id(&mut state, &[1], 0),
+            Some((pair_ids[1], 10))
+        );
+        assert_eq!(pop_wtn_proposal_id(&mut state, &[1, 2], 0), None);
+        assert_eq!(
+            pop_wtn_proposal_id(&mut state, &[], 0),
+            Some((pair_ids[0], 10))
+        );
+        assert_eq!(
+            pop_wtn_proposal_id(&mut state, &[], 0),
+            Some((pair_ids[2], 11))
+        );
+        assert_eq!(
+            pop_wtn_proposal_id(&mut state, &[], 0),
+            Some((pair_ids[1], 11))
+        );
+
+        // NNS votes are left in the queue until their WTN proposals are known
+        assert_eq!(pop_wtn_proposal_id(&mut state, &[], 0), None);
+        assert_eq!(state.votes_to_process_count(), 1);
+    }
 }
@@ -1,8 +1,93 @@
-use crate::{state, DeregisterNeuronPairArgs};
+use crate::{state, DeregisterNeuronPairArgs, DeregisterNeuronPairError};
 use ic_cdk::update;
+use ic_principal::Principal;
 
+#[update]
+fn deregister_neuron_pair_v2(
+    args: DeregisterNeuronPairArgs,
+) -> Result<(), DeregisterNeuronPairError> {
+    deregister(ic_cdk::caller(), args)
+}
+
+// Retained for existing clients, returns true if the pair was deregistered
 #[update]
 fn deregister_neuron_pair(args: DeregisterNeuronPairArgs) -> bool {
-    let caller = ic_cdk::caller();
+    deregister_legacy(ic_cdk::caller(), args)
+}
+
+fn deregister_legacy(caller: Principal, args: DeregisterNeuronPairArgs) -> bool {
+    deregister(caller, args).is_ok()
+}
+
+fn deregister(
+    caller: Principal,
+    args: DeregisterNeuronPairArgs,
+) -> Result<(), DeregisterNeuronPairError> {
+    if caller == Principal::anonymous() {
+        return Err(DeregisterNeuronPairError::NotAuthorized);
+    }
+
     state::mutate(|s| s.deregister_neuron_pair(caller, args.pair_id))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neuron_pair::NewNeuronPair;
+    use crate::state::State;
+    use crate::InitArgs;
+
+    // Runs `f` against a state holding a single pair registered by `admin`, returning its result
+    // along with whether the pair is still registered
+    fn with_registered_pair<R>(admin: Principal, f: impl FnOnce(u64) -> R) -> (R, bool) {
+        let mut state = State::new(InitArgs::default());
+        let args = NewNeuronPair {
+            name: "Pair".to_string(),
+            nns_neuron_id: 1,
+            nns_known_neuron_name: None,
+            wtn_neuron_id: [1; 32],
+            backfill_open_proposals: false,
+        };
+        let pair_id = state.register_neuron_pair(admin, args, 0).unwrap();
+        state::init(state);
+
+        let result = f(pair_id);
+        let registered = state::take().neuron_pairs().contains_key(&pair_id);
+        (result, registered)
+    }
+
+    #[test]
+    fn legacy_endpoint_returns_whether_the_v2_endpoint_succeeded() {
+        let admin = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+
+        for (caller, use_pair_id, expected) in [
+            (admin, true, Ok(())),
+            (other, true, Err(DeregisterNeuronPairError::NotAuthorized)),
+            (
+                Principal::anonymous(),
+                true,
+                Err(DeregisterNeuronPairError::NotAuthorized),
+            ),
+            (admin, false, Err(DeregisterNeuronPairError::NotFound)),
+        ] {
+            let args = |pair_id| DeregisterNeuronPairArgs {
+                pair_id: if use_pair_id {
+                    pair_id
+                } else {
+                    pair_id.wrapping_add(1)
+                },
+            };
+
+            let (v2, v2_registered) =
+                with_registered_pair(admin, |pair_id| deregister(caller, args(pair_id)));
+            let (legacy, legacy_registered) =
+                with_registered_pair(admin, |pair_id| deregister_legacy(caller, args(pair_id)));
+
+            assert_eq!(format!("{v2:?}"), format!("{expected:?}"));
+            assert_eq!(legacy, v2.is_ok());
+            assert_eq!(legacy_registered, v2_registered);
+            assert_eq!(v2_registered, expected.is_err());
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use crate::{state, DeregisterNeuronPairsArgs, DeregisterNeuronPairsError};
+use ic_cdk::update;
+use ic_principal::Principal;
+
+// Deregisters each of the caller's pairs which match every field set in the filter, returning the
+// ids of the pairs deregistered
+#[update]
+fn deregister_neuron_pairs(
+    args: DeregisterNeuronPairsArgs,
+) -> Result<Vec<u64>, DeregisterNeuronPairsError> {
+    if args.nns_neuron_id.is_none() && args.wtn_neuron_id.is_none() && args.name.is_none() {
+        return Err(DeregisterNeuronPairsError::InvalidArgument(
+            "At least one of `nns_neuron_id`, `wtn_neuron_id` or `name` must be provided"
+                .to_string(),
+        ));
+    }
+
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(DeregisterNeuronPairsError::NotAuthorized);
+    }
+
+    let pair_ids = state::mutate(|s| s.deregister_neuron_pairs(caller, &args));
+    if pair_ids.is_empty() {
+        return Err(DeregisterNeuronPairsError::NotFound);
+    }
+    Ok(pair_ids)
+}
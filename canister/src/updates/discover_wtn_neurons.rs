@@ -0,0 +1,97 @@
+use crate::neuron_pair::NewNeuronPair;
+use crate::updates::register_neuron_pair::{
+    load_known_neurons, poll_new_neuron_pairs, resolve_nns_neuron,
+};
+use crate::wtn_governance::REGISTER_VOTE_PERMISSION;
+use crate::{
+    state, wtn_governance, DiscoverWtnNeuronsArgs, DiscoverWtnNeuronsError, DiscoveredWtnNeuron,
+};
+use ic_cdk::update;
+use ic_principal::Principal;
+
+// Lists the WTN neurons which the caller has permissions on and for which this canister has
+// been added as a hotkey, optionally registering a neuron pair for each of them
+#[update]
+async fn discover_wtn_neurons(
+    args: DiscoverWtnNeuronsArgs,
+) -> Result<Vec<DiscoveredWtnNeuron>, DiscoverWtnNeuronsError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(DiscoverWtnNeuronsError::NotAuthorized);
+    }
+
+    let this_canister_id = ic_cdk::id();
+    let wtn_governance_canister = state::mutate(|s| {
+        if s.is_paused() && args.register_for_nns_neuron.is_some() {
+            Err(DiscoverWtnNeuronsError::Paused)
+        } else if !s.try_record_rate_limited_call(caller, ic_cdk::api::time()) {
+            Err(DiscoverWtnNeuronsError::RateLimited)
+        } else {
+            Ok(s.wtn_governance_canister_id())
+        }
+    })?;
+
+    let nns_neuron = match args.register_for_nns_neuron {
+        Some(register_args) => {
+            let resolved =
+                match load_known_neurons(register_args.nns_known_neuron_name.is_some()).await {
+                    Ok(known_neurons) => resolve_nns_neuron(
+                        register_args.nns_neuron_id,
+                        register_args.nns_known_neuron_name,
+                        &known_neurons,
+                    ),
+                    Err(error) => Err(error),
+                };
+            Some((
+                register_args.name,
+                register_args.backfill_open_proposals.unwrap_or_default(),
+                resolved,
+            ))
+        }
+        None => None,
+    };
+
+    let neurons = wtn_governance::list_neurons_of_principal(wtn_governance_canister, caller)
+        .await
+        .map_err(|(code, msg)| {
+            DiscoverWtnNeuronsError::ErrorCallingGovernanceCanister(code as i32, msg)
+        })?
+        .ok_or(DiscoverWtnNeuronsError::TooManyNeurons(
+            wtn_governance::LIST_NEURONS_MAX_NEURONS,
+        ))?;
+
+    let wtn_neuron_ids: Vec<_> = neurons
+        .iter()
+        .filter(|n| n.has_permission(this_canister_id, REGISTER_VOTE_PERMISSION))
+        .filter_map(|n| n.id())
+        .collect();
+
+    let now = ic_cdk::api::time();
+    let results: Vec<_> = state::mutate(|s| {
+        wtn_neuron_ids
+            .into_iter()
+            .map(|wtn_neuron_id| DiscoveredWtnNeuron {
+                wtn_neuron_id,
+                registration_result: nns_neuron.as_ref().map(|(name, backfill, nns_neuron)| {
+                    let (nns_neuron_id, nns_known_neuron_name) = nns_neuron.clone()?;
+                    let new_pair = NewNeuronPair {
+                        name: name.clone(),
+                        nns_neuron_id,
+                        nns_known_neuron_name,
+                        wtn_neuron_id,
+                        backfill_open_proposals: *backfill,
+                    };
+                    s.register_neuron_pair(caller, new_pair, now)
+                }),
+            })
+            .collect()
+    });
+
+    if results
+        .iter()
+        .any(|r| matches!(r.registration_result, Some(Ok(_))))
+    {
+        poll_new_neuron_pairs();
+    }
+    Ok(results)
+}
@@ -0,0 +1,32 @@
+use crate::guards::check_caller_is_controller;
+use crate::logs::log;
+use crate::{state, ControllerUpdateError, InvalidateWtnProposalMappingsArgs};
+use ic_cdk::update;
+
+// Removes cached NNS to WTN proposal mappings so that they are resolved again when next needed
+#[update]
+fn invalidate_wtn_proposal_mappings(
+    args: InvalidateWtnProposalMappingsArgs,
+) -> Result<u32, ControllerUpdateError> {
+    validate(&args)?;
+
+    let removed = state::mutate(|s| s.invalidate_wtn_proposal_mappings(args.nns_proposal_ids));
+    log(format!("WTN proposal mappings invalidated: {removed}"));
+    Ok(removed)
+}
+
+fn validate(args: &InvalidateWtnProposalMappingsArgs) -> Result<(), ControllerUpdateError> {
+    check_caller_is_controller(ControllerUpdateError::NotAuthorized)?;
+
+    if args
+        .nns_proposal_ids
+        .as_ref()
+        .is_some_and(|ids| ids.is_empty())
+    {
+        return Err(ControllerUpdateError::InvalidArgument(
+            "`nns_proposal_ids` must not be empty, set it to null to invalidate every mapping"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
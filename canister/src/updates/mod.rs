@@ -1,3 +1,14 @@
 mod deregister_neuron_pair;
-mod register_neuron_pair;
+mod deregister_neuron_pairs;
+mod discover_wtn_neurons;
+mod invalidate_wtn_proposal_mappings;
+pub(crate) mod register_neuron_pair;
+mod register_neuron_pairs;
+mod seed_wtn_proposal_mappings;
+mod set_paused;
 mod status;
+
+// Used by the endpoints which are retained for existing clients and which can't return an error
+fn unwrap_or_trap<T, E: std::fmt::Debug>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|error| ic_cdk::trap(&format!("{error:?}")))
+}
@@ -1,17 +1,50 @@
-use crate::{state, RegisterNeuronPairArgs, RegisterNeuronPairError};
-use candid::CandidType;
-use ic_cdk::api::call::CallResult;
+use crate::idempotency_keys::MAX_IDEMPOTENCY_KEY_LEN;
+use crate::jobs::check_for_new_nns_votes;
+use crate::neuron_pair::NewNeuronPair;
+use crate::state::{MAX_NEURON_PAIRS_PER_PRINCIPAL, REGISTRATIONS_LIMIT};
+use crate::wtn_governance::REGISTER_VOTE_PERMISSION;
+use crate::{
+    nns_governance, state, wtn_governance, RegisterNeuronPairArgs, RegisterNeuronPairError,
+};
 use ic_cdk::update;
 use ic_principal::Principal;
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
 
-const REGISTER_VOTE_PERMISSION: i32 = 4;
-const REGISTRATIONS_LIMIT: u32 = 100;
+const MAX_GET_NEURON_ATTEMPTS: u32 = 2;
 
+// If an idempotency key is provided and the caller has already made a registration with the same
+// key, the outcome of that registration is returned rather than registering again
 #[update]
 async fn register_neuron_pair(
     args: RegisterNeuronPairArgs,
 ) -> Result<u64, RegisterNeuronPairError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(RegisterNeuronPairError::NotAuthorized);
+    }
+
+    let idempotency_key = args.idempotency_key.clone();
+    if let Some(key) = &idempotency_key {
+        validate_idempotency_key(key)?;
+        if let Some(result) =
+            state::read(|s| s.registration_outcome(caller, key, ic_cdk::api::time()))
+        {
+            return result;
+        }
+    }
+
+    let result = register(args).await;
+
+    match idempotency_key {
+        Some(key) => state::mutate(|s| {
+            s.record_registration_outcome(caller, key, result, ic_cdk::api::time())
+        }),
+        None => result,
+    }
+}
+
+async fn register(args: RegisterNeuronPairArgs) -> Result<u64, RegisterNeuronPairError> {
     let PrepareSuccess {
         caller,
         wtn_governance_canister,
@@ -20,38 +53,112 @@ async fn register_neuron_pair(
         Err(error) => return Err(error),
     };
 
-    match call_get_neuron(wtn_governance_canister, args.wtn_neuron_id).await {
-        Ok(response) => match response.0.result.unwrap() {
-            GetNeuronResult::Neuron(neuron) => {
-                let this_canister_id = ic_cdk::id();
-                if !neuron.permissions.iter().any(|p| {
-                    p.principal == Some(this_canister_id)
-                        && p.permission_type.contains(&REGISTER_VOTE_PERMISSION)
-                }) {
-                    return Err(RegisterNeuronPairError::NotPermittedToVote);
+    let known_neurons = load_known_neurons(args.nns_known_neuron_name.is_some()).await?;
+    let (nns_neuron_id, nns_known_neuron_name) = resolve_nns_neuron(
+        args.nns_neuron_id,
+        args.nns_known_neuron_name,
+        &known_neurons,
+    )?;
+
+    check_vote_permission(wtn_governance_canister, args.wtn_neuron_id).await?;
+
+    let new_pair = NewNeuronPair {
+        name: args.name,
+        nns_neuron_id,
+        nns_known_neuron_name,
+        wtn_neuron_id: args.wtn_neuron_id,
+        backfill_open_proposals: args.backfill_open_proposals.unwrap_or_default(),
+    };
+    let result = state::mutate(|s| s.register_neuron_pair(caller, new_pair, ic_cdk::api::time()));
+    if result.is_ok() {
+        poll_new_neuron_pairs();
+    }
+    result
+}
+
+// Polls the NNS neurons of newly registered pairs straight away, rather than waiting for the next
+// scheduled poll which may be up to the heartbeat interval away
+pub(crate) fn poll_new_neuron_pairs() {
+    check_for_new_nns_votes::run_within(Duration::ZERO);
+}
+
+pub(crate) fn validate_idempotency_key(key: &str) -> Result<(), RegisterNeuronPairError> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        Err(RegisterNeuronPairError::InvalidArgument(format!(
+            "`idempotency_key` must be between 1 and {MAX_IDEMPOTENCY_KEY_LEN} bytes"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+// Retrieves the NNS known neurons, but only if they are needed to resolve a neuron by name,
+// otherwise an empty map is returned
+pub(crate) async fn load_known_neurons(
+    required: bool,
+) -> Result<BTreeMap<u64, String>, RegisterNeuronPairError> {
+    if !required {
+        return Ok(BTreeMap::new());
+    }
+
+    let nns_governance_canister = state::read(|s| s.nns_governance_canister_id());
+    nns_governance::list_known_neurons(nns_governance_canister)
+        .await
+        .map_err(|(code, msg)| {
+            RegisterNeuronPairError::ErrorCallingNnsGovernanceCanister(code as i32, msg)
+        })
+}
+
+// Determines the NNS neuron to follow, which can be specified either by its id or by the name of
+// a known neuron. Returns the neuron id along with the resolved known neuron name, if any.
+pub(crate) fn resolve_nns_neuron(
+    nns_neuron_id: Option<u64>,
+    nns_known_neuron_name: Option<String>,
+    known_neurons: &BTreeMap<u64, String>,
+) -> Result<(u64, Option<String>), RegisterNeuronPairError> {
+    match (nns_neuron_id, nns_known_neuron_name) {
+        (Some(nns_neuron_id), None) => Ok((nns_neuron_id, None)),
+        (None, Some(name)) => nns_governance::find_known_neuron_by_name(known_neurons, &name)
+            .map(|id| (id, known_neurons.get(&id).cloned()))
+            .ok_or(RegisterNeuronPairError::KnownNeuronNotFound(name)),
+        _ => Err(RegisterNeuronPairError::InvalidArgument(
+            "Exactly one of `nns_neuron_id` or `nns_known_neuron_name` must be provided"
+                .to_string(),
+        )),
+    }
+}
+
+// Checks that this canister has been granted permission to vote on behalf of the WTN neuron.
+// Transient WTN governance errors are retried once, any other errors are returned to the caller.
+pub(crate) async fn check_vote_permission(
+    wtn_governance_canister: Principal,
+    wtn_neuron_id: [u8; 32],
+) -> Result<(), RegisterNeuronPairError> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match wtn_governance::get_neuron(wtn_governance_canister, wtn_neuron_id).await {
+            Ok(Ok(neuron)) => {
+                return if neuron.has_permission(ic_cdk::id(), REGISTER_VOTE_PERMISSION) {
+                    Ok(())
+                } else {
+                    Err(RegisterNeuronPairError::NotPermittedToVote)
+                };
+            }
+            Ok(Err(error)) => {
+                let error = error.into_sns_error();
+                state::mutate(|s| s.record_sns_error(error.kind));
+                if !error.kind.is_transient() || attempts >= MAX_GET_NEURON_ATTEMPTS {
+                    return Err(RegisterNeuronPairError::SnsError(error));
                 }
             }
-            GetNeuronResult::Error(error) => {
-                return Err(RegisterNeuronPairError::GovernanceError(
-                    error.error_type,
-                    error.error_message,
+            Err((code, msg)) => {
+                return Err(RegisterNeuronPairError::ErrorCallingGovernanceCanister(
+                    code as i32,
+                    msg,
                 ));
             }
-        },
-        Err((code, msg)) => {
-            return Err(RegisterNeuronPairError::ErrorCallingGovernanceCanister(
-                code as i32,
-                msg,
-            ))
         }
-    };
-
-    if let Some(pair_id) = state::mutate(|s| {
-        s.register_neuron_pair(caller, args.name, args.nns_neuron_id, args.wtn_neuron_id)
-    }) {
-        Ok(pair_id)
-    } else {
-        Err(RegisterNeuronPairError::AlreadyRegistered)
     }
 }
 
@@ -60,66 +167,29 @@ struct PrepareSuccess {
     wtn_governance_canister: Principal,
 }
 
+// Rejects the registration before any inter-canister calls are made if it can't succeed, and
+// otherwise counts it towards the caller's rate limit
 fn prepare() -> Result<PrepareSuccess, RegisterNeuronPairError> {
-    state::read(|s| {
-        if s.neuron_pairs().len() >= REGISTRATIONS_LIMIT as usize {
+    let caller = ic_cdk::caller();
+    state::mutate(|s| {
+        if s.is_paused() {
+            Err(RegisterNeuronPairError::Paused)
+        } else if s.neuron_pairs().len() >= REGISTRATIONS_LIMIT as usize {
             Err(RegisterNeuronPairError::RegistrationLimitExceeded(
                 REGISTRATIONS_LIMIT,
             ))
+        } else if s.neuron_pair_count_for_admin(caller) >= MAX_NEURON_PAIRS_PER_PRINCIPAL as usize {
+            Err(RegisterNeuronPairError::PairQuotaExceeded(
+                MAX_NEURON_PAIRS_PER_PRINCIPAL,
+            ))
+        } else if !s.try_record_rate_limited_call(caller, ic_cdk::api::time()) {
+            Err(RegisterNeuronPairError::RateLimited)
         } else {
             Ok(s.wtn_governance_canister_id())
         }
     })
     .map(|wtn_governance_canister| PrepareSuccess {
-        caller: ic_cdk::caller(),
+        caller,
         wtn_governance_canister,
     })
 }
-
-async fn call_get_neuron(
-    governance_canister: Principal,
-    neuron_id: [u8; 32],
-) -> CallResult<(GetNeuronResponse,)> {
-    let get_neuron_args = GetNeuronArgs {
-        neuron_id: NeuronId { id: neuron_id },
-    };
-    ic_cdk::call(governance_canister, "get_neuron", (get_neuron_args,)).await
-}
-
-#[derive(CandidType, Serialize)]
-struct GetNeuronArgs {
-    neuron_id: NeuronId,
-}
-
-#[derive(CandidType, Serialize)]
-struct NeuronId {
-    id: [u8; 32],
-}
-
-#[derive(CandidType, Deserialize)]
-struct GetNeuronResponse {
-    result: Option<GetNeuronResult>,
-}
-
-#[derive(CandidType, Deserialize)]
-enum GetNeuronResult {
-    Neuron(Neuron),
-    Error(GovernanceError),
-}
-
-#[derive(CandidType, Deserialize)]
-struct Neuron {
-    permissions: Vec<NeuronPermission>,
-}
-
-#[derive(CandidType, Deserialize)]
-struct NeuronPermission {
-    principal: Option<Principal>,
-    permission_type: Vec<i32>,
-}
-
-#[derive(CandidType, Deserialize)]
-struct GovernanceError {
-    error_type: i32,
-    error_message: String,
-}
@@ -0,0 +1,206 @@
+use crate::neuron_pair::NewNeuronPair;
+use crate::state::{State, MAX_NEURON_PAIRS_PER_PRINCIPAL};
+use crate::updates::register_neuron_pair::{
+    check_vote_permission, load_known_neurons, poll_new_neuron_pairs, resolve_nns_neuron,
+    validate_idempotency_key,
+};
+use crate::{state, RegisterNeuronPairArgs, RegisterNeuronPairError};
+use ic_cdk::update;
+use ic_principal::Principal;
+use std::collections::BTreeMap;
+
+#[update]
+async fn register_neuron_pairs(
+    args: Vec<RegisterNeuronPairArgs>,
+) -> Vec<Result<u64, RegisterNeuronPairError>> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return args
+            .iter()
+            .map(|_| Err(RegisterNeuronPairError::NotAuthorized))
+            .collect();
+    }
+    // Also checked by `inspect_message`, but that isn't called for inter-canister calls
+    if args.len() > MAX_NEURON_PAIRS_PER_PRINCIPAL as usize {
+        let error = RegisterNeuronPairError::InvalidArgument(format!(
+            "At most {MAX_NEURON_PAIRS_PER_PRINCIPAL} pairs can be registered per call"
+        ));
+        return args.iter().map(|_| Err(error.clone())).collect();
+    }
+
+    // Items which repeat an earlier registration's idempotency key return its outcome
+    let now = ic_cdk::api::time();
+    let previous_outcomes: Vec<_> = state::read(|s| {
+        args.iter()
+            .map(|a| {
+                let key = a.idempotency_key.as_ref()?;
+                if let Err(error) = validate_idempotency_key(key) {
+                    return Some(Err(error));
+                }
+                s.registration_outcome(caller, key, now)
+            })
+            .collect()
+    });
+    let idempotency_keys: Vec<_> = args.iter().map(|a| a.idempotency_key.clone()).collect();
+
+    // The call only counts towards the caller's rate limit if it has new items to register
+    let has_new_items = previous_outcomes.iter().any(|o| o.is_none());
+    let prepare_result = state::mutate(|s| {
+        if s.is_paused() {
+            Err(RegisterNeuronPairError::Paused)
+        } else if has_new_items && !s.try_record_rate_limited_call(caller, now) {
+            Err(RegisterNeuronPairError::RateLimited)
+        } else {
+            let (remaining_slots, limit_error) = s.remaining_registration_slots(caller);
+            Ok((
+                s.wtn_governance_canister_id(),
+                (remaining_slots == 0).then_some(limit_error),
+            ))
+        }
+    });
+    let (wtn_governance_canister, no_slots_error) = match prepare_result {
+        Ok(result) => result,
+        Err(error) => return args.iter().map(|_| Err(error.clone())).collect(),
+    };
+
+    let known_neurons =
+        load_known_neurons(args.iter().any(|a| a.nns_known_neuron_name.is_some())).await;
+
+    // Resolve each NNS neuron and check each WTN neuron's permissions concurrently, skipping the
+    // calls if the caller has no slots left to register pairs into
+    let futures: Vec<_> = args
+        .into_iter()
+        .zip(previous_outcomes)
+        .map(|(args, previous_outcome)| {
+            let known_neurons = &known_neurons;
+            let no_slots_error = &no_slots_error;
+            async move {
+                if let Some(previous_outcome) = previous_outcome {
+                    return Prepared::Previous(previous_outcome);
+                }
+                if let Some(error) = no_slots_error {
+                    return Prepared::New(Err(error.clone()));
+                }
+                Prepared::New(prepare(args, wtn_governance_canister, known_neurons).await)
+            }
+        })
+        .collect();
+
+    let prepared = futures::future::join_all(futures).await;
+
+    let now = ic_cdk::api::time();
+    let (results, registered) =
+        state::mutate(|s| register_prepared(s, caller, prepared, idempotency_keys, now));
+
+    if registered {
+        poll_new_neuron_pairs();
+    }
+    results
+}
+
+// Either the outcome of an earlier call with the same idempotency key, or the result of validating
+// a new registration
+enum Prepared {
+    Previous(Result<u64, RegisterNeuronPairError>),
+    New(Result<NewNeuronPair, RegisterNeuronPairError>),
+}
+
+// Resolves the NNS neuron and checks the WTN neuron's permissions
+async fn prepare(
+    args: RegisterNeuronPairArgs,
+    wtn_governance_canister: Principal,
+    known_neurons: &Result<BTreeMap<u64, String>, RegisterNeuronPairError>,
+) -> Result<NewNeuronPair, RegisterNeuronPairError> {
+    let (nns_neuron_id, nns_known_neuron_name) = resolve_nns_neuron(
+        args.nns_neuron_id,
+        args.nns_known_neuron_name,
+        known_neurons.as_ref().map_err(|e| e.clone())?,
+    )?;
+    check_vote_permission(wtn_governance_canister, args.wtn_neuron_id).await?;
+    Ok(NewNeuronPair {
+        name: args.name,
+        nns_neuron_id,
+        nns_known_neuron_name,
+        wtn_neuron_id: args.wtn_neuron_id,
+        backfill_open_proposals: args.backfill_open_proposals.unwrap_or_default(),
+    })
+}
+
+// Registers the new items which passed validation, in order, returning the result of each item
+// along with whether any pairs were registered. The registration limits are checked as each pair
+// is registered, so replayed and invalid items don't take up any of the caller's remaining slots.
+fn register_prepared(
+    state: &mut State,
+    caller: Principal,
+    prepared: Vec<Prepared>,
+    idempotency_keys: Vec<Option<String>>,
+    now: u64,
+) -> (Vec<Result<u64, RegisterNeuronPairError>>, bool) {
+    let mut registered = false;
+    let results = prepared
+        .into_iter()
+        .zip(idempotency_keys)
+        .map(|(prepared, key)| match prepared {
+            Prepared::Previous(outcome) => outcome,
+            Prepared::New(new_pair) => {
+                let result = new_pair.and_then(|p| state.register_neuron_pair(caller, p, now));
+                registered |= result.is_ok();
+                match key {
+                    Some(key) => state.record_registration_outcome(caller, key, result, now),
+                    None => result,
+                }
+            }
+        })
+        .collect();
+    (results, registered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InitArgs;
+
+    fn new_pair(wtn_neuron_id: u8) -> NewNeuronPair {
+        NewNeuronPair {
+            name: format!("Pair{wtn_neuron_id}"),
+            nns_neuron_id: 1,
+            nns_known_neuron_name: None,
+            wtn_neuron_id: [wtn_neuron_id; 32],
+            backfill_open_proposals: false,
+        }
+    }
+
+    #[test]
+    fn only_new_valid_items_take_up_the_remaining_slots() {
+        let mut state = State::new(InitArgs::default());
+        let caller = Principal::from_slice(&[1]);
+        for i in 0..MAX_NEURON_PAIRS_PER_PRINCIPAL as u8 - 1 {
+            state.register_neuron_pair(caller, new_pair(i), 0).unwrap();
+        }
+
+        let prepared = vec![
+            Prepared::New(Err(RegisterNeuronPairError::NotPermittedToVote)),
+            Prepared::Previous(Ok(7)),
+            Prepared::New(Ok(new_pair(20))),
+            Prepared::New(Ok(new_pair(21))),
+        ];
+        let (results, registered) =
+            register_prepared(&mut state, caller, prepared, vec![None; 4], 0);
+
+        assert!(registered);
+        assert!(matches!(
+            results[0],
+            Err(RegisterNeuronPairError::NotPermittedToVote)
+        ));
+        assert!(matches!(results[1], Ok(7)));
+        assert!(results[2].is_ok());
+        assert!(matches!(
+            results[3],
+            Err(RegisterNeuronPairError::PairQuotaExceeded(_))
+        ));
+        assert_eq!(
+            state.neuron_pair_count_for_admin(caller),
+            MAX_NEURON_PAIRS_PER_PRINCIPAL as usize
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use crate::guards::check_caller_is_controller;
+use crate::logs::log;
+use crate::{state, ControllerUpdateError, SeedWtnProposalMappingsArgs, WtnProposalMappingSource};
+use ic_cdk::update;
+
+const MAX_MAPPINGS_PER_CALL: usize = 500;
+
+// Pre-populates the NNS to WTN proposal mappings, for example while the WTN protocol canister is
+// unavailable. Seeded mappings are still verified against the WTN proposal before being voted on.
+#[update]
+fn seed_wtn_proposal_mappings(
+    args: SeedWtnProposalMappingsArgs,
+) -> Result<u32, ControllerUpdateError> {
+    validate(&args)?;
+
+    let now = ic_cdk::api::time();
+    let count = args.mappings.len() as u32;
+    state::mutate(|s| {
+        for mapping in args.mappings {
+            s.record_wtn_proposal_for_nns_proposal(
+                mapping.nns_proposal_id,
+                mapping.wtn_proposal_id,
+                WtnProposalMappingSource::Controller,
+                now,
+            );
+        }
+    });
+    log(format!("WTN proposal mappings seeded: {count}"));
+    Ok(count)
+}
+
+fn validate(args: &SeedWtnProposalMappingsArgs) -> Result<(), ControllerUpdateError> {
+    check_caller_is_controller(ControllerUpdateError::NotAuthorized)?;
+
+    if args.mappings.is_empty() || args.mappings.len() > MAX_MAPPINGS_PER_CALL {
+        return Err(ControllerUpdateError::InvalidArgument(format!(
+            "Between 1 and {MAX_MAPPINGS_PER_CALL} mappings must be provided"
+        )));
+    }
+    Ok(())
+}
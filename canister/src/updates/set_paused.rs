@@ -0,0 +1,13 @@
+use crate::guards::check_caller_is_controller;
+use crate::{state, SetPausedArgs, SetPausedError};
+use ic_cdk::update;
+
+// Pauses or resumes the registering of neuron pairs. Pairs can still be deregistered and votes
+// continue to be relayed while paused, so that users can always stop the relay voting with their
+// neurons.
+#[update]
+fn set_paused(args: SetPausedArgs) -> Result<(), SetPausedError> {
+    check_caller_is_controller(SetPausedError::NotAuthorized)?;
+    state::mutate(|s| s.set_paused(args.paused));
+    Ok(())
+}
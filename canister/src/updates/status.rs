@@ -1,11 +1,19 @@
-use crate::{CanisterIdRecord, CanisterStatusResponse};
+use crate::updates::unwrap_or_trap;
+use crate::{CanisterIdRecord, CanisterStatusResponse, StatusError};
 use ic_cdk::update;
 
+// Fails if the relay is not one of its own controllers
 #[update]
-async fn status() -> CanisterStatusResponse {
+async fn status_v2() -> Result<CanisterStatusResponse, StatusError> {
     let canister_id = ic_cdk::id();
     ic_cdk::api::management_canister::main::canister_status(CanisterIdRecord { canister_id })
         .await
-        .unwrap()
-        .0
+        .map(|r| r.0)
+        .map_err(|(code, msg)| StatusError::ErrorCallingManagementCanister(code as i32, msg))
+}
+
+// Retained for existing clients, traps if the status can't be retrieved
+#[update]
+async fn status() -> CanisterStatusResponse {
+    unwrap_or_trap(status_v2().await)
 }
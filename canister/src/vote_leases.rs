@@ -0,0 +1,127 @@
+use crate::WtnVote;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+// How long a vote can be in flight before it is assumed to have been lost, eg. due to a trap
+// after one of its calls completed
+const VOTE_LEASE_DURATION: Duration = Duration::from_secs(10 * 60); // 10 minutes
+
+// The votes which have been taken off the queue and are being cast. Each is held under a lease
+// which is released once the vote's outcome has been recorded. If a lease expires, or the canister
+// is upgraded while it is held, the vote is returned to the queue, and since it may already have
+// been cast, the WTN neuron's ballot is checked before it is cast again.
+#[derive(Serialize, Deserialize, Default)]
+pub struct VoteLeases {
+    next_id: u64,
+    leases: BTreeMap<u64, VoteLease>,
+    // The (pair id, WTN proposal id) of each recovered vote whose ballot must be checked
+    ballot_checks: BTreeSet<(u64, u64)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VoteLease {
+    pub pair_id: u64,
+    pub wtn_vote: WtnVote,
+    pub started_at: u64,
+    pub expires_at: u64,
+}
+
+impl VoteLeases {
+    pub fn acquire(&mut self, pair_id: u64, wtn_vote: WtnVote, now: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.leases.insert(
+            id,
+            VoteLease {
+                pair_id,
+                wtn_vote,
+                started_at: now,
+                expires_at: now.saturating_add(VOTE_LEASE_DURATION.as_nanos() as u64),
+            },
+        );
+        id
+    }
+
+    // Returns false if the lease has already expired and its vote been returned to the queue
+    pub fn release(&mut self, id: u64) -> bool {
+        self.leases.remove(&id).is_some()
+    }
+
+    // Removes the leases which expired before `now`, oldest first, marking their votes as
+    // requiring a ballot check
+    pub fn take_expired(&mut self, now: u64) -> Vec<VoteLease> {
+        let expired: Vec<_> = self
+            .leases
+            .iter()
+            .filter(|(_, l)| l.expires_at <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.leases.remove(&id))
+            .inspect(|l| {
+                self.ballot_checks
+                    .insert((l.pair_id, l.wtn_vote.wtn_proposal_id));
+            })
+            .collect()
+    }
+
+    // Whether the pair's vote on the NNS proposal is currently being cast
+    pub fn is_leased(&self, pair_id: u64, nns_proposal_id: u64) -> bool {
+        self.leases
+            .values()
+            .any(|l| l.pair_id == pair_id && l.wtn_vote.nns_proposal_id == nns_proposal_id)
+    }
+
+    pub fn requires_ballot_check(&self, pair_id: u64, wtn_proposal_id: u64) -> bool {
+        self.ballot_checks.contains(&(pair_id, wtn_proposal_id))
+    }
+
+    pub fn clear_ballot_check(&mut self, pair_id: u64, wtn_proposal_id: u64) {
+        self.ballot_checks.remove(&(pair_id, wtn_proposal_id));
+    }
+
+    pub fn count(&self) -> usize {
+        self.leases.len()
+    }
+
+    pub fn remove_pair(&mut self, pair_id: u64) {
+        self.leases.retain(|_, l| l.pair_id != pair_id);
+        self.ballot_checks.retain(|(id, _)| *id != pair_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wtn_vote(wtn_proposal_id: u64) -> WtnVote {
+        WtnVote {
+            nns_proposal_id: wtn_proposal_id + 1000,
+            wtn_proposal_id,
+            adopt: true,
+        }
+    }
+
+    #[test]
+    fn expired_leases_require_a_ballot_check() {
+        let mut leases = VoteLeases::default();
+        let first = leases.acquire(1, wtn_vote(10), 0);
+        let second = leases.acquire(2, wtn_vote(11), 100);
+        let expiry = VOTE_LEASE_DURATION.as_nanos() as u64;
+
+        assert!(leases.take_expired(expiry - 1).is_empty());
+
+        let expired = leases.take_expired(expiry);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].pair_id, 1);
+        assert!(leases.requires_ballot_check(1, 10));
+        assert!(!leases.requires_ballot_check(2, 11));
+
+        assert!(!leases.release(first));
+        assert!(leases.release(second));
+        assert_eq!(leases.count(), 0);
+    }
+}
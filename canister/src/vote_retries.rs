@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// The delay before a failed vote is first retried, which doubles with each further failure
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10 * 60); // 10 minutes
+
+// The number of times a vote can fail before it is given up on
+pub const MAX_VOTE_ATTEMPTS: u32 = 8;
+
+// The votes which have failed with a transient error and are waiting to be retried, keyed by
+// (pair id, WTN proposal id). A vote remains in the queue while waiting, but isn't cast again
+// until its backoff has passed.
+#[derive(Serialize, Deserialize, Default)]
+pub struct VoteRetries {
+    retries: BTreeMap<(u64, u64), VoteRetry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoteRetry {
+    pub attempts: u32,
+    pub not_before: u64,
+}
+
+impl VoteRetries {
+    // Records a failed attempt at casting the vote, returning when it can next be attempted, or
+    // None if it has run out of attempts, in which case it is forgotten
+    pub fn record_failure(&mut self, pair_id: u64, wtn_proposal_id: u64, now: u64) -> Option<u64> {
+        let key = (pair_id, wtn_proposal_id);
+        let attempts = self.retries.get(&key).map_or(0, |r| r.attempts) + 1;
+        if attempts >= MAX_VOTE_ATTEMPTS {
+            self.retries.remove(&key);
+            return None;
+        }
+
+        let not_before = now.saturating_add(retry_delay(attempts).as_nanos() as u64);
+        self.retries.insert(
+            key,
+            VoteRetry {
+                attempts,
+                not_before,
+            },
+        );
+        Some(not_before)
+    }
+
+    pub fn get(&self, pair_id: u64, wtn_proposal_id: u64) -> Option<VoteRetry> {
+        self.retries.get(&(pair_id, wtn_proposal_id)).copied()
+    }
+
+    pub fn is_ready(&self, pair_id: u64, wtn_proposal_id: u64, now: u64) -> bool {
+        self.get(pair_id, wtn_proposal_id)
+            .filter(|r| r.not_before > now)
+            .is_none()
+    }
+
+    pub fn remove(&mut self, pair_id: u64, wtn_proposal_id: u64) {
+        self.retries.remove(&(pair_id, wtn_proposal_id));
+    }
+
+    pub fn remove_pair(&mut self, pair_id: u64) {
+        self.retries.retain(|(id, _), _| *id != pair_id);
+    }
+
+    // The earliest time after `now` at which a vote becomes ready to be retried
+    pub fn next_retry_at(&self, now: u64) -> Option<u64> {
+        self.retries
+            .values()
+            .map(|r| r.not_before)
+            .filter(|t| *t > now)
+            .min()
+    }
+
+    pub fn count(&self) -> usize {
+        self.retries.len()
+    }
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    let multiplier = 1u32
+        .checked_shl(attempts.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    INITIAL_RETRY_DELAY
+        .saturating_mul(multiplier)
+        .min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn retries_back_off_exponentially_up_to_the_limit() {
+        let mut retries = VoteRetries::default();
+
+        assert_eq!(retries.record_failure(1, 10, 0), Some(10 * SECOND));
+        assert!(!retries.is_ready(1, 10, 10 * SECOND - 1));
+        assert!(retries.is_ready(1, 10, 10 * SECOND));
+        assert!(retries.is_ready(2, 10, 0));
+
+        assert_eq!(retries.record_failure(1, 10, 0), Some(20 * SECOND));
+        assert_eq!(retries.record_failure(1, 10, 0), Some(40 * SECOND));
+        for _ in 3..MAX_VOTE_ATTEMPTS - 1 {
+            retries.record_failure(1, 10, 0);
+        }
+        assert_eq!(retries.get(1, 10).unwrap().not_before, 600 * SECOND);
+
+        assert_eq!(retries.record_failure(1, 10, 0), None);
+        assert_eq!(retries.get(1, 10), None);
+        assert_eq!(retries.count(), 0);
+    }
+
+    #[test]
+    fn next_retry_is_the_earliest_still_pending() {
+        let mut retries = VoteRetries::default();
+        retries.record_failure(1, 10, 0);
+        retries.record_failure(1, 11, 5 * SECOND);
+        retries.record_failure(2, 10, 0);
+        retries.record_failure(2, 10, 0);
+
+        assert_eq!(retries.next_retry_at(0), Some(10 * SECOND));
+        assert_eq!(retries.next_retry_at(10 * SECOND), Some(15 * SECOND));
+        assert_eq!(retries.next_retry_at(20 * SECOND), None);
+
+        retries.remove_pair(1);
+        assert_eq!(retries.next_retry_at(0), Some(20 * SECOND));
+        retries.remove(2, 10);
+        assert_eq!(retries.count(), 0);
+    }
+}
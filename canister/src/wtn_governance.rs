@@ -0,0 +1,894 @@
+use crate::{SnsError, SnsErrorKind};
+use candid::CandidType;
+use ic_cdk::api::call::CallResult;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const REGISTER_VOTE_PERMISSION: i32 = 4;
+const LIST_NEURONS_PAGE_SIZE: u32 = 100;
+const LIST_NEURONS_MAX_PAGES: usize = 10;
+// The maximum number of neurons which `list_neurons_of_principal` can return
+pub const LIST_NEURONS_MAX_NEURONS: u32 = LIST_NEURONS_PAGE_SIZE * LIST_NEURONS_MAX_PAGES as u32;
+const VOTE_FOR_NNS_PROPOSALS_FUNCTION_NAME: &str = "Vote for NNS Proposals";
+
+pub async fn get_neuron(
+    governance_canister: Principal,
+    neuron_id: [u8; 32],
+) -> CallResult<Result<Neuron, GovernanceError>> {
+    let args = GetNeuronArgs {
+        neuron_id: NeuronId { id: neuron_id },
+    };
+    let response: CallResult<(GetNeuronResponse,)> =
+        ic_cdk::call(governance_canister, "get_neuron", (args,)).await;
+
+    response.map(|r| match r.0.result.unwrap() {
+        GetNeuronResult::Neuron(neuron) => Ok(neuron),
+        GetNeuronResult::Error(error) => Err(error),
+    })
+}
+
+pub async fn get_proposal(
+    governance_canister: Principal,
+    proposal_id: u64,
+) -> CallResult<Result<ProposalData, GovernanceError>> {
+    let args = GetProposalArgs {
+        proposal_id: Some(ProposalId { id: proposal_id }),
+    };
+    let response: CallResult<(GetProposalResponse,)> =
+        ic_cdk::call(governance_canister, "get_proposal", (args,)).await;
+
+    response.map(|r| match r.0.result {
+        Some(GetProposalResult::Proposal(proposal)) => Ok(proposal),
+        Some(GetProposalResult::Error(error)) => Err(error),
+        None => Err(GovernanceError {
+            error_type: 0,
+            error_message: "Empty response from `get_proposal`".to_string(),
+        }),
+    })
+}
+
+// Returns the most recent proposals, including the ballots of any neurons which this canister has
+// permissions on
+pub async fn list_recent_proposals(
+    governance_canister: Principal,
+    limit: u32,
+) -> CallResult<Vec<ProposalData>> {
+    let args = ListProposalsArgs {
+        limit,
+        before_proposal: None,
+        exclude_type: Vec::new(),
+        include_reward_status: Vec::new(),
+        include_status: Vec::new(),
+        include_ballots_by_caller: Some(true),
+    };
+    let response: CallResult<(ListProposalsResponse,)> =
+        ic_cdk::call(governance_canister, "list_proposals", (args,)).await;
+
+    response.map(|r| r.0.proposals)
+}
+
+// Finds the proposals created by the WTN protocol canister to mirror each of the given NNS
+// proposals, keyed by NNS proposal id
+pub fn find_mirror_proposals<'a>(
+    proposals: &'a [ProposalData],
+    function_id: u64,
+    nns_proposal_ids: &BTreeSet<u64>,
+) -> BTreeMap<u64, &'a ProposalData> {
+    proposals
+        .iter()
+        .filter(|p| p.action == function_id)
+        .filter_map(|p| Some((p.referenced_nns_proposal_id()?, p)))
+        .filter(|(id, _)| nns_proposal_ids.contains(id))
+        .collect()
+}
+
+pub async fn list_nervous_system_functions(
+    governance_canister: Principal,
+) -> CallResult<Vec<NervousSystemFunction>> {
+    let response: CallResult<(ListNervousSystemFunctionsResponse,)> =
+        ic_cdk::call(governance_canister, "list_nervous_system_functions", ()).await;
+
+    response.map(|r| r.0.functions)
+}
+
+// Finds the generic nervous system function used by the WTN protocol canister to create its
+// "Vote for NNS Proposals" proposals. None is returned if there isn't exactly one such function,
+// so that votes are never cast on proposals of a guessed function.
+pub fn find_vote_for_nns_proposals_function(functions: &[NervousSystemFunction]) -> Option<u64> {
+    let mut matching = functions.iter().filter(|f| {
+        matches!(
+            f.function_type,
+            Some(FunctionType::GenericNervousSystemFunction(_))
+        ) && f
+            .name
+            .trim()
+            .eq_ignore_ascii_case(VOTE_FOR_NNS_PROPOSALS_FUNCTION_NAME)
+    });
+
+    match (matching.next(), matching.next()) {
+        (Some(function), None) => Some(function.id),
+        _ => None,
+    }
+}
+
+// Returns all of the neurons for which `principal` holds any permissions, following the pages
+// returned by the governance canister. Returns None if the principal has more neurons than can be
+// listed within `LIST_NEURONS_MAX_PAGES`, rather than returning a truncated list.
+pub async fn list_neurons_of_principal(
+    governance_canister: Principal,
+    principal: Principal,
+) -> CallResult<Option<Vec<Neuron>>> {
+    let mut neurons: Vec<Neuron> = Vec::new();
+    let mut start_page_at = None;
+
+    for _ in 0..LIST_NEURONS_MAX_PAGES {
+        let args = ListNeuronsArgs {
+            limit: LIST_NEURONS_PAGE_SIZE,
+            start_page_at,
+            of_principal: Some(principal),
+        };
+        let response: CallResult<(ListNeuronsResponse,)> =
+            ic_cdk::call(governance_canister, "list_neurons", (args,)).await;
+
+        let page = response?.0.neurons;
+        let page_len = page.len();
+        neurons.extend(page);
+
+        start_page_at = next_page_start(&neurons, page_len);
+        if start_page_at.is_none() {
+            return Ok(Some(neurons));
+        }
+    }
+
+    Ok(None)
+}
+
+// The neuron which the next page of neurons starts after, or None if the latest page was the last
+fn next_page_start(neurons: &[Neuron], page_len: usize) -> Option<NeuronId> {
+    if page_len < LIST_NEURONS_PAGE_SIZE as usize {
+        return None;
+    }
+    neurons.last().and_then(|n| n.id.clone())
+}
+
+impl Neuron {
+    pub fn id(&self) -> Option<[u8; 32]> {
+        self.id.as_ref().map(|id| id.id)
+    }
+
+    pub fn has_permission(&self, principal: Principal, permission_type: i32) -> bool {
+        self.permissions
+            .iter()
+            .any(|p| p.principal == Some(principal) && p.permission_type.contains(&permission_type))
+    }
+}
+
+impl ProposalData {
+    // The time at which the proposal stops accepting votes
+    pub fn deadline_timestamp_seconds(&self) -> u64 {
+        self.wait_for_quiet_state
+            .as_ref()
+            .map(|w| w.current_deadline_timestamp_seconds)
+            .unwrap_or(
+                self.proposal_creation_timestamp_seconds + self.initial_voting_period_seconds,
+            )
+    }
+}
+
+impl ProposalData {
+    pub fn id(&self) -> Option<u64> {
+        self.id.as_ref().map(|id| id.id)
+    }
+
+    // The NNS proposal which this WTN proposal votes on, taken from the payload which the WTN
+    // protocol canister attached to the proposal. The payload holds the candid encoded id of the
+    // NNS proposal, either as a `nat64` or as an NNS `ProposalId` record.
+    pub fn referenced_nns_proposal_id(&self) -> Option<u64> {
+        let Some(Action::ExecuteGenericNervousSystemFunction(function)) =
+            self.proposal.as_ref()?.action.as_ref()
+        else {
+            return None;
+        };
+        if function.function_id != self.action {
+            return None;
+        }
+        candid::decode_one::<u64>(&function.payload)
+            .or_else(|_| candid::decode_one::<ProposalId>(&function.payload).map(|id| id.id))
+            .ok()
+    }
+
+    // Guesses which NNS proposal this WTN proposal mirrors by searching its title, then its
+    // payload rendering, then its summary for a reference to an NNS proposal id. Since this is
+    // taken from free text it is only ever used as a hint, never to decide which votes to cast.
+    pub fn parsed_nns_proposal_id(&self) -> Option<u64> {
+        let proposal = self.proposal.as_ref();
+        [
+            proposal.map(|p| p.title.as_str()),
+            self.payload_text_rendering.as_deref(),
+            proposal.map(|p| p.summary.as_str()),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(parse_nns_proposal_id)
+    }
+
+    pub fn ballot(&self, neuron_id: [u8; 32]) -> Option<&Ballot> {
+        let neuron_id_hex = hex_encode(&neuron_id);
+        self.ballots
+            .iter()
+            .find(|(id, _)| *id == neuron_id_hex)
+            .map(|(_, ballot)| ballot)
+    }
+}
+
+// Finds the first number following the word "proposal", allowing for common separators such as in
+// "Proposal 123", "Proposal #123", "proposal_id: 123" or ".../proposal/123"
+fn parse_nns_proposal_id(text: &str) -> Option<u64> {
+    let lowercase = text.to_lowercase();
+    lowercase
+        .match_indices("proposal")
+        .find_map(|(index, keyword)| {
+            let digits: String = lowercase[index + keyword.len()..]
+                .trim_start_matches(|c: char| c.is_whitespace() || "#:/_-=id".contains(c))
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse().ok()
+        })
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// The details of a WTN proposal which are needed to decide whether to vote on it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WtnProposal {
+    pub function_id: u64,
+    pub deadline_timestamp_seconds: u64,
+    pub decided_timestamp_seconds: u64,
+    pub retrieved_at_seconds: u64,
+}
+
+impl WtnProposal {
+    pub fn new(proposal: &ProposalData, now_seconds: u64) -> WtnProposal {
+        WtnProposal {
+            function_id: proposal.action,
+            deadline_timestamp_seconds: proposal.deadline_timestamp_seconds(),
+            decided_timestamp_seconds: proposal.decided_timestamp_seconds,
+            retrieved_at_seconds: now_seconds,
+        }
+    }
+
+    pub fn is_open(&self, now_seconds: u64) -> bool {
+        now_seconds < self.deadline_timestamp_seconds
+    }
+
+    // The deadline can only be extended by wait-for-quiet while the proposal is undecided, so once
+    // the deadline has passed, the proposal is known to be closed if it had been decided or if it
+    // was retrieved after its deadline
+    pub fn is_known_to_be_closed(&self, now_seconds: u64) -> bool {
+        !self.is_open(now_seconds)
+            && (self.decided_timestamp_seconds > 0
+                || self.retrieved_at_seconds >= self.deadline_timestamp_seconds)
+    }
+}
+
+#[derive(CandidType, Serialize)]
+struct GetNeuronArgs {
+    neuron_id: NeuronId,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct NeuronId {
+    pub id: [u8; 32],
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetNeuronResponse {
+    result: Option<GetNeuronResult>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum GetNeuronResult {
+    Neuron(Neuron),
+    Error(GovernanceError),
+}
+
+#[derive(CandidType, Serialize)]
+struct ListNeuronsArgs {
+    limit: u32,
+    start_page_at: Option<NeuronId>,
+    of_principal: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListNeuronsResponse {
+    neurons: Vec<Neuron>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Neuron {
+    id: Option<NeuronId>,
+    permissions: Vec<NeuronPermission>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct NeuronPermission {
+    principal: Option<Principal>,
+    permission_type: Vec<i32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ProposalId {
+    pub id: u64,
+}
+
+#[derive(CandidType, Serialize)]
+struct GetProposalArgs {
+    proposal_id: Option<ProposalId>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetProposalResponse {
+    result: Option<GetProposalResult>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum GetProposalResult {
+    Error(GovernanceError),
+    Proposal(ProposalData),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ProposalData {
+    pub id: Option<ProposalId>,
+    // The id of the nervous system function which the proposal executes
+    pub action: u64,
+    pub decided_timestamp_seconds: u64,
+    pub proposal_creation_timestamp_seconds: u64,
+    pub initial_voting_period_seconds: u64,
+    pub wait_for_quiet_state: Option<WaitForQuietState>,
+    // Keyed by the hex encoded neuron id
+    pub ballots: Vec<(String, Ballot)>,
+    pub proposal: Option<Proposal>,
+    pub payload_text_rendering: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct Proposal {
+    pub title: String,
+    pub summary: String,
+    // Only generic nervous system functions are decoded, any other action decodes as None
+    pub action: Option<Action>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum Action {
+    ExecuteGenericNervousSystemFunction(ExecuteGenericNervousSystemFunction),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ExecuteGenericNervousSystemFunction {
+    pub function_id: u64,
+    pub payload: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize)]
+struct ListProposalsArgs {
+    limit: u32,
+    before_proposal: Option<ProposalId>,
+    exclude_type: Vec<u64>,
+    include_reward_status: Vec<i32>,
+    include_status: Vec<i32>,
+    include_ballots_by_caller: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListProposalsResponse {
+    proposals: Vec<ProposalData>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListNervousSystemFunctionsResponse {
+    functions: Vec<NervousSystemFunction>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct NervousSystemFunction {
+    pub id: u64,
+    pub name: String,
+    pub function_type: Option<FunctionType>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum FunctionType {
+    NativeNervousSystemFunction {},
+    GenericNervousSystemFunction(GenericNervousSystemFunction),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GenericNervousSystemFunction {
+    pub target_canister_id: Option<Principal>,
+    pub target_method_name: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct Ballot {
+    pub vote: i32,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct WaitForQuietState {
+    pub current_deadline_timestamp_seconds: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct GovernanceError {
+    pub error_type: i32,
+    pub error_message: String,
+}
+
+impl GovernanceError {
+    // Maps the SNS governance `ErrorType` to its category
+    pub fn kind(&self) -> SnsErrorKind {
+        let message = self.error_message.to_lowercase();
+        if message.contains("already voted") {
+            return SnsErrorKind::AlreadyVoted;
+        }
+        match self.error_type {
+            1 => SnsErrorKind::Unavailable,
+            // NotAuthorized and AccessControlList
+            2 | 8 => SnsErrorKind::NotAuthorized,
+            3 => SnsErrorKind::NotFound,
+            // InvalidCommand, InvalidPrincipal and InvalidProposal
+            4 | 14 | 15 => SnsErrorKind::InvalidCommand,
+            9 => SnsErrorKind::ResourceExhausted,
+            10 if message.contains("deadline") || message.contains("no longer open") => {
+                SnsErrorKind::ProposalClosed
+            }
+            10 => SnsErrorKind::PreconditionFailed,
+            11 => SnsErrorKind::External,
+            12 => SnsErrorKind::NeuronLocked,
+            _ => SnsErrorKind::Other,
+        }
+    }
+
+    pub fn into_sns_error(self) -> SnsError {
+        SnsError {
+            kind: self.kind(),
+            message: self.error_message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes a WTN "Vote for NNS Proposals" proposal using the full SNS governance types, so
+    // that decoding is checked against the fields which the relay ignores
+    fn encode_mirror_proposal(title: &str, function_id: u64, payload: Vec<u8>) -> Vec<u8> {
+        #[derive(CandidType)]
+        struct FullProposalData {
+            id: Option<ProposalId>,
+            payload_text_rendering: Option<String>,
+            action: u64,
+            failure_reason: Option<GovernanceError>,
+            ballots: Vec<(String, FullBallot)>,
+            reward_event_round: u64,
+            failed_timestamp_seconds: u64,
+            proposal_creation_timestamp_seconds: u64,
+            initial_voting_period_seconds: u64,
+            reject_cost_e8s: u64,
+            latest_tally: Option<Tally>,
+            wait_for_quiet_deadline_increase_seconds: u64,
+            decided_timestamp_seconds: u64,
+            proposal: Option<FullProposal>,
+            proposer: Option<NeuronId>,
+            wait_for_quiet_state: Option<WaitForQuietState>,
+            is_eligible_for_rewards: bool,
+            executed_timestamp_seconds: u64,
+        }
+        #[derive(CandidType)]
+        struct FullBallot {
+            vote: i32,
+            cast_timestamp_seconds: u64,
+            voting_power: u64,
+        }
+        #[derive(CandidType)]
+        struct Tally {
+            no: u64,
+            yes: u64,
+            total: u64,
+            timestamp_seconds: u64,
+        }
+        #[derive(CandidType)]
+        struct FullProposal {
+            url: String,
+            title: String,
+            action: Option<FullAction>,
+            summary: String,
+        }
+        #[derive(CandidType)]
+        #[allow(dead_code)]
+        enum FullAction {
+            Motion(Motion),
+            ExecuteGenericNervousSystemFunction(ExecuteGenericNervousSystemFunction),
+        }
+        #[derive(CandidType)]
+        struct Motion {
+            motion_text: String,
+        }
+
+        let proposal_data = FullProposalData {
+            id: Some(ProposalId { id: 1234 }),
+            payload_text_rendering: Some("{\"id\": 134567}".to_string()),
+            action: function_id,
+            failure_reason: None,
+            ballots: vec![(
+                hex_encode(&[1; 32]),
+                FullBallot {
+                    vote: 1,
+                    cast_timestamp_seconds: 1_730_000_000,
+                    voting_power: 100,
+                },
+            )],
+            reward_event_round: 0,
+            failed_timestamp_seconds: 0,
+            proposal_creation_timestamp_seconds: 1_730_000_000,
+            initial_voting_period_seconds: 4 * 24 * 60 * 60,
+            reject_cost_e8s: 0,
+            latest_tally: Some(Tally {
+                no: 0,
+                yes: 100,
+                total: 1000,
+                timestamp_seconds: 1_730_000_000,
+            }),
+            wait_for_quiet_deadline_increase_seconds: 24 * 60 * 60,
+            decided_timestamp_seconds: 0,
+            proposal: Some(FullProposal {
+                url: String::new(),
+                title: title.to_string(),
+                action: Some(FullAction::ExecuteGenericNervousSystemFunction(
+                    ExecuteGenericNervousSystemFunction {
+                        function_id,
+                        payload,
+                    },
+                )),
+                summary: "This proposal 2 of the day mirrors an NNS proposal.".to_string(),
+            }),
+            proposer: None,
+            wait_for_quiet_state: None,
+            is_eligible_for_rewards: true,
+            executed_timestamp_seconds: 0,
+        };
+        candid::encode_one(proposal_data).unwrap()
+    }
+
+    fn decode_proposal(bytes: &[u8]) -> ProposalData {
+        candid::decode_one(bytes).unwrap()
+    }
+
+    #[test]
+    fn referenced_nns_proposal_id_is_taken_from_the_payload() {
+        let payload = candid::encode_one(ProposalId { id: 134567 }).unwrap();
+        let proposal = decode_proposal(&encode_mirror_proposal(
+            "Vote on NNS proposal 99",
+            1000,
+            payload,
+        ));
+        assert_eq!(proposal.referenced_nns_proposal_id(), Some(134567));
+        assert_eq!(proposal.parsed_nns_proposal_id(), Some(99));
+
+        let payload = candid::encode_one(134567u64).unwrap();
+        let proposal = decode_proposal(&encode_mirror_proposal("Vote", 1000, payload));
+        assert_eq!(proposal.referenced_nns_proposal_id(), Some(134567));
+    }
+
+    #[test]
+    fn referenced_nns_proposal_id_requires_a_decodable_payload_of_the_proposals_function() {
+        let proposal = decode_proposal(&encode_mirror_proposal(
+            "Vote on NNS proposal 134567",
+            1000,
+            candid::encode_one("134567").unwrap(),
+        ));
+        assert_eq!(proposal.referenced_nns_proposal_id(), None);
+
+        let mut bytes = encode_mirror_proposal(
+            "Vote on NNS proposal 134567",
+            1000,
+            candid::encode_one(134567u64).unwrap(),
+        );
+        let mut proposal = decode_proposal(&bytes);
+        proposal.action = 1001;
+        assert_eq!(proposal.referenced_nns_proposal_id(), None);
+
+        bytes = candid::encode_one(ProposalData {
+            proposal: Some(Proposal {
+                title: "Vote on NNS proposal 134567".to_string(),
+                summary: String::new(),
+                action: None,
+            }),
+            ..decode_proposal(&bytes)
+        })
+        .unwrap();
+        assert_eq!(decode_proposal(&bytes).referenced_nns_proposal_id(), None);
+    }
+
+    fn neuron(id: Option<u8>, permissions: &[(Principal, &[i32])]) -> Neuron {
+        Neuron {
+            id: id.map(|id| NeuronId { id: [id; 32] }),
+            permissions: permissions
+                .iter()
+                .map(|(principal, permission_type)| NeuronPermission {
+                    principal: Some(*principal),
+                    permission_type: permission_type.to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn neuron_permissions_are_checked_per_principal() {
+        let caller = Principal::from_slice(&[1]);
+        let relay = Principal::from_slice(&[2]);
+        let neuron = neuron(
+            Some(1),
+            &[
+                (caller, &[1, 2, REGISTER_VOTE_PERMISSION]),
+                (relay, &[REGISTER_VOTE_PERMISSION]),
+            ],
+        );
+
+        assert!(neuron.has_permission(caller, 2));
+        assert!(neuron.has_permission(relay, REGISTER_VOTE_PERMISSION));
+        assert!(!neuron.has_permission(relay, 2));
+        assert!(!neuron.has_permission(Principal::from_slice(&[3]), REGISTER_VOTE_PERMISSION));
+
+        let unowned = Neuron {
+            id: None,
+            permissions: vec![NeuronPermission {
+                principal: None,
+                permission_type: vec![REGISTER_VOTE_PERMISSION],
+            }],
+        };
+        assert!(!unowned.has_permission(Principal::anonymous(), REGISTER_VOTE_PERMISSION));
+    }
+
+    #[test]
+    fn neurons_are_paged_until_a_short_page() {
+        let page_size = LIST_NEURONS_PAGE_SIZE as usize;
+        let mut neurons: Vec<_> = (0..page_size as u8).map(|i| neuron(Some(i), &[])).collect();
+        let start = next_page_start(&neurons, page_size).map(|id| id.id);
+        assert_eq!(start, Some([page_size as u8 - 1; 32]));
+
+        neurons.push(neuron(Some(200), &[]));
+        assert!(next_page_start(&neurons, 1).is_none());
+        assert!(next_page_start(&[], 0).is_none());
+
+        // Paging can't continue past a neuron without an id
+        neurons.push(neuron(None, &[]));
+        assert!(next_page_start(&neurons, page_size).is_none());
+    }
+
+    #[test]
+    fn mirror_proposals_are_matched_by_their_payloads() {
+        let mirror =
+            |wtn_proposal_id, function_id, title: &str, nns_proposal_id: u64| ProposalData {
+                id: Some(ProposalId {
+                    id: wtn_proposal_id,
+                }),
+                ..decode_proposal(&encode_mirror_proposal(
+                    title,
+                    function_id,
+                    candid::encode_one(nns_proposal_id).unwrap(),
+                ))
+            };
+        let proposals = [
+            mirror(1, 1000, "Vote on NNS proposal 10", 10),
+            mirror(2, 1000, "Vote on NNS proposal 11", 12),
+            mirror(3, 2000, "Vote on NNS proposal 13", 13),
+            mirror(4, 1000, "Vote on NNS proposal 14", 14),
+        ];
+
+        let mirrors = find_mirror_proposals(&proposals, 1000, &BTreeSet::from([10, 11, 13]));
+        let found: Vec<_> = mirrors.iter().map(|(nns, p)| (*nns, p.id())).collect();
+        assert_eq!(found, vec![(10, Some(1))]);
+
+        let mirrors = find_mirror_proposals(&proposals, 1000, &BTreeSet::from([12, 14]));
+        let found: Vec<_> = mirrors.iter().map(|(nns, p)| (*nns, p.id())).collect();
+        assert_eq!(found, vec![(12, Some(2)), (14, Some(4))]);
+    }
+
+    #[test]
+    fn parse_nns_proposal_id_formats() {
+        for text in [
+            "Vote on NNS Proposal 134567: Upgrade canister",
+            "Vote on NNS proposal #134567",
+            "proposal_id: 134567",
+            "https://dashboard.internetcomputer.org/proposal/134567",
+        ] {
+            assert_eq!(parse_nns_proposal_id(text), Some(134567), "{text}");
+        }
+    }
+
+    #[test]
+    fn parse_nns_proposal_id_finds_the_first_mention_followed_by_an_id() {
+        // The first "proposal" isn't followed by an id, so the id after the second is used
+        assert_eq!(
+            parse_nns_proposal_id("This proposal mirrors NNS proposal 42"),
+            Some(42)
+        );
+    }
+
+    #[test]
+    fn parse_nns_proposal_id_returns_none_if_no_mention_is_followed_by_an_id() {
+        assert_eq!(parse_nns_proposal_id("No id in this proposal"), None);
+        assert_eq!(parse_nns_proposal_id("Proposal for NNS proposal"), None);
+    }
+
+    #[test]
+    fn governance_errors_are_categorised() {
+        let error = |error_type: i32, message: &str| GovernanceError {
+            error_type,
+            error_message: message.to_string(),
+        };
+
+        assert_eq!(
+            error(10, "Neuron already voted on proposal.").kind(),
+            SnsErrorKind::AlreadyVoted
+        );
+        assert_eq!(
+            error(10, "Proposal deadline has passed.").kind(),
+            SnsErrorKind::ProposalClosed
+        );
+        assert_eq!(
+            error(10, "Neuron is dissolving").kind(),
+            SnsErrorKind::PreconditionFailed
+        );
+        assert_eq!(error(8, "").kind(), SnsErrorKind::NotAuthorized);
+        assert_eq!(error(99, "").kind(), SnsErrorKind::Other);
+    }
+
+    #[test]
+    fn ballot_is_found_by_hex_encoded_neuron_id() {
+        let mut neuron_id = [0; 32];
+        neuron_id[0] = 0xab;
+        neuron_id[31] = 0x01;
+        let proposal = ProposalData {
+            id: Some(ProposalId { id: 1 }),
+            action: 1000,
+            decided_timestamp_seconds: 0,
+            proposal_creation_timestamp_seconds: 0,
+            initial_voting_period_seconds: 0,
+            wait_for_quiet_state: None,
+            ballots: vec![
+                (hex_encode(&[1; 32]), Ballot { vote: 2 }),
+                (
+                    "ab000000000000000000000000000000000000000000000000000000000000\
+                     01"
+                    .to_string(),
+                    Ballot { vote: 1 },
+                ),
+            ],
+            proposal: None,
+            payload_text_rendering: None,
+        };
+
+        assert_eq!(proposal.ballot(neuron_id).unwrap().vote, 1);
+        assert_eq!(proposal.ballot([1; 32]).unwrap().vote, 2);
+        assert!(proposal.ballot([2; 32]).is_none());
+    }
+
+    #[test]
+    fn decided_proposals_are_known_to_be_closed_once_their_deadline_passes() {
+        let proposal = |decided_timestamp_seconds, retrieved_at_seconds| WtnProposal {
+            function_id: 1000,
+            deadline_timestamp_seconds: 100,
+            decided_timestamp_seconds,
+            retrieved_at_seconds,
+        };
+
+        // Undecided proposals may have had their deadline extended since being retrieved
+        assert!(!proposal(0, 50).is_known_to_be_closed(150));
+        assert!(proposal(0, 100).is_known_to_be_closed(150));
+        assert!(proposal(60, 50).is_known_to_be_closed(150));
+        assert!(!proposal(60, 50).is_known_to_be_closed(99));
+    }
+
+    // Encodes the response using the full SNS governance types, so that decoding is checked
+    // against the fields which the relay ignores
+    fn encode_functions(functions: Vec<(u64, &str, bool)>) -> Vec<u8> {
+        #[derive(CandidType)]
+        struct Response {
+            reserved_ids: Vec<u64>,
+            functions: Vec<Function>,
+        }
+        #[derive(CandidType)]
+        struct Function {
+            id: u64,
+            name: String,
+            description: Option<String>,
+            function_type: Option<Type>,
+        }
+        #[derive(CandidType)]
+        enum Type {
+            NativeNervousSystemFunction {},
+            GenericNervousSystemFunction(Generic),
+        }
+        #[derive(CandidType)]
+        struct Generic {
+            validator_canister_id: Option<Principal>,
+            target_canister_id: Option<Principal>,
+            validator_method_name: Option<String>,
+            target_method_name: Option<String>,
+            topic: Option<Topic>,
+        }
+        #[derive(CandidType)]
+        enum Topic {
+            ApplicationBusinessLogic,
+        }
+
+        let protocol_canister = Principal::from_slice(&[0, 0, 0, 0, 2, 48, 1, 106, 1, 1]);
+        let response = Response {
+            reserved_ids: vec![],
+            functions: functions
+                .into_iter()
+                .map(|(id, name, generic)| Function {
+                    id,
+                    name: name.to_string(),
+                    description: Some(format!("{name} description")),
+                    function_type: Some(if generic {
+                        Type::GenericNervousSystemFunction(Generic {
+                            validator_canister_id: Some(protocol_canister),
+                            target_canister_id: Some(protocol_canister),
+                            validator_method_name: Some("validate".to_string()),
+                            target_method_name: Some("execute".to_string()),
+                            topic: Some(Topic::ApplicationBusinessLogic),
+                        })
+                    } else {
+                        Type::NativeNervousSystemFunction {}
+                    }),
+                })
+                .collect(),
+        };
+        candid::encode_one(response).unwrap()
+    }
+
+    fn find_function(functions: Vec<(u64, &str, bool)>) -> Option<u64> {
+        let bytes = encode_functions(functions);
+        let response: ListNervousSystemFunctionsResponse = candid::decode_one(&bytes).unwrap();
+        find_vote_for_nns_proposals_function(&response.functions)
+    }
+
+    #[test]
+    fn vote_for_nns_proposals_function_is_found_by_name() {
+        assert_eq!(
+            find_function(vec![
+                (0, "All Topics", false),
+                (1, "Motion", false),
+                (1001, "Update Protocol Settings", true),
+                (1002, "Vote for NNS proposals", true),
+            ]),
+            Some(1002)
+        );
+    }
+
+    #[test]
+    fn vote_for_nns_proposals_function_must_be_unique_and_generic() {
+        assert_eq!(find_function(vec![(1, "Motion", false)]), None);
+        assert_eq!(
+            find_function(vec![(2, "Vote for NNS Proposals", false)]),
+            None
+        );
+        assert_eq!(
+            find_function(vec![
+                (1001, "Vote for NNS Proposals", true),
+                (1002, "Vote for NNS Proposals", true),
+            ]),
+            None
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use crate::memory::{
+    get_quarantined_wtn_proposal_mappings_memory, get_wtn_proposal_mappings_by_expiry_memory,
+    get_wtn_proposal_mappings_by_wtn_proposal_memory, get_wtn_proposal_mappings_memory, Memory,
+};
+use crate::{WtnProposalMapping, WtnProposalMappingSource, WtnProposalMappingStatus};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// The cache of which WTN proposal mirrors each NNS proposal, keyed by NNS proposal id. This lives
+// in stable memory so it is retained across upgrades without being serialized with the rest of
+// the state. Entries expire after a TTL which depends on whether a WTN proposal was found, since
+// the protocol canister may yet create a WTN proposal for an NNS proposal which didn't have one.
+pub struct WtnProposalMappings {
+    map: StableBTreeMap<u64, StoredMapping, Memory>,
+    // The NNS proposal id mapped to each WTN proposal id
+    by_wtn_proposal: StableBTreeMap<u64, u64, Memory>,
+    // The (expiry time, NNS proposal id) of each mapping, so that expired mappings can be evicted
+    // without scanning the cache
+    by_expiry: StableBTreeMap<(u64, u64), (), Memory>,
+    // The (NNS proposal id, WTN proposal id) of each mapping which failed verification. These are
+    // kept when the mapping expires or is resolved again, until a controller removes them.
+    quarantined: StableBTreeMap<(u64, u64), (), Memory>,
+    // Kept in memory so that the metrics don't need to scan the cache
+    source_counts: BTreeMap<WtnProposalMappingSource, u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct WtnProposalMappingsConfig {
+    pub max_entries: u32,
+    pub positive_ttl: Duration,
+    pub negative_ttl: Duration,
+}
+
+impl Default for WtnProposalMappingsConfig {
+    fn default() -> Self {
+        WtnProposalMappingsConfig {
+            max_entries: 500,
+            positive_ttl: Duration::from_secs(14 * 24 * 60 * 60), // 14 days
+            negative_ttl: Duration::from_secs(60 * 60),           // 1 hour
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredMapping {
+    pub mapping: WtnProposalMapping,
+    pub recorded_at: u64,
+    pub expires_at: u64,
+}
+
+impl StoredMapping {
+    fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+}
+
+impl Storable for StoredMapping {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(rmp_serde::to_vec_named(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        rmp_serde::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Default for WtnProposalMappings {
+    fn default() -> Self {
+        let mut mappings = WtnProposalMappings {
+            map: StableBTreeMap::init(get_wtn_proposal_mappings_memory()),
+            by_wtn_proposal: StableBTreeMap::init(
+                get_wtn_proposal_mappings_by_wtn_proposal_memory(),
+            ),
+            by_expiry: StableBTreeMap::init(get_wtn_proposal_mappings_by_expiry_memory()),
+            quarantined: StableBTreeMap::init(get_quarantined_wtn_proposal_mappings_memory()),
+            source_counts: BTreeMap::new(),
+        };
+        mappings.rebuild_indexes_if_required();
+        mappings
+    }
+}
+
+impl WtnProposalMappings {
+    pub fn insert(
+        &mut self,
+        nns_proposal_id: u64,
+        mut mapping: WtnProposalMapping,
+        config: &WtnProposalMappingsConfig,
+        now: u64,
+    ) {
+        let ttl = if mapping.wtn_proposal_id.is_some() {
+            config.positive_ttl
+        } else {
+            config.negative_ttl
+        };
+        if mapping
+            .wtn_proposal_id
+            .is_some_and(|id| self.is_quarantined(nns_proposal_id, id))
+        {
+            mapping.status = WtnProposalMappingStatus::Quarantined;
+        }
+
+        self.remove_entry(nns_proposal_id);
+        self.insert_entry(
+            nns_proposal_id,
+            StoredMapping {
+                mapping,
+                recorded_at: now,
+                expires_at: now.saturating_add(ttl.as_nanos() as u64),
+            },
+        );
+        self.evict(config, now);
+    }
+
+    pub fn get(&self, nns_proposal_id: u64, now: u64) -> Option<WtnProposalMapping> {
+        self.map
+            .get(&nns_proposal_id)
+            .filter(|m| !m.is_expired(now))
+            .map(|m| m.mapping)
+    }
+
+    pub fn find_by_wtn_proposal(&self, wtn_proposal_id: u64, now: u64) -> Option<u64> {
+        let nns_proposal_id = self.by_wtn_proposal.get(&wtn_proposal_id)?;
+        self.get(nns_proposal_id, now)
+            .filter(|m| m.wtn_proposal_id == Some(wtn_proposal_id))
+            .map(|_| nns_proposal_id)
+    }
+
+    // Quarantined mappings stay quarantined even once they are no longer cached
+    pub fn status(
+        &self,
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        now: u64,
+    ) -> WtnProposalMappingStatus {
+        if self.is_quarantined(nns_proposal_id, wtn_proposal_id) {
+            return WtnProposalMappingStatus::Quarantined;
+        }
+        self.get(nns_proposal_id, now)
+            .filter(|m| m.wtn_proposal_id == Some(wtn_proposal_id))
+            .map_or(WtnProposalMappingStatus::Unverified, |m| m.status)
+    }
+
+    // Returns false if the mapping already had this status. Once quarantined, a mapping can only
+    // be released by a controller removing it.
+    pub fn set_status(
+        &mut self,
+        nns_proposal_id: u64,
+        wtn_proposal_id: u64,
+        status: WtnProposalMappingStatus,
+    ) -> bool {
+        if self.is_quarantined(nns_proposal_id, wtn_proposal_id) {
+            return false;
+        }
+        if status == WtnProposalMappingStatus::Quarantined {
+            self.quarantined
+                .insert((nns_proposal_id, wtn_proposal_id), ());
+        }
+
+        match self
+            .map
+            .get(&nns_proposal_id)
+            .filter(|m| m.mapping.wtn_proposal_id == Some(wtn_proposal_id))
+        {
+            Some(stored) if stored.mapping.status == status => false,
+            Some(mut stored) => {
+                stored.mapping.status = status;
+                self.map.insert(nns_proposal_id, stored);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn is_quarantined(&self, nns_proposal_id: u64, wtn_proposal_id: u64) -> bool {
+        self.quarantined
+            .contains_key(&(nns_proposal_id, wtn_proposal_id))
+    }
+
+    // Removes the mapping along with any quarantine of the NNS proposal's mappings
+    pub fn remove(&mut self, nns_proposal_id: u64) -> bool {
+        let quarantined: Vec<_> = self
+            .quarantined
+            .range((nns_proposal_id, 0)..=(nns_proposal_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in quarantined.iter() {
+            self.quarantined.remove(key);
+        }
+        self.remove_entry(nns_proposal_id).is_some() || !quarantined.is_empty()
+    }
+
+    pub fn clear(&mut self) -> u32 {
+        let count = self.map.len() as u32;
+        self.map.clear_new();
+        self.by_wtn_proposal.clear_new();
+        self.by_expiry.clear_new();
+        self.quarantined.clear_new();
+        self.source_counts.clear();
+        count
+    }
+
+    pub fn page(&self, start_after: Option<u64>, limit: usize) -> Vec<(u64, StoredMapping)> {
+        let start = start_after.map_or(0, |id| id.saturating_add(1));
+        if start_after == Some(u64::MAX) {
+            return Vec::new();
+        }
+        self.map.range(start..).take(limit).collect()
+    }
+
+    pub fn count_by_source(&self, source: WtnProposalMappingSource) -> u32 {
+        self.source_counts.get(&source).copied().unwrap_or_default()
+    }
+
+    // Removes expired entries, then if the cache is still too large, removes those with the
+    // lowest NNS proposal ids
+    fn evict(&mut self, config: &WtnProposalMappingsConfig, now: u64) {
+        if self.map.len() <= config.max_entries as u64 {
+            return;
+        }
+
+        let expired: Vec<_> = self
+            .by_expiry
+            .range(..=(now, u64::MAX))
+            .map(|((_, nns_proposal_id), _)| nns_proposal_id)
+            .collect();
+        for nns_proposal_id in expired {
+            self.remove_entry(nns_proposal_id);
+        }
+
+        while self.map.len() > config.max_entries as u64 {
+            let Some((nns_proposal_id, _)) = self.map.first_key_value() else {
+                break;
+            };
+            self.remove_entry(nns_proposal_id);
+        }
+    }
+
+    fn insert_entry(&mut self, nns_proposal_id: u64, stored: StoredMapping) {
+        if let Some(wtn_proposal_id) = stored.mapping.wtn_proposal_id {
+            self.by_wtn_proposal
+                .insert(wtn_proposal_id, nns_proposal_id);
+        }
+        self.by_expiry
+            .insert((stored.expires_at, nns_proposal_id), ());
+        *self.source_counts.entry(stored.mapping.source).or_default() += 1;
+        self.map.insert(nns_proposal_id, stored);
+    }
+
+    fn remove_entry(&mut self, nns_proposal_id: u64) -> Option<StoredMapping> {
+        let stored = self.map.remove(&nns_proposal_id)?;
+        if let Some(wtn_proposal_id) = stored.mapping.wtn_proposal_id {
+            if self.by_wtn_proposal.get(&wtn_proposal_id) == Some(nns_proposal_id) {
+                self.by_wtn_proposal.remove(&wtn_proposal_id);
+            }
+        }
+        self.by_expiry.remove(&(stored.expires_at, nns_proposal_id));
+        if let Some(count) = self.source_counts.get_mut(&stored.mapping.source) {
+            *count = count.saturating_sub(1);
+        }
+        Some(stored)
+    }
+
+    // Counts the mappings from each source, and if the indexes don't match the cache, eg. because
+    // they were introduced after the cache was populated, rebuilds them. This is the only time the
+    // whole cache is scanned.
+    fn rebuild_indexes_if_required(&mut self) {
+        let mut positive = 0;
+        self.source_counts.clear();
+        for (_, stored) in self.map.iter() {
+            if stored.mapping.wtn_proposal_id.is_some() {
+                positive += 1;
+            }
+            *self.source_counts.entry(stored.mapping.source).or_default() += 1;
+        }
+
+        if self.by_expiry.len() == self.map.len() && self.by_wtn_proposal.len() == positive {
+            return;
+        }
+
+        self.by_wtn_proposal.clear_new();
+        self.by_expiry.clear_new();
+        for (nns_proposal_id, stored) in self.map.iter() {
+            if let Some(wtn_proposal_id) = stored.mapping.wtn_proposal_id {
+                self.by_wtn_proposal
+                    .insert(wtn_proposal_id, nns_proposal_id);
+            }
+            self.by_expiry
+                .insert((stored.expires_at, nns_proposal_id), ());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(wtn_proposal_id: Option<u64>) -> WtnProposalMapping {
+        WtnProposalMapping {
+            wtn_proposal_id,
+            source: WtnProposalMappingSource::ProtocolCanister,
+            status: WtnProposalMappingStatus::Unverified,
+        }
+    }
+
+    #[test]
+    fn negative_mappings_expire_before_positive_mappings() {
+        let config = WtnProposalMappingsConfig {
+            max_entries: 10,
+            positive_ttl: Duration::from_nanos(100),
+            negative_ttl: Duration::from_nanos(10),
+        };
+        let mut mappings = WtnProposalMappings::default();
+        mappings.insert(1, mapping(Some(5)), &config, 0);
+        mappings.insert(2, mapping(None), &config, 0);
+
+        assert!(mappings.get(2, 5).is_some());
+        assert!(mappings.get(2, 10).is_none());
+        assert_eq!(mappings.find_by_wtn_proposal(5, 50), Some(1));
+        assert!(mappings.get(1, 100).is_none());
+    }
+
+    #[test]
+    fn oldest_proposals_are_evicted_once_full() {
+        let config = WtnProposalMappingsConfig {
+            max_entries: 2,
+            ..WtnProposalMappingsConfig::default()
+        };
+        let mut mappings = WtnProposalMappings::default();
+        for nns_proposal_id in [3, 1, 2] {
+            mappings.insert(nns_proposal_id, mapping(Some(nns_proposal_id)), &config, 0);
+        }
+
+        let ids: Vec<_> = mappings
+            .page(None, 10)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn indexes_follow_replaced_and_evicted_mappings() {
+        let config = WtnProposalMappingsConfig {
+            max_entries: 2,
+            positive_ttl: Duration::from_nanos(100),
+            negative_ttl: Duration::from_nanos(10),
+        };
+        let mut mappings = WtnProposalMappings::default();
+        mappings.insert(1, mapping(Some(5)), &config, 0);
+        mappings.insert(1, mapping(Some(6)), &config, 0);
+        assert_eq!(mappings.find_by_wtn_proposal(5, 0), None);
+        assert_eq!(mappings.find_by_wtn_proposal(6, 0), Some(1));
+
+        // The expired negative mapping is evicted ahead of the lower NNS proposal id
+        mappings.insert(
+            3,
+            WtnProposalMapping {
+                source: WtnProposalMappingSource::Controller,
+                ..mapping(None)
+            },
+            &config,
+            0,
+        );
+        mappings.insert(2, mapping(Some(7)), &config, 20);
+        assert!(mappings.get(3, 20).is_none());
+        assert_eq!(mappings.find_by_wtn_proposal(6, 20), Some(1));
+        assert_eq!(mappings.find_by_wtn_proposal(7, 20), Some(2));
+        assert_eq!(
+            mappings.count_by_source(WtnProposalMappingSource::ProtocolCanister),
+            2
+        );
+        assert_eq!(
+            mappings.count_by_source(WtnProposalMappingSource::Controller),
+            0
+        );
+
+        // Missing indexes, eg. for a cache populated by an earlier version, are rebuilt
+        mappings.by_wtn_proposal.clear_new();
+        mappings.by_expiry.clear_new();
+        mappings.rebuild_indexes_if_required();
+        assert_eq!(mappings.find_by_wtn_proposal(7, 20), Some(2));
+        assert_eq!(mappings.by_expiry.len(), 2);
+    }
+
+    #[test]
+    fn quarantine_is_kept_until_the_mapping_is_removed() {
+        let config = WtnProposalMappingsConfig {
+            max_entries: 10,
+            positive_ttl: Duration::from_nanos(100),
+            negative_ttl: Duration::from_nanos(10),
+        };
+        let mut mappings = WtnProposalMappings::default();
+        mappings.insert(1, mapping(Some(5)), &config, 0);
+        assert!(mappings.set_status(1, 5, WtnProposalMappingStatus::Quarantined));
+        assert!(!mappings.set_status(1, 5, WtnProposalMappingStatus::Verified));
+
+        // Expired and then resolved again to the same WTN proposal
+        assert_eq!(
+            mappings.status(1, 5, 200),
+            WtnProposalMappingStatus::Quarantined
+        );
+        mappings.insert(1, mapping(Some(5)), &config, 200);
+        assert_eq!(
+            mappings.get(1, 200).unwrap().status,
+            WtnProposalMappingStatus::Quarantined
+        );
+
+        // Resolving to a different WTN proposal isn't affected
+        mappings.insert(1, mapping(Some(6)), &config, 200);
+        assert_eq!(
+            mappings.status(1, 6, 200),
+            WtnProposalMappingStatus::Unverified
+        );
+
+        assert!(mappings.remove(1));
+        assert_eq!(
+            mappings.status(1, 5, 200),
+            WtnProposalMappingStatus::Unverified
+        );
+    }
+}